@@ -55,6 +55,8 @@ pub fn start<C>(service: &Service<C>, exit: ::exit_future::Exit, handle: TaskExe
 				(SyncState::Idle, _) => ("Idle".into(), "".into()),
 				(SyncState::Downloading, None) => (format!("Syncing{}", speed()), "".into()),
 				(SyncState::Downloading, Some(n)) => (format!("Syncing{}", speed()), format!(", target=#{}", n)),
+				(SyncState::Stalled, None) => ("Stalled".into(), "".into()),
+				(SyncState::Stalled, Some(n)) => ("Stalled".into(), format!(", target=#{}", n)),
 			};
 			last_number = Some(best_number);
 			let txpool_status = txpool.status();
@@ -604,7 +604,17 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 	{
 		let parent_hash = import_headers.post().parent_hash().clone();
 		match self.backend.blockchain().status(BlockId::Hash(hash))? {
-			blockchain::BlockStatus::InChain => return Ok(ImportResult::AlreadyInChain),
+			blockchain::BlockStatus::InChain => {
+				// the block is known, but the justification finalizing it may be new.
+				// blocks behind the last finalized one keep their justifications.
+				if finalized {
+					let info = self.backend.blockchain().info()?;
+					if *import_headers.post().number() >= info.finalized_number {
+						self.apply_finality(hash, Some(justification), info.best_hash, true)?;
+					}
+				}
+				return Ok(ImportResult::AlreadyInChain)
+			},
 			blockchain::BlockStatus::Unknown => {},
 		}
 
@@ -624,7 +634,7 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 		// ensure parent block is finalized to maintain invariant that
 		// finality is called sequentially.
 		if finalized {
-			self.apply_finality(parent_hash, last_best, make_notifications)?;
+			self.apply_finality(parent_hash, None, last_best, make_notifications)?;
 		}
 
 		let tags = self.transaction_tags(parent_hash, &body)?;
@@ -727,12 +737,23 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 		Ok(ImportResult::Queued)
 	}
 
-	/// Finalizes all blocks up to given.
-	fn apply_finality(&self, block: Block::Hash, best_block: Block::Hash, notify: bool) -> error::Result<()> {
+	/// Finalizes all blocks up to given, storing the justification of the given block if any.
+	fn apply_finality(
+		&self,
+		block: Block::Hash,
+		mut justification: Option<Justification>,
+		best_block: Block::Hash,
+		notify: bool,
+	) -> error::Result<()> {
 		// find tree route from last finalized to given block.
 		let last_finalized = self.backend.blockchain().last_finalized()?;
 
-		if block == last_finalized { return Ok(()) }
+		if block == last_finalized {
+			if justification.is_some() {
+				self.backend.finalize_block(BlockId::Hash(block), justification)?;
+			}
+			return Ok(())
+		}
 		let route_from_finalized = ::blockchain::tree_route(
 			self.backend.blockchain(),
 			BlockId::Hash(last_finalized),
@@ -760,7 +781,8 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 		}
 
 		for finalize_new in route_from_finalized.enacted() {
-			self.backend.finalize_block(BlockId::Hash(finalize_new.hash))?;
+			let justification = if finalize_new.hash == block { justification.take() } else { None };
+			self.backend.finalize_block(BlockId::Hash(finalize_new.hash), justification)?;
 		}
 
 		if notify {
@@ -799,7 +821,7 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 				.ok_or_else(|| error::ErrorKind::UnknownBlock(format!("No block with number {:?}", n)))?,
 		};
 
-		self.apply_finality(to_finalize_hash, last_best, notify)
+		self.apply_finality(to_finalize_hash, None, last_best, notify)
 	}
 
 	/// Attempts to revert the chain by `n` blocks. Returns the number of blocks that were
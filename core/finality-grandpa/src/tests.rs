@@ -89,6 +89,7 @@ impl TestNetFactory for GrandpaTestNet {
 		// the authority role ensures gossip hits all nodes here.
 		ProtocolConfig {
 			roles: Roles::AUTHORITY,
+			..Default::default()
 		}
 	}
 
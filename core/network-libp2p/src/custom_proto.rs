@@ -72,6 +72,8 @@ pub struct RegisteredProtocolSubstream<TSubstream> {
 	is_closing: bool,
 	/// Buffer of packets to send.
 	send_queue: VecDeque<Bytes>,
+	/// Number of packets written to the sink since the last call to `take_sent_packets`.
+	sent_packets: usize,
 	/// If true, we should call `poll_complete` on the inner sink.
 	requires_poll_complete: bool,
 	/// The underlying substream.
@@ -110,6 +112,17 @@ impl<TSubstream> RegisteredProtocolSubstream<TSubstream> {
 		}
 	}
 
+	/// Returns the number of packets written to the substream since the last call, and resets it.
+	pub fn take_sent_packets(&mut self) -> usize {
+		::std::mem::replace(&mut self.sent_packets, 0)
+	}
+
+	/// Returns the number of packets waiting to be written to the substream.
+	#[inline]
+	pub fn queued_packets(&self) -> usize {
+		self.send_queue.len()
+	}
+
 	/// Sends a message to the substream.
 	pub fn send_message(&mut self, data: Bytes) {
 		self.send_queue.push_back(data);
@@ -145,7 +158,10 @@ where TSubstream: AsyncRead + AsyncWrite,
 					self.send_queue.push_front(packet);
 					break;
 				},
-				AsyncSink::Ready => self.requires_poll_complete = true,
+				AsyncSink::Ready => {
+					self.requires_poll_complete = true;
+					self.sent_packets += 1;
+				},
 			}
 		}
 
@@ -209,6 +225,7 @@ where TSubstream: AsyncRead + AsyncWrite,
 		future::ok(RegisteredProtocolSubstream {
 			is_closing: false,
 			send_queue: VecDeque::new(),
+			sent_packets: 0,
 			requires_poll_complete: false,
 			inner: framed.fuse(),
 			protocol_id: self.id,
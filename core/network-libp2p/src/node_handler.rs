@@ -52,6 +52,8 @@ pub struct SubstrateNodeHandler<TSubstream> {
 	registered_custom: Arc<RegisteredProtocols>,
 	/// Substreams open for "custom" protocols (eg. dot).
 	custom_protocols_substreams: Vec<RegisteredProtocolSubstream<TSubstream>>,
+	/// Number of custom messages that left the send queues and haven't been reported yet.
+	unqueued_custom_messages: usize,
 
 	/// Substream open for Kademlia, if any.
 	kademlia_substream: Option<(KadConnecController, Box<Stream<Item = KadIncomingRequest, Error = IoError> + Send>)>,
@@ -132,6 +134,13 @@ pub enum SubstrateOutEvent<TSubstream> {
 		result: Result<(), IoError>,
 	},
 
+	/// Custom messages we were asked to send left the send queues, either written to their
+	/// substream or dropped with it.
+	CustomMessagesSent {
+		/// Number of messages.
+		count: usize,
+	},
+
 	/// Receives a message on a custom protocol substream.
 	CustomMessage {
 		/// Protocol which generated the message.
@@ -265,6 +274,7 @@ where TSubstream: AsyncRead + AsyncWrite + Send + 'static,
 
 		SubstrateNodeHandler {
 			custom_protocols_substreams: Vec::with_capacity(registered_custom_len),
+			unqueued_custom_messages: 0,
 			kademlia_substream: None,
 			need_report_kad_open: false,
 			identify_send_back: Arc::new(Mutex::new(Vec::with_capacity(1))),
@@ -447,6 +457,10 @@ where TSubstream: AsyncRead + AsyncWrite + Send + 'static,
 			None => {
 				// We are processing a message event before we could report to the outside that
 				// we disconnected from the protocol. This is not an error.
+				self.unqueued_custom_messages += 1;
+				if let Some(to_notify) = self.to_notify.take() {
+					to_notify.notify();
+				}
 				return
 			},
 		};
@@ -666,7 +680,9 @@ where TSubstream: AsyncRead + AsyncWrite + Send + 'static,
 		// Poll for messages on the custom protocol stream.
 		for n in (0 .. self.custom_protocols_substreams.len()).rev() {
 			let mut custom_proto = self.custom_protocols_substreams.swap_remove(n);
-			match custom_proto.poll() {
+			let polled = custom_proto.poll();
+			self.unqueued_custom_messages += custom_proto.take_sent_packets();
+			match polled {
 				Ok(Async::NotReady) => self.custom_protocols_substreams.push(custom_proto),
 				Ok(Async::Ready(Some(data))) => {
 					let protocol_id = custom_proto.protocol_id();
@@ -677,6 +693,7 @@ where TSubstream: AsyncRead + AsyncWrite + Send + 'static,
 					})));
 				},
 				Ok(Async::Ready(None)) => {
+					self.unqueued_custom_messages += custom_proto.queued_packets();
 					// Trying to reopen the protocol.
 					self.queued_dial_upgrades.push(UpgradePurpose::Custom(custom_proto.protocol_id()));
 					self.num_out_user_must_open += 1;
@@ -686,6 +703,7 @@ where TSubstream: AsyncRead + AsyncWrite + Send + 'static,
 					})))
 				},
 				Err(err) => {
+					self.unqueued_custom_messages += custom_proto.queued_packets();
 					// Trying to reopen the protocol.
 					self.queued_dial_upgrades.push(UpgradePurpose::Custom(custom_proto.protocol_id()));
 					self.num_out_user_must_open += 1;
@@ -697,6 +715,11 @@ where TSubstream: AsyncRead + AsyncWrite + Send + 'static,
 			}
 		}
 
+		if self.unqueued_custom_messages != 0 {
+			let count = ::std::mem::replace(&mut self.unqueued_custom_messages, 0);
+			return Ok(Async::Ready(Some(SubstrateOutEvent::CustomMessagesSent { count })));
+		}
+
 		Ok(Async::NotReady)
 	}
 
@@ -367,17 +367,24 @@ impl Service {
 		self.nodes_addresses.get(&node_index)
 	}
 
-	/// Sends a message to a peer using the custom protocol.
+	/// Sends a message to a peer using the custom protocol. Returns `false` if we're not
+	/// connected to the peer.
 	// TODO: report invalid node index or protocol?
 	pub fn send_custom_message(
 		&mut self,
 		node_index: NodeIndex,
 		protocol: ProtocolId,
 		data: Vec<u8>
-	) {
+	) -> bool {
 		self.swarm.send_custom_message(node_index, protocol, data)
 	}
 
+	/// Returns the number of custom messages waiting to be sent to a peer.
+	#[inline]
+	pub fn send_queue_len(&self, node_index: NodeIndex) -> usize {
+		self.swarm.send_queue_len(node_index)
+	}
+
 	/// Disconnects a peer and bans it for a little while.
 	///
 	/// Same as `drop_node`, except that the same peer will not be able to reconnect later.
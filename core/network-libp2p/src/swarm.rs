@@ -227,6 +227,9 @@ struct NodeInfo {
 
 	/// List of custom protocol substreams that are open.
 	open_protocols: Vec<ProtocolId>,
+
+	/// Number of custom messages passed to the node handler that are not sent yet.
+	queued_custom_messages: usize,
 }
 
 /// The muxer used by the transport.
@@ -284,17 +287,20 @@ impl Swarm {
 		self.nodes_info.get(&node_index).map(|i| i.endpoint)
 	}
 
-	/// Sends a message to a peer using the custom protocol.
+	/// Sends a message to a peer using the custom protocol. Returns `false` if we're not
+	/// connected to the peer.
 	// TODO: report invalid node index or protocol?
 	pub fn send_custom_message(
 		&mut self,
 		node_index: NodeIndex,
 		protocol: ProtocolId,
 		data: Vec<u8>
-	) {
+	) -> bool {
 		if let Some(info) = self.nodes_info.get_mut(&node_index) {
 			if let Some(mut connected) = self.swarm.peer(info.peer_id.clone()).as_connected() {
 				connected.send_event(SubstrateInEvent::SendCustomMessage { protocol, data });
+				info.queued_custom_messages += 1;
+				return true;
 			} else {
 				error!(target: "sub-libp2p", "Tried to send message to {:?}, but we're not \
 					connected to it", info.peer_id);
@@ -303,6 +309,13 @@ impl Swarm {
 			error!(target: "sub-libp2p", "Tried to send message to invalid node index {:?}",
 				node_index);
 		}
+		false
+	}
+
+	/// Returns the number of custom messages waiting to be sent to a node.
+	#[inline]
+	pub fn send_queue_len(&self, node_index: NodeIndex) -> usize {
+		self.nodes_info.get(&node_index).map_or(0, |i| i.queued_custom_messages)
 	}
 
 	/// Returns the peer id of a node we're connected to.
@@ -549,6 +562,12 @@ impl Swarm {
 					protocol: protocol_id,
 				})
 			},
+			SubstrateOutEvent::CustomMessagesSent { count } => {
+				let info = self.nodes_info.get_mut(&node_index)
+					.expect("nodes_info is kept in sync with the underlying swarm");
+				info.queued_custom_messages = info.queued_custom_messages.saturating_sub(count);
+				None
+			},
 			SubstrateOutEvent::CustomMessage { protocol_id, data } => {
 				Some(SwarmEvent::CustomMessage {
 					node_index,
@@ -583,6 +602,7 @@ impl Stream for Swarm {
 							ConnectedPoint::Dialer { .. } => Endpoint::Dialer,
 						},
 						open_protocols: Vec::new(),
+						queued_custom_messages: 0,
 					});
 
 					return Ok(Async::Ready(Some(SwarmEvent::NodePending {
@@ -0,0 +1,106 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Propagation of a large transaction pool to many peers.
+
+#[macro_use]
+extern crate criterion;
+extern crate parity_codec as codec;
+extern crate sr_primitives as runtime_primitives;
+extern crate substrate_keyring as keyring;
+extern crate substrate_network;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use codec::Encode;
+use criterion::Criterion;
+use keyring::Keyring;
+use runtime_primitives::traits::{BlakeTwo256, Hash as HashT};
+use substrate_network::TransactionPool;
+use substrate_network::config::ProtocolConfig;
+use substrate_network::test::{Block, Extrinsic, Hash, TestIo, TestNet, TestNetFactory, Transfer};
+
+const POOL_SIZE: usize = 2048;
+const PEERS: usize = 24;
+
+/// Pool returning the same extrinsics under fresh hashes on every call, so that none of them
+/// is known to the peers yet and all of them are propagated.
+struct FreshPool {
+	extrinsics: Vec<Extrinsic>,
+	round: AtomicUsize,
+}
+
+impl TransactionPool<Hash, Block> for FreshPool {
+	fn transactions(&self) -> Vec<(Hash, Extrinsic)> {
+		let round = self.round.fetch_add(1, Ordering::SeqCst) as u64;
+		self.extrinsics.iter().enumerate()
+			.map(|(i, e)| (BlakeTwo256::hash_of(&(round, i as u64)), e.clone()))
+			.collect()
+	}
+
+	fn import(&self, _reputation: i32, _transaction: &Extrinsic) -> Option<Hash> {
+		None
+	}
+
+	fn on_broadcasted(&self, _: HashMap<Hash, Vec<String>>) {}
+}
+
+fn pool() -> FreshPool {
+	let extrinsics = (0..POOL_SIZE as u64).map(|nonce| {
+		let transfer = Transfer {
+			from: Keyring::Alice.to_raw_public().into(),
+			to: Keyring::Bob.to_raw_public().into(),
+			amount: 1,
+			nonce,
+		};
+		let signature = Keyring::Alice.sign(&transfer.encode()).into();
+		Extrinsic { transfer, signature }
+	}).collect();
+	FreshPool { extrinsics, round: AtomicUsize::new(0) }
+}
+
+fn propagate_to_many_peers(c: &mut Criterion) {
+	let config = ProtocolConfig::default();
+	let mut net = TestNet::from_config(&config);
+	net.add_peer_with_pool(&config, Arc::new(pool()));
+	net.add_peer(&config);
+
+	// the status of the second peer is replayed to connect many peers to the first one
+	let status = {
+		let remote = net.peer(1);
+		remote.sync.on_peer_connected(&mut TestIo::new(&remote.queue, None), 0);
+		let packet = remote.queue.write().pop_front().expect("Status is sent on connection");
+		packet.data
+	};
+
+	c.bench_function("propagate_extrinsics", move |b| {
+		let peer = net.peer(0);
+		b.iter_with_setup(|| {
+			let mut io = TestIo::new(&peer.queue, None);
+			for who in 1..PEERS + 1 {
+				peer.sync.on_peer_disconnected(&mut io, who);
+				peer.sync.handle_packet(&mut io, who, &status).ok();
+			}
+		}, |_| {
+			peer.sync.propagate_extrinsics(&mut TestIo::new(&peer.queue, None));
+			peer.queue.write().clear();
+		})
+	});
+}
+
+criterion_group!(benches, propagate_to_many_peers);
+criterion_main!(benches);
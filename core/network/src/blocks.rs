@@ -213,6 +213,7 @@ mod test {
 			message_queue: None,
 			receipt: None,
 			justification: None,
+			compact_body: None,
 		}).collect()
 	}
 
@@ -50,6 +50,9 @@ pub trait Client<Block: BlockT>: Send + Sync {
 	/// Get block justification.
 	fn justification(&self, id: &BlockId<Block>) -> Result<Option<Justification>, Error>;
 
+	/// Get the authority set at the given block.
+	fn authorities(&self, id: &BlockId<Block>) -> Result<Vec<AuthorityId>, Error>;
+
 	/// Get block header proof.
 	fn header_proof(&self, block_number: <Block::Header as HeaderT>::Number) -> Result<(Block::Header, Vec<Vec<u8>>), Error>;
 
@@ -107,6 +110,10 @@ impl<B, E, Block, RA> Client<Block> for SubstrateClient<B, E, Block, RA> where
 		(self as &SubstrateClient<B, E, Block, RA>).justification(id)
 	}
 
+	fn authorities(&self, id: &BlockId<Block>) -> Result<Vec<AuthorityId>, Error> {
+		(self as &SubstrateClient<B, E, Block, RA>).authorities_at(id)
+	}
+
 	fn header_proof(&self, block_number: <Block::Header as HeaderT>::Number) -> Result<(Block::Header, Vec<Vec<u8>>), Error> {
 		(self as &SubstrateClient<B, E, Block, RA>).header_proof(&BlockId::Number(block_number))
 	}
@@ -18,12 +18,15 @@
 
 pub use network_libp2p::{NonReservedPeerMode, NetworkConfiguration};
 
+use network_libp2p::PeerId;
 use chain::Client;
 use codec;
+use message::BlockAttributes;
 use on_demand::OnDemandService;
 use runtime_primitives::traits::{Block as BlockT};
 use service::{ExHashT, TransactionPool};
 use std::sync::Arc;
+use std::time::Duration;
 
 /// Service initialization parameters.
 pub struct Params<B: BlockT, S, H: ExHashT> {
@@ -33,6 +36,9 @@ pub struct Params<B: BlockT, S, H: ExHashT> {
 	pub network_config: NetworkConfiguration,
 	/// Substrate relay chain access point.
 	pub chain: Arc<Client<B>>,
+	/// Read-only access to the chain, such as a database replica, used to serve blocks and
+	/// proofs to peers. `chain` is used when unset.
+	pub serving_chain: Option<Arc<Client<B>>>,
 	/// On-demand service reference.
 	pub on_demand: Option<Arc<OnDemandService<B>>>,
 	/// Transaction pool.
@@ -46,12 +52,239 @@ pub struct Params<B: BlockT, S, H: ExHashT> {
 pub struct ProtocolConfig {
 	/// Assigned roles.
 	pub roles: Roles,
+	/// Log every incoming consensus message with its sender, topic and hash. Intended for
+	/// debugging consensus stalls; too noisy for production use.
+	pub trace_consensus_messages: bool,
+	/// Minimal protocol version a peer must use to be served block bodies. Older peers are
+	/// still served headers.
+	pub min_version_for_block_bodies: u32,
+	/// The node is not expected to have any peers (e.g. a development node).
+	pub isolated: bool,
+	/// When set, peers are visited in an order derived from this seed when broadcasting
+	/// blocks and transactions, making propagation reproducible.
+	pub peer_order_seed: Option<u64>,
+	/// When set, extrinsics received from peers are imported on a background thread, with
+	/// up to this many batches waiting for import. Batches are imported in place on the
+	/// network thread when the queue is full.
+	pub extrinsics_import_queue: Option<usize>,
+	/// Time a peer is given to answer the smallest block request. Larger requests are given
+	/// more time, up to `max_block_request_timeout`.
+	pub min_block_request_timeout: Duration,
+	/// Upper bound of the time a peer is given to answer a block request.
+	pub max_block_request_timeout: Duration,
+	/// Allow peers to split large block responses into chunks, so that a lost chunk can be
+	/// requested again instead of the whole response.
+	pub chunked_block_responses: bool,
+	/// Treat messages of a type unknown to this version as a protocol breach. When unset, such
+	/// messages, most likely sent by newer peers, are ignored.
+	pub strict_messages: bool,
+	/// When set, requests for more than one block are answered with a busy response while more
+	/// than this many blocks are waiting for import, so that peers download from someone else.
+	pub busy_import_queue_len: Option<usize>,
+	/// Never request blocks from peers. Blocks are still served and announcements processed,
+	/// the chain is expected to be fed by other means.
+	pub observer_mode: bool,
+	/// Send a digest of our recent blocks to peers after the handshake, so that they can find
+	/// the common ancestor with fewer requests.
+	pub block_digests: bool,
+	/// Download block bodies as the hashes of their extrinsics and rebuild them from the
+	/// transaction pool. Bodies are downloaded in full only when extrinsics are missing.
+	pub compact_block_bodies: bool,
+	/// Coalesce consecutive messages sent to the same peer by a handler into a single packet.
+	/// Only useful when all peers understand batches.
+	pub batch_messages: bool,
+	/// Which peer sync downloads from first when several peers report the same best block number.
+	pub sync_tie_break: SyncTieBreak,
+	/// Peers served all optional requests, in addition to authorities.
+	pub trusted_peers: Vec<PeerId>,
+	/// Optional requests served to the other peers.
+	pub untrusted_peer_features: PeerFeatures,
+	/// When this many peers are connected, the lowest-scoring peer is disconnected whenever a
+	/// peer with a higher score connects, keeping room for better peers below the hard limit.
+	/// Of equally scored peers, the one without requests, responses or announcements for the
+	/// longest is disconnected.
+	pub soft_peer_limit: Option<usize>,
+	/// Reject block responses in which a block lacks its header or body while it was requested,
+	/// instead of importing incomplete blocks.
+	pub require_complete_blocks: bool,
+	/// Lower bound of the number of blocks served in a response. The number served to a peer
+	/// adapts, between the bounds, to how fast the peer receives our responses.
+	pub min_block_response_len: u32,
+	/// Upper bound of the number of blocks served in a response.
+	pub max_block_response_len: u32,
+	/// Decides what to do with peers whose genesis hash differs from ours. Peers on another
+	/// chain are disabled when unset.
+	pub genesis_mismatch_handler: Option<GenesisMismatchHandler>,
+	/// Download the headers of the chain of a peer before requesting the block bodies,
+	/// validating that the headers link to each other first.
+	pub headers_first_sync: bool,
+	/// When set, block announcements are buffered and delivered to sync together on the next
+	/// tick, or once this many are buffered, instead of one at a time.
+	pub announce_batch_size: Option<usize>,
+	/// Block attributes served to peers. Requested attributes missing from the mask are
+	/// omitted from our responses. `CHUNKED` and `BEST_EFFORT` are always honoured.
+	pub servable_block_attributes: BlockAttributes,
+	/// When set, at most this many connected peers are checked for timed out requests per
+	/// tick, resuming with the next peers on the following tick, so that maintaining many
+	/// peers doesn't hold up packet handling.
+	pub peers_maintained_per_tick: Option<usize>,
+	/// Sync is reported as stalled when we are behind our peers and our best block did not
+	/// advance for this long.
+	pub sync_stall_timeout: Option<Duration>,
+	/// Drop messages identical to one already sent to the same peer by the same handler.
+	pub dedup_context_messages: bool,
+	/// Peers sending consensus messages larger than this many bytes are disabled, and the
+	/// messages dropped before reaching consensus.
+	pub max_consensus_message_size: Option<usize>,
+	/// When set, block responses are limited to about this many bytes per tick. Requests
+	/// received over the budget are served on the next ticks, the requests of trusted and
+	/// higher-scoring peers first.
+	pub block_response_budget: Option<u64>,
+	/// Called for every transaction received from a peer and accepted by the transaction pool.
+	pub on_transaction_imported_hook: Option<TransactionImportedHook>,
+	/// When set, block requests are deferred while the block responses waiting in the send
+	/// queues of peers add up to this many bytes, and served on the next ticks.
+	pub max_in_flight_response_bytes: Option<u64>,
+	/// When set, the reputation peers earn through the extrinsics they supply decays toward
+	/// neutral over time.
+	pub reputation_decay: Option<ReputationDecay>,
+	/// Send the chunks of concurrent chunked block responses to their peers in turn, while the
+	/// send queues of the peers have room, instead of each response at once.
+	pub interleave_chunked_responses: bool,
+	/// When set, at most this many distinct fork heads announced by peers are tracked. The
+	/// heads announced by the fewest peers are forgotten first.
+	pub max_announced_forks: Option<usize>,
+	/// When set, at most this many block requests are in flight across all peers. Further
+	/// requests are deferred until a response arrives or a peer disconnects.
+	pub global_max_inflight_requests: Option<usize>,
+	/// When set, ready transactions last propagated longer than this ago are propagated again
+	/// on the next tick, to all peers, in case the peers dropped them.
+	pub extrinsics_repropagation_age: Option<Duration>,
+	/// Which block requests are served while we are syncing ourselves.
+	pub serve_during_sync: ServeDuringSync,
+	/// Called when the peers at our best block number all report another best block, see
+	/// `ProtocolStatus::on_minority_fork`.
+	pub on_minority_fork_hook: Option<MinorityForkHook>,
+}
+
+/// Handler of peers on another chain, given their ID and encoded genesis hash.
+pub type GenesisMismatchHandler = Arc<Fn(&PeerId, &[u8]) -> GenesisMismatchAction + Send + Sync>;
+
+/// Observer of the transactions imported from peers, given the encoded hash of the
+/// transaction and the ID of the peer it was received from.
+pub type TransactionImportedHook = Arc<Fn(&[u8], PeerId) + Send + Sync>;
+
+/// Observer of us getting on a minority fork, given the encoded hash of our best block.
+pub type MinorityForkHook = Arc<Fn(&[u8]) + Send + Sync>;
+
+/// What to do with a peer whose genesis hash differs from ours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenesisMismatchAction {
+	/// Report the peer and disconnect it.
+	Disable,
+	/// Keep the connection, but don't talk to the peer.
+	Ignore,
+	/// Keep the connection and pass the peer to the specialization, e.g. to bridge chains.
+	/// The peer is not added to the connected peers.
+	RouteToSpecialization,
+}
+
+/// Order in which sync asks peers reporting the same best block number for blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncTieBreak {
+	/// No preference.
+	Arbitrary,
+	/// Prefer the peer whose best block we heard of first.
+	FirstSeen,
+	/// Order peers pseudo-randomly, based on the given seed.
+	Random(u64),
+}
+
+/// Block requests served while we are syncing, see `ProtocolConfig::serve_during_sync`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServeDuringSync {
+	/// Answer all block requests as busy.
+	Never,
+	/// Serve the headers of the requested blocks, but nothing else.
+	HeadersOnly,
+	/// Serve block requests as when synced.
+	Always,
+}
+
+/// Decay of peer reputations toward neutral, see `ProtocolConfig::reputation_decay`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReputationDecay {
+	/// Time between two decay steps.
+	pub interval: Duration,
+	/// Fraction of its reputation a peer loses at every step, between 0 and 1.
+	pub fraction: f64,
 }
 
 impl Default for ProtocolConfig {
 	fn default() -> ProtocolConfig {
 		ProtocolConfig {
 			roles: Roles::FULL,
+			trace_consensus_messages: false,
+			min_version_for_block_bodies: 0,
+			isolated: false,
+			peer_order_seed: None,
+			extrinsics_import_queue: None,
+			min_block_request_timeout: Duration::from_secs(40),
+			max_block_request_timeout: Duration::from_secs(120),
+			chunked_block_responses: false,
+			strict_messages: false,
+			busy_import_queue_len: None,
+			observer_mode: false,
+			block_digests: false,
+			compact_block_bodies: false,
+			batch_messages: false,
+			sync_tie_break: SyncTieBreak::Arbitrary,
+			trusted_peers: Vec::new(),
+			untrusted_peer_features: PeerFeatures::all(),
+			soft_peer_limit: None,
+			require_complete_blocks: false,
+			min_block_response_len: 128,
+			max_block_response_len: 128,
+			genesis_mismatch_handler: None,
+			headers_first_sync: false,
+			announce_batch_size: None,
+			servable_block_attributes: BlockAttributes::all(),
+			peers_maintained_per_tick: None,
+			sync_stall_timeout: Some(Duration::from_secs(120)),
+			dedup_context_messages: false,
+			max_consensus_message_size: None,
+			block_response_budget: None,
+			on_transaction_imported_hook: None,
+			max_in_flight_response_bytes: None,
+			reputation_decay: None,
+			interleave_chunked_responses: false,
+			max_announced_forks: None,
+			global_max_inflight_requests: None,
+			extrinsics_repropagation_age: None,
+			serve_during_sync: ServeDuringSync::Always,
+			on_minority_fork_hook: None,
+		}
+	}
+}
+
+/// Part of the `ProtocolConfig` that can be changed while the node is running, see
+/// `Protocol::update_config`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TunableProtocolConfig {
+	/// See `ProtocolConfig::trace_consensus_messages`.
+	pub trace_consensus_messages: bool,
+	/// See `ProtocolConfig::min_block_request_timeout`.
+	pub min_block_request_timeout: Duration,
+	/// See `ProtocolConfig::max_block_request_timeout`.
+	pub max_block_request_timeout: Duration,
+}
+
+impl<'a> From<&'a ProtocolConfig> for TunableProtocolConfig {
+	fn from(config: &'a ProtocolConfig) -> TunableProtocolConfig {
+		TunableProtocolConfig {
+			trace_consensus_messages: config.trace_consensus_messages,
+			min_block_request_timeout: config.min_block_request_timeout,
+			max_block_request_timeout: config.max_block_request_timeout,
 		}
 	}
 }
@@ -81,3 +314,19 @@ impl codec::Decode for Roles {
 		Self::from_bits(input.read_byte()?)
 	}
 }
+
+bitflags! {
+	/// Optional requests a peer is served.
+	pub struct PeerFeatures: u8 {
+		/// Remote call proofs.
+		const REMOTE_CALL = 0b00000001;
+		/// Remote storage read proofs.
+		const REMOTE_READ = 0b00000010;
+		/// Remote headers.
+		const REMOTE_HEADER = 0b00000100;
+		/// Remote changes proofs.
+		const REMOTE_CHANGES = 0b00001000;
+		/// Current authority set.
+		const AUTHORITY_SET = 0b00010000;
+	}
+}
@@ -149,7 +149,8 @@ impl<B: BlockT> ConsensusGossip<B> {
 
 	/// Prune old or no longer relevant consensus messages. Provide a predicate
 	/// for pruning, which returns `false` when the items with a given topic should be pruned.
-	pub fn collect_garbage<P: Fn(&B::Hash) -> bool>(&mut self, predicate: P) {
+	/// Returns the number of pruned messages.
+	pub fn collect_garbage<P: Fn(&B::Hash) -> bool>(&mut self, predicate: P) -> usize {
 		self.live_message_sinks.retain(|_, sink| !sink.is_closed());
 
 		let hashes = &mut self.known_messages;
@@ -167,6 +168,7 @@ impl<B: BlockT> ConsensusGossip<B> {
 		for (_, ref mut peer) in self.peers.iter_mut() {
 			peer.known_messages.retain(|h| hashes.contains(h));
 		}
+		before - self.messages.len()
 	}
 
 	/// Get all incoming messages for a topic.
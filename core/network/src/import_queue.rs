@@ -32,6 +32,7 @@ use network_libp2p::{NodeIndex, Severity};
 use primitives::AuthorityId;
 
 use runtime_primitives::traits::{Block as BlockT, Header as HeaderT, NumberFor, Zero};
+use runtime_primitives::Justification;
 
 pub use blocks::BlockData;
 use client::error::Error as ClientError;
@@ -84,6 +85,11 @@ pub trait ImportQueue<B: BlockT>: Send + Sync {
 	fn is_importing(&self, hash: &B::Hash) -> bool;
 	/// Import bunch of blocks.
 	fn import_blocks(&self, origin: BlockOrigin, blocks: Vec<BlockData<B>>);
+	/// Verify a justification received apart from its block, and hand it to the block import.
+	/// Returns whether it has been accepted. Queues without a verifier reject it.
+	fn import_justification(&self, _header: B::Header, _body: Option<Vec<B::Extrinsic>>, _justification: Justification) -> bool {
+		false
+	}
 }
 
 /// Import queue status. It isn't completely accurate.
@@ -204,6 +210,10 @@ impl<B: BlockT, V: 'static + Verifier<B>> ImportQueue<B> for BasicQueue<B, V> {
 		queue.push_back((origin, blocks));
 		self.data.signal.notify_one();
 	}
+
+	fn import_justification(&self, header: B::Header, body: Option<Vec<B::Extrinsic>>, justification: Justification) -> bool {
+		import_single_justification(&*self.block_import, header, body, justification, &*self.verifier)
+	}
 }
 
 impl<B: BlockT, V: 'static + Verifier<B>> Drop for BasicQueue<B, V> {
@@ -471,6 +481,44 @@ fn import_single_block<B: BlockT, V: Verifier<B>>(
 	}
 }
 
+/// Single justification import function, for a block which has already been imported.
+fn import_single_justification<B: BlockT, V: Verifier<B>>(
+	import_handle: &BlockImport<B,Error=ClientError>,
+	header: B::Header,
+	body: Option<Vec<B::Extrinsic>>,
+	justification: Justification,
+	verifier: &V
+) -> bool
+{
+	let number = header.number().clone();
+	let hash = header.hash();
+	let (import_block, new_authorities) = match verifier.verify(BlockOrigin::NetworkBroadcast, header, justification, body) {
+		Ok(verified) => verified,
+		Err(msg) => {
+			trace!(target: "sync", "Verifying justification of {}({}) failed: {}", number, hash, msg);
+			return false;
+		},
+	};
+
+	// a justification of a known block is only stored when it finalizes the block.
+	if !import_block.finalized {
+		trace!(target: "sync", "Justification of {}({}) does not finalize it", number, hash);
+		return false;
+	}
+
+	match import_handle.import_block(import_block, new_authorities) {
+		Ok(ImportResult::AlreadyInChain) | Ok(ImportResult::AlreadyQueued) | Ok(ImportResult::Queued) => true,
+		Ok(result) => {
+			debug!(target: "sync", "Justification of {}({}) not imported: {:?}", number, hash, result);
+			false
+		},
+		Err(e) => {
+			debug!(target: "sync", "Error importing justification of {}: {:?}: {:?}", number, hash, e);
+			false
+		},
+	}
+}
+
 /// Process single block import result.
 fn process_import_result<B: BlockT>(
 	link: &Link<B>,
@@ -646,6 +694,10 @@ impl<B: 'static + BlockT, V: 'static + Verifier<B>> ImportQueue<B> for SyncImpor
 	fn import_blocks(&self, origin: BlockOrigin, blocks: Vec<BlockData<B>>) {
 		self.link.call(origin, blocks);
 	}
+
+	fn import_justification(&self, header: B::Header, body: Option<Vec<B::Extrinsic>>, justification: Justification) -> bool {
+		import_single_justification(&*self.block_import, header, body, justification, &*self.verifier)
+	}
 }
 
 #[cfg(test)]
@@ -712,6 +764,7 @@ pub mod tests {
 			receipt: None,
 			message_queue: None,
 			justification: client.justification(&BlockId::Number(1)).unwrap(),
+			compact_body: None,
 		};
 
 		(client, hash, number, BlockData { block, origin: Some(0) })
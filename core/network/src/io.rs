@@ -23,14 +23,18 @@ use std::sync::Arc;
 pub trait SyncIo {
 	/// Report a peer for misbehaviour.
 	fn report_peer(&mut self, who: NodeIndex, reason: Severity);
-	/// Send a packet to a peer.
-	fn send(&mut self, who: NodeIndex, data: Vec<u8>);
+	/// Send a packet to a peer. Returns `false` if the packet could not be queued.
+	fn send(&mut self, who: NodeIndex, data: Vec<u8>) -> bool;
 	/// Returns peer identifier string
 	fn peer_debug_info(&self, who: NodeIndex) -> String {
 		who.to_string()
 	}
 	/// Returns information on p2p session
 	fn peer_id(&self, who: NodeIndex) -> Option<PeerId>;
+	/// Returns the number of packets waiting to be sent to a peer.
+	fn send_queue_len(&self, _who: NodeIndex) -> usize {
+		0
+	}
 }
 
 /// Wraps the network service.
@@ -59,7 +63,7 @@ impl<'s> SyncIo for NetSyncIo<'s> {
 		}
 	}
 
-	fn send(&mut self, who: NodeIndex, data: Vec<u8>) {
+	fn send(&mut self, who: NodeIndex, data: Vec<u8>) -> bool {
 		self.network.lock().send_custom_message(who, self.protocol, data)
 	}
 
@@ -76,4 +80,8 @@ impl<'s> SyncIo for NetSyncIo<'s> {
 			"unknown".to_string()
 		}
 	}
+
+	fn send_queue_len(&self, who: NodeIndex) -> usize {
+		self.network.lock().send_queue_len(who)
+	}
 }
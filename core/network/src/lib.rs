@@ -36,10 +36,15 @@ extern crate tokio;
 #[macro_use] extern crate bitflags;
 #[macro_use] extern crate error_chain;
 #[macro_use] extern crate parity_codec_derive;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
 
 #[cfg(test)]
 extern crate env_logger;
 
+#[cfg(test)]
+extern crate serde_json;
+
 #[cfg(any(test, feature = "test-helpers"))]
 extern crate substrate_keyring as keyring;
 
@@ -66,7 +71,8 @@ pub mod test;
 
 pub use chain::Client as ClientHandle;
 pub use service::{Service, FetchFuture, TransactionPool, ManageNetwork, SyncProvider, ExHashT};
-pub use protocol::{ProtocolStatus, PeerInfo, Context};
+pub use protocol::{ProtocolStatus, ProtocolMetrics, BlockResponseSizes, Health, PeerInfo, Context, PropagationSnapshot, KnownItems, PeerSession,
+	PacketError, TickOutcome, ProtocolSnapshot, PeerSnapshot, RequestSnapshot, SyncSnapshot};
 pub use sync::{Status as SyncStatus, SyncState};
 pub use network_libp2p::{NodeIndex, ProtocolId, Severity, Protocol};
 pub use message::{generic as generic_message, RequestId, Status as StatusMessage};
@@ -22,7 +22,7 @@ pub use self::generic::{
 	BlockAnnounce, RemoteCallRequest, RemoteReadRequest,
 	RemoteHeaderRequest, RemoteHeaderResponse,
 	RemoteChangesRequest, RemoteChangesResponse,
-	FromBlock
+	AuthoritySetResponse, FromBlock, GoodbyeReason
 };
 
 /// A unique ID of a request.
@@ -42,6 +42,12 @@ pub type Status<B> = generic::Status<
 	<<B as BlockT>::Header as HeaderT>::Number,
 >;
 
+/// Type alias for using the compact status type using block type parameters.
+pub type CompactStatus<B> = generic::CompactStatus<
+	<B as BlockT>::Hash,
+	<<B as BlockT>::Header as HeaderT>::Number,
+>;
+
 /// Type alias for using the block request type using block type parameters.
 pub type BlockRequest<B> = generic::BlockRequest<
 	<B as BlockT>::Hash,
@@ -63,9 +69,39 @@ pub type BlockResponse<B> = generic::BlockResponse<
 	<B as BlockT>::Extrinsic,
 >;
 
+/// Type alias for using the BlockResponseChunk type using block type parameters.
+pub type BlockResponseChunk<B> = generic::BlockResponseChunk<
+	<B as BlockT>::Header,
+	<B as BlockT>::Hash,
+	<B as BlockT>::Extrinsic,
+>;
+
 /// A set of transactions.
 pub type Transactions<E> = Vec<E>;
 
+/// Index of the last `Message` variant known to this version, not counting `ChainSpecific`.
+/// Must be updated when adding a variant.
+pub(crate) const LAST_MESSAGE_INDEX: u8 = 22;
+
+/// Index of the `Batch` message variant.
+pub(crate) const BATCH_MESSAGE_INDEX: u8 = 17;
+
+/// Index of the `Status` message variant.
+pub(crate) const STATUS_MESSAGE_INDEX: u8 = 0;
+
+/// Index of the `BlockResponse` message variant.
+pub(crate) const BLOCK_RESPONSE_MESSAGE_INDEX: u8 = 2;
+
+/// Index of the last `Message` variant known to protocol version 1, not counting
+/// `ChainSpecific`.
+pub(crate) const V1_LAST_MESSAGE_INDEX: u8 = 13;
+
+/// Whether the encoded message starts with the index of a variant unknown to this version,
+/// i.e. was most likely sent by a newer peer.
+pub(crate) fn is_future_message(data: &[u8]) -> bool {
+	data.first().map_or(false, |index| *index > LAST_MESSAGE_INDEX && *index != 255)
+}
+
 /// Bits of block data and associated artefacts to request.
 bitflags! {
 	/// Node roles bitmask.
@@ -80,6 +116,12 @@ bitflags! {
 		const MESSAGE_QUEUE = 0b00001000;
 		/// Include a justification for the block.
 		const JUSTIFICATION = 0b00010000;
+		/// Include the hashes of the block extrinsics instead of the extrinsics themselves.
+		const COMPACT_BODY = 0b00100000;
+		/// Large responses may be split into `BlockResponseChunk`s.
+		const CHUNKED = 0b01000000;
+		/// Only include recent blocks, which can be served without deep reads.
+		const BEST_EFFORT = 0b10000000;
 	}
 }
 
@@ -113,6 +155,59 @@ pub struct RemoteCallResponse {
 	pub proof: Vec<Vec<u8>>,
 }
 
+/// Request to resend some chunks of a chunked block response.
+#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+pub struct BlockChunkRequest {
+	/// Id of the block request the chunks were sent for.
+	pub id: RequestId,
+	/// Indices of the chunks to resend.
+	pub chunks: Vec<u32>,
+}
+
+/// Number of the most recent blocks of the sender's best chain included in a `BlockDigest`.
+pub(crate) const BLOCK_DIGEST_DEPTH: u64 = 256;
+/// Size in bytes of the `BlockDigest` we send.
+pub(crate) const BLOCK_DIGEST_SIZE: usize = 512;
+/// Maximum size in bytes of a `BlockDigest` we accept.
+pub(crate) const MAX_BLOCK_DIGEST_SIZE: usize = 4096;
+/// Number of bits set in a `BlockDigest` for every hash.
+const BLOCK_DIGEST_HASH_BITS: usize = 3;
+
+/// Bloom filter of the hashes of the recent blocks of the sender's best chain, sent after the
+/// handshake. May report hashes which were not inserted, but never misses one which was.
+#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+pub struct BlockDigest {
+	/// Filter bits.
+	pub bits: Vec<u8>,
+}
+
+impl BlockDigest {
+	/// Create an empty digest of `size` bytes. `size` must not be 0.
+	pub fn new(size: usize) -> Self {
+		BlockDigest { bits: vec![0; size] }
+	}
+
+	/// Add a block hash.
+	pub fn insert(&mut self, hash: &[u8]) {
+		for bit in self.bit_positions(hash) {
+			self.bits[bit / 8] |= 1 << (bit % 8);
+		}
+	}
+
+	/// Whether the block hash may have been added.
+	pub fn contains(&self, hash: &[u8]) -> bool {
+		self.bit_positions(hash).into_iter().all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+	}
+
+	fn bit_positions(&self, hash: &[u8]) -> Vec<usize> {
+		let len = self.bits.len() * 8;
+		hash.chunks(4)
+			.take(BLOCK_DIGEST_HASH_BITS)
+			.map(|chunk| chunk.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize) % len)
+			.collect()
+	}
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
 /// Remote read response.
 pub struct RemoteReadResponse {
@@ -122,13 +217,21 @@ pub struct RemoteReadResponse {
 	pub proof: Vec<Vec<u8>>,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+/// Current authority set request.
+pub struct AuthoritySetRequest {
+	/// Unique request id.
+	pub id: RequestId,
+}
+
 /// Generic types.
 pub mod generic {
 	use runtime_primitives::Justification;
+	use primitives::AuthorityId;
 	use config::Roles;
 	use super::{
-		BlockAttributes, RemoteCallResponse, RemoteReadResponse,
-		RequestId, Transactions, Direction
+		AuthoritySetRequest, BlockAttributes, BlockChunkRequest, BlockDigest, RemoteCallResponse,
+		RemoteReadResponse, RequestId, Transactions, Direction
 	};
 	/// Consensus is opaque to us
 	pub type ConsensusMessage = Vec<u8>;
@@ -148,6 +251,8 @@ pub mod generic {
 		pub message_queue: Option<Vec<u8>>,
 		/// Justification if requested.
 		pub justification: Option<Justification>,
+		/// Hashes of the block extrinsics if a compact body is requested.
+		pub compact_body: Option<Vec<Hash>>,
 	}
 
 	/// Identifies starting point of a block sequence.
@@ -190,6 +295,24 @@ pub mod generic {
 		RemoteChangesRequest(RemoteChangesRequest<Hash>),
 		/// Remote changes reponse.
 		RemoteChangesResponse(RemoteChangesResponse<Number, Hash>),
+		/// Part of a block response.
+		BlockResponseChunk(BlockResponseChunk<Header, Hash, Extrinsic>),
+		/// Request to resend parts of a block response.
+		BlockChunkRequest(BlockChunkRequest),
+		/// Digest of the recent blocks of the sender.
+		BlockDigest(BlockDigest),
+		/// Several encoded messages sent to the same peer at once.
+		Batch(Vec<Vec<u8>>),
+		/// Lowest block the sender still serves, sent when it prunes older blocks.
+		PruningNotification(PruningNotification<Number>),
+		/// Sent before closing the connection, with the reason.
+		Goodbye(GoodbyeReason),
+		/// Current authority set request.
+		AuthoritySetRequest(AuthoritySetRequest),
+		/// Current authority set response.
+		AuthoritySetResponse(AuthoritySetResponse<Hash, Number>),
+		/// Status sent on connection by light clients.
+		CompactStatus(CompactStatus<Hash, Number>),
 		/// Chain-specific message
 		#[codec(index = "255")]
 		ChainSpecific(Vec<u8>),
@@ -210,6 +333,20 @@ pub mod generic {
 		pub genesis_hash: Hash,
 		/// Chain-specific status.
 		pub chain_status: Vec<u8>,
+		/// Session nonce. Grows each time the node restarts, so that a fresh session on the
+		/// same connection can be told apart from a duplicated status.
+		pub session_nonce: u64,
+	}
+
+	/// Status sent on connection by light clients, with the fields a light peer needs only.
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	pub struct CompactStatus<Hash, Number> {
+		/// Protocol version.
+		pub version: u32,
+		/// Genesis block hash.
+		pub genesis_hash: Hash,
+		/// Best block number.
+		pub best_number: Number,
 	}
 
 	/// Request block data from a peer.
@@ -236,6 +373,42 @@ pub mod generic {
 		pub id: RequestId,
 		/// Block data for the requested sequence.
 		pub blocks: Vec<BlockData<Header, Hash, Extrinsic>>,
+		/// Proof that the highest block of the sequence is finalized, if justifications were
+		/// requested and the block is finalized.
+		pub finality_proof: Option<FinalityProof<Header>>,
+		/// The request was not served because the responder is too busy. It should be retried
+		/// with another peer.
+		pub busy: bool,
+		/// Number of the block at which the chain of the responder stops extending the fork
+		/// the request started from. Blocks from this number on are not part of the response.
+		pub fork_divergence: Option<u64>,
+	}
+
+	/// Proof that a block is finalized: the justification of a finalized descendant.
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	pub struct FinalityProof<Header> {
+		/// Header of the finalized descendant.
+		pub header: Header,
+		/// Justification of the finalized descendant.
+		pub justification: Justification,
+	}
+
+	/// Part of a response to `BlockRequest`, sent instead of a `BlockResponse` when the request
+	/// allows `BlockAttributes::CHUNKED`.
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	pub struct BlockResponseChunk<Header, Hash, Extrinsic> {
+		/// Id of a request this chunk was made for.
+		pub id: RequestId,
+		/// Index of this chunk.
+		pub index: u32,
+		/// Total number of chunks of the response.
+		pub count: u32,
+		/// Block data for this part of the requested sequence.
+		pub blocks: Vec<BlockData<Header, Hash, Extrinsic>>,
+		/// Finality proof of the response, only sent with the last chunk.
+		pub finality_proof: Option<FinalityProof<Header>>,
+		/// Fork divergence of the response, only sent with the last chunk.
+		pub fork_divergence: Option<u64>,
 	}
 
 	/// Announce a new complete relay chain block on the network.
@@ -269,6 +442,39 @@ pub mod generic {
 		pub key: Vec<u8>,
 	}
 
+	/// Notification that the sender pruned its older blocks.
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	pub struct PruningNotification<Number> {
+		/// Number of the lowest block the sender still serves.
+		pub available_from: Number,
+	}
+
+	/// Reason for closing a connection, given to the peer.
+	#[derive(Debug, PartialEq, Eq, Clone, Copy, Encode, Decode)]
+	pub enum GoodbyeReason {
+		/// The node is shutting down.
+		Shutdown,
+		/// The node has better peers to connect to.
+		TooManyPeers,
+		/// The peer is of no use to the node.
+		Useless,
+		/// The peer is banned for a while.
+		Banned,
+	}
+
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	/// Current authority set response.
+	pub struct AuthoritySetResponse<Hash, Number> {
+		/// Id of a request this response was made for.
+		pub id: RequestId,
+		/// Number of the best block the set was read at, identifying its epoch.
+		pub number: Number,
+		/// Hash of the best block the set was read at.
+		pub hash: Hash,
+		/// Authorities of the set. Empty if the request was not served.
+		pub authorities: Vec<AuthorityId>,
+	}
+
 	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
 	/// Remote header request.
 	pub struct RemoteHeaderRequest<N> {
@@ -323,3 +529,122 @@ pub mod generic {
 		pub roots_proof: Vec<Vec<u8>>,
 	}
 }
+
+/// Messages in the format of protocol version 1, for the messages whose format changed since.
+pub mod v1 {
+	use runtime_primitives::Justification;
+	use config::Roles;
+	use super::{BlockAttributes, RequestId};
+	use super::generic;
+
+	/// Block attributes known to protocol version 1.
+	pub fn block_attributes() -> BlockAttributes {
+		BlockAttributes::HEADER | BlockAttributes::BODY | BlockAttributes::RECEIPT
+			| BlockAttributes::MESSAGE_QUEUE | BlockAttributes::JUSTIFICATION
+	}
+
+	/// Status sent on connection, without session nonce.
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	pub struct Status<Hash, Number> {
+		/// Protocol version.
+		pub version: u32,
+		/// Supported roles.
+		pub roles: Roles,
+		/// Best block number.
+		pub best_number: Number,
+		/// Best block hash.
+		pub best_hash: Hash,
+		/// Genesis block hash.
+		pub genesis_hash: Hash,
+		/// Chain-specific status.
+		pub chain_status: Vec<u8>,
+	}
+
+	impl<Hash, Number> From<Status<Hash, Number>> for generic::Status<Hash, Number> {
+		fn from(status: Status<Hash, Number>) -> Self {
+			generic::Status {
+				version: status.version,
+				roles: status.roles,
+				best_number: status.best_number,
+				best_hash: status.best_hash,
+				genesis_hash: status.genesis_hash,
+				chain_status: status.chain_status,
+				session_nonce: 0,
+			}
+		}
+	}
+
+	/// Block data sent in the response, without compact body.
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	pub struct BlockData<Header, Hash, Extrinsic> {
+		/// Block header hash.
+		pub hash: Hash,
+		/// Block header if requested.
+		pub header: Option<Header>,
+		/// Block body if requested.
+		pub body: Option<Vec<Extrinsic>>,
+		/// Block receipt if requested.
+		pub receipt: Option<Vec<u8>>,
+		/// Block message queue if requested.
+		pub message_queue: Option<Vec<u8>>,
+		/// Justification if requested.
+		pub justification: Option<Justification>,
+	}
+
+	impl<Header, Hash, Extrinsic> From<BlockData<Header, Hash, Extrinsic>> for generic::BlockData<Header, Hash, Extrinsic> {
+		fn from(data: BlockData<Header, Hash, Extrinsic>) -> Self {
+			generic::BlockData {
+				hash: data.hash,
+				header: data.header,
+				body: data.body,
+				receipt: data.receipt,
+				message_queue: data.message_queue,
+				justification: data.justification,
+				compact_body: None,
+			}
+		}
+	}
+
+	impl<Header, Hash, Extrinsic> From<generic::BlockData<Header, Hash, Extrinsic>> for BlockData<Header, Hash, Extrinsic> {
+		fn from(data: generic::BlockData<Header, Hash, Extrinsic>) -> Self {
+			BlockData {
+				hash: data.hash,
+				header: data.header,
+				body: data.body,
+				receipt: data.receipt,
+				message_queue: data.message_queue,
+				justification: data.justification,
+			}
+		}
+	}
+
+	/// Response to `BlockRequest`, with the block data only.
+	#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+	pub struct BlockResponse<Header, Hash, Extrinsic> {
+		/// Id of a request this response was made for.
+		pub id: RequestId,
+		/// Block data for the requested sequence.
+		pub blocks: Vec<BlockData<Header, Hash, Extrinsic>>,
+	}
+
+	impl<Header, Hash, Extrinsic> From<BlockResponse<Header, Hash, Extrinsic>> for generic::BlockResponse<Header, Hash, Extrinsic> {
+		fn from(response: BlockResponse<Header, Hash, Extrinsic>) -> Self {
+			generic::BlockResponse {
+				id: response.id,
+				blocks: response.blocks.into_iter().map(Into::into).collect(),
+				finality_proof: None,
+				busy: false,
+				fork_divergence: None,
+			}
+		}
+	}
+
+	impl<Header, Hash, Extrinsic> From<generic::BlockResponse<Header, Hash, Extrinsic>> for BlockResponse<Header, Hash, Extrinsic> {
+		fn from(response: generic::BlockResponse<Header, Hash, Extrinsic>) -> Self {
+			BlockResponse {
+				id: response.id,
+				blocks: response.blocks.into_iter().map(Into::into).collect(),
+			}
+		}
+	}
+}
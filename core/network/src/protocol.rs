@@ -14,15 +14,17 @@
 // You should have received a copy of the GNU General Public License
 // along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::{HashMap, HashSet, BTreeMap};
-use std::{mem, cmp};
-use std::sync::Arc;
-use std::time;
-use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
+use std::{fmt, mem, cmp};
+use std::sync::{mpsc, Arc};
+use std::{thread, time};
+use parking_lot::{Mutex, RwLock};
+use linked_hash_map::LinkedHashMap;
 use rustc_hex::ToHex;
-use runtime_primitives::traits::{Block as BlockT, Header as HeaderT, NumberFor, As, Zero};
+use runtime_primitives::traits::{Block as BlockT, Header as HeaderT, Hash as HashT, HashFor, NumberFor, As, Zero};
 use runtime_primitives::generic::BlockId;
-use network_libp2p::{NodeIndex, Severity};
+use runtime_primitives::Justification;
+use network_libp2p::{NodeIndex, PeerId, Severity};
 use codec::{Encode, Decode};
 
 use message::{self, Message};
@@ -31,39 +33,317 @@ use consensus_gossip::ConsensusGossip;
 use specialization::NetworkSpecialization;
 use sync::{ChainSync, Status as SyncStatus, SyncState};
 use service::{TransactionPool, ExHashT};
-use import_queue::ImportQueue;
-use config::{ProtocolConfig, Roles};
+use import_queue::{ImportQueue, ImportQueueStatus};
+use config::{GenesisMismatchAction, PeerFeatures, ProtocolConfig, ReputationDecay, ServeDuringSync, TunableProtocolConfig, Roles};
 use chain::Client;
+use client::{BlockStatus, ClientInfo};
 use client::light::fetcher::ChangesProof;
 use on_demand::OnDemandService;
 use io::SyncIo;
 use error;
 
 const REQUEST_TIMEOUT_SEC: u64 = 40;
+/// Extra time granted to a peer for every requested block, on top of the minimal timeout.
+const BLOCK_REQUEST_TIMEOUT_PER_HEADER_MS: u64 = 50;
+/// Extra time granted to a peer for every requested block body.
+const BLOCK_REQUEST_TIMEOUT_PER_BODY_MS: u64 = 500;
 
 /// Current protocol version.
-pub (crate) const CURRENT_VERSION: u32 = 1;
-
-// Maximum allowed entries in `BlockResponse`
+pub (crate) const CURRENT_VERSION: u32 = 3;
+/// Oldest protocol version we still talk to peers with.
+pub (crate) const MIN_VERSION: u32 = 1;
+/// First protocol version with `Batch` and `Goodbye` messages. Batched messages are sent
+/// one by one to older peers, which are disconnected without a goodbye.
+const BATCH_AND_GOODBYE_VERSION: u32 = 2;
+/// First protocol version with session nonces in `Status`, compact bodies in `BlockData`,
+/// and finality proofs, busy flags and fork divergences in `BlockResponse`, as well as all the
+/// messages added since version 1. Older peers are sent and send these messages in the format
+/// of `message::v1`, and aren't sent the messages they don't know.
+const EXTENDED_FORMATS_VERSION: u32 = 2;
+/// First protocol version with `CompactStatus` messages, which light clients may send to
+/// peers using it in place of a `Status`.
+pub (crate) const COMPACT_STATUS_VERSION: u32 = 3;
+
+// Default maximum of entries in `BlockResponse`, within `ProtocolConfig::{min,max}_block_response_len`
 const MAX_BLOCK_DATA_RESPONSE: u32 = 128;
 /// When light node connects to the full node and the full node is behind light node
 /// for at least `LIGHT_MAXIMAL_BLOCKS_DIFFERENCE` blocks, we consider it unuseful
 /// and disconnect to free connection slot.
 const LIGHT_MAXIMAL_BLOCKS_DIFFERENCE: u64 = 8192;
+/// Maximum number of imported blocks waiting for their body before being announced.
+const MAX_PENDING_ANNOUNCEMENTS: usize = 64;
+/// Maximum number of recently seen blocks for which we remember the first informing peer.
+const MAX_BLOCK_SOURCES: usize = 1024;
+/// Maximum number of peer extrinsics held back while a reorg is being processed.
+const MAX_DEFERRED_EXTRINSICS: usize = 4096;
+/// Maximum number of connections of a node within `RECONNECT_WINDOW_SEC` before it is put on cooldown.
+const MAX_RECONNECTS: usize = 5;
+/// Window over which connections of a node are counted.
+const RECONNECT_WINDOW_SEC: u64 = 60;
+/// Time during which connections of a flapping node are refused.
+const RECONNECT_COOLDOWN_SEC: u64 = 300;
+/// Maximum number of nodes for which the connection history is kept.
+const MAX_CONNECTION_HISTORY: usize = 1024;
+/// Number of blocks in each chunk of a chunked block response.
+const BLOCK_RESPONSE_CHUNK_SIZE: usize = 16;
+/// Maximum number of served chunked requests kept around for resending chunks.
+const MAX_SERVED_CHUNKED_REQUESTS: usize = 64;
+/// Time after the last received chunk before missing chunks are requested again.
+const CHUNK_RESEND_TIMEOUT_SEC: u64 = 5;
+/// Maximum depth below our best block of the blocks served to best-effort block requests.
+const BEST_EFFORT_MAX_DEPTH: u64 = 256;
+/// Number of attempts at reading the chain info when starting up.
+const CHAIN_INFO_ATTEMPTS: usize = 5;
+/// Delay between two attempts at reading the chain info.
+const CHAIN_INFO_RETRY_DELAY_MS: u64 = 200;
+/// Maximum number of recently propagated extrinsics remembered.
+const MAX_PROPAGATED_EXTRINSICS: usize = 4096;
+/// Maximum number of block announcements buffered for a node which has not completed the handshake.
+const MAX_EARLY_ANNOUNCEMENTS: usize = 8;
+/// Maximum number of nodes for which early block announcements are buffered.
+const MAX_EARLY_ANNOUNCING_NODES: usize = 64;
+/// Time after which a buffered early block announcement is dropped.
+const EARLY_ANNOUNCEMENT_TIMEOUT_SEC: u64 = 10;
+/// Maximum number of responses with an unexpected request id a peer may send.
+const MAX_MISMATCHED_RESPONSES: u32 = 8;
+/// Block requests asking for more blocks than this, far above what we serve in a response, are
+/// suspicious: they are still served, but count against the requesting peer.
+const SUSPICIOUS_REQUEST_MAX: u32 = 1 << 16;
+/// Maximum number of distinct fork heads a peer may announce.
+const MAX_ANNOUNCED_FORKS: usize = 16;
+/// Fork heads more than this many blocks behind the latest announcement of a peer are forgotten.
+const ANNOUNCED_FORK_DEPTH: u64 = 64;
+/// Peers reporting a best block more than this many blocks below the one they reported before
+/// are lying, such a deep reorg is not plausible.
+const MAX_BEST_REGRESSION: u64 = 1024;
+/// Peers are notified of pruning when the lowest block we serve advanced by at least this many blocks.
+const PRUNING_NOTIFICATION_INTERVAL: u64 = 256;
+/// Peers with at least this many packets waiting to be sent are served fewer blocks per response.
+const SLOW_PEER_SEND_QUEUE_LEN: usize = 4;
+/// Score of authorities on top of the score earned through their behaviour.
+const AUTHORITY_SCORE: i64 = 1_000;
+/// Maximum number of recently served blocks kept to answer further requests.
+const MAX_CACHED_SERVED_BLOCKS: usize = 256;
+/// Maximum number of recently imported extrinsics checked for pool hash collisions.
+const MAX_IMPORTED_EXTRINSIC_DIGESTS: usize = 4096;
+/// How long `tick` waits for the consensus gossip lock before skipping its garbage collection,
+/// so that consensus holding the lock doesn't stall the network thread.
+const CONSENSUS_GC_LOCK_TIMEOUT_MS: u64 = 100;
+/// Interleaved response chunks are only sent to peers with fewer packets than this waiting
+/// to be sent, the other chunks wait for the queue to drain.
+const MAX_QUEUED_CHUNKS: usize = 2;
 
 // Lock must always be taken in order declared here.
 pub struct Protocol<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> {
 	config: ProtocolConfig,
+	// Runtime-tunable part of the config, takes precedence over the same fields of `config`.
+	tunable_config: RwLock<TunableProtocolConfig>,
+	// Config update to be applied on the next tick.
+	pending_config: Mutex<Option<TunableProtocolConfig>>,
 	on_demand: Option<Arc<OnDemandService<B>>>,
 	genesis_hash: B::Hash,
+	session_nonce: u64,
 	sync: Arc<RwLock<ChainSync<B>>>,
 	specialization: RwLock<S>,
 	consensus_gossip: RwLock<ConsensusGossip<B>>,
 	context_data: ContextData<B, H>,
+	// Read-only client serving blocks and proofs to peers, instead of `context_data.chain`.
+	serving_chain: Option<Arc<Client<B>>>,
 	// Connected peers pending Status message.
 	handshaking_peers: RwLock<HashMap<NodeIndex, time::Instant>>,
 	transaction_pool: Arc<TransactionPool<H, B>>,
+	extrinsics_importer: Option<ExtrinsicsImporter<B, H>>,
+	// Hash of the last block passed to `on_block_imported`.
+	last_imported: RwLock<Option<B::Hash>>,
+	// Extrinsics received while a reorg is being processed. `Some` until the next tick after a reorg.
+	deferred_extrinsics: RwLock<Option<Vec<(NodeIndex, message::Transactions<B::Extrinsic>)>>>,
+	// Imported blocks that can't be announced until their body is available.
+	pending_announcements: RwLock<Vec<(B::Hash, B::Header)>>,
+	// First peer that informed us about each recently seen block.
+	block_sources: RwLock<LinkedHashMap<B::Hash, NodeIndex>>,
+	// Recent connections of each node, kept across sessions to detect flapping nodes.
+	connection_history: RwLock<LinkedHashMap<PeerId, ConnectionHistory>>,
+	// Chunks of recently served chunked block responses, to resend lost chunks.
+	served_chunked_responses: RwLock<LinkedHashMap<(NodeIndex, message::RequestId), Vec<Message<B>>>>,
+	// Block announcements received from nodes before their Status, replayed after the handshake.
+	early_announcements: RwLock<LinkedHashMap<PeerId, Vec<(time::Instant, message::BlockAnnounce<B::Header>)>>>,
+	// Recently propagated extrinsics, sent to at least one peer, with the time they were last sent.
+	propagated_extrinsics: RwLock<LinkedHashMap<H, time::Instant>>,
+	// Items known to peers before a restart, given to them again when they reconnect.
+	restored_propagation: RwLock<HashMap<PeerId, KnownItems<B, H>>>,
+	// Lowest block we serve, as last notified to peers.
+	notified_pruning: RwLock<Option<NumberFor<B>>>,
+	// Block announcements waiting to be delivered to sync, in the order they were received.
+	pending_announces: RwLock<Vec<(NodeIndex, B::Hash, B::Header)>>,
+	// Bodies and justifications of recently served blocks, least recently served first.
+	served_blocks: RwLock<LinkedHashMap<B::Hash, message::BlockData<B>>>,
+	// Digests of the encoding of recently imported extrinsics, by pool hash.
+	imported_extrinsic_digests: RwLock<LinkedHashMap<H, B::Hash>>,
+	// First peer checked by the next `maintain_peers`, when peers are checked incrementally.
+	maintenance_cursor: RwLock<NodeIndex>,
+	// Nodes whose connections are refused, with the end of their ban.
+	bans: RwLock<HashMap<PeerId, time::Instant>>,
+	// Bytes of block responses served since the last tick, when responses are budgeted.
+	served_response_bytes: RwLock<u64>,
+	// Block requests received over the response budget, waiting to be served.
+	deferred_block_requests: RwLock<Vec<(NodeIndex, message::BlockRequest<B>)>>,
+	// Sizes of the block responses which may still be waiting in the send queue of each peer,
+	// oldest first, when in-flight responses are limited.
+	in_flight_responses: RwLock<HashMap<NodeIndex, VecDeque<u64>>>,
+	// Chunks of block responses waiting for their turn to be sent, by peer, when interleaved.
+	pending_chunks: RwLock<VecDeque<(NodeIndex, VecDeque<Message<B>>)>>,
+	// Whether we were on a minority fork at the last tick.
+	on_minority_fork: RwLock<bool>,
+}
+
+/// Recent connections of a node.
+#[derive(Default)]
+struct ConnectionHistory {
+	/// Times of the connections within the last `RECONNECT_WINDOW_SEC`.
+	connected: VecDeque<time::Instant>,
+	/// Connections are refused until then.
+	cooldown_until: Option<time::Instant>,
+}
+
+/// Blocks and extrinsics known to a peer.
+#[derive(Debug, Clone)]
+pub struct KnownItems<B: BlockT, H: ExHashT> {
+	/// Extrinsics known to the peer.
+	pub extrinsics: HashSet<H>,
+	/// Blocks known to the peer.
+	pub blocks: HashSet<B::Hash>,
+}
+
+/// Items known to the connected peers, exported before a restart so that they are not
+/// propagated again to the same peers once they reconnect.
+#[derive(Debug, Clone)]
+pub struct PropagationSnapshot<B: BlockT, H: ExHashT> {
+	/// Known items, by peer.
+	pub peers: HashMap<PeerId, KnownItems<B, H>>,
+}
+
+/// Protocol counters, keyed by metric name.
+pub type ProtocolMetrics = HashMap<&'static str, u64>;
+
+/// Average number of blocks per block response, `None` until a response has been seen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockResponseSizes {
+	/// Average over the responses served to peers.
+	pub served: Option<f64>,
+	/// Average over the responses received from peers.
+	pub received: Option<f64>,
+}
+
+/// Data exchanged with a peer, whether connected yet or not.
+#[derive(Clone, Debug, Default)]
+struct PeerTraffic {
+	bytes_received: u64,
+	bytes_sent: u64,
+	blocks_served: u64,
+	blocks_received: u64,
+}
+
+/// Summary of the session with a peer, from handshake to disconnection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerSession {
+	/// Time the peer was connected for.
+	pub duration: time::Duration,
+	/// Bytes received from the peer, including the handshake.
+	pub bytes_received: u64,
+	/// Bytes sent to the peer, including the handshake.
+	pub bytes_sent: u64,
+	/// Blocks served to the peer.
+	pub blocks_served: u64,
+	/// Blocks received from the peer.
+	pub blocks_received: u64,
+	/// Reason the peer gave for leaving, if it said goodbye.
+	pub goodbye: Option<message::GoodbyeReason>,
+}
+
+impl fmt::Display for PeerSession {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "duration={}s bytes_received={} bytes_sent={} blocks_served={} blocks_received={}",
+			self.duration.as_secs(), self.bytes_received, self.bytes_sent, self.blocks_served, self.blocks_received)?;
+		if let Some(reason) = self.goodbye {
+			write!(f, " goodbye={:?}", reason)?;
+		}
+		Ok(())
+	}
+}
+
+/// Read-only view of the protocol state, suitable for debugging dumps. Session nonces are left
+/// out; hashes and numbers are rendered as plain strings and integers.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProtocolSnapshot {
+	/// Connected peers, ordered by index.
+	pub peers: Vec<PeerSnapshot>,
+	/// Sync status.
+	pub sync: SyncSnapshot,
+	/// Number of block requests we are waiting on.
+	pub outstanding_requests: usize,
+	/// Number of recently propagated extrinsics.
+	pub propagated_extrinsics: usize,
+	/// Protocol counters, ordered by name.
+	pub metrics: BTreeMap<String, u64>,
+}
+
+/// State of a connected peer, as part of a `ProtocolSnapshot`.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerSnapshot {
+	/// Peer index.
+	pub index: NodeIndex,
+	/// Peer id, if known.
+	pub peer_id: Option<String>,
+	/// Protocol version.
+	pub protocol_version: u32,
+	/// Roles, as advertised in the status message.
+	pub roles: u8,
+	/// Best block hash.
+	pub best_hash: String,
+	/// Best block number.
+	pub best_number: u64,
+	/// Block request we are waiting a response to.
+	pub pending_request: Option<RequestSnapshot>,
+	/// Number of extrinsics known to the peer.
+	pub known_extrinsics: usize,
+	/// Number of blocks known to the peer.
+	pub known_blocks: usize,
+	/// Seconds since the handshake.
+	pub connected_secs: u64,
+	/// Bytes received from the peer.
+	pub bytes_received: u64,
+	/// Bytes sent to the peer.
+	pub bytes_sent: u64,
+	/// Blocks served to the peer.
+	pub blocks_served: u64,
+	/// Blocks received from the peer.
+	pub blocks_received: u64,
+	/// Extrinsics from the peer accepted by the pool, minus the rejected ones, after decay.
+	pub reputation: i32,
+}
+
+/// Pending block request, as part of a `PeerSnapshot`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RequestSnapshot {
+	/// Request id.
+	pub id: message::RequestId,
+	/// Starting block, as a hash or a number.
+	pub from: String,
+	/// Maximum number of blocks requested.
+	pub max: Option<u32>,
+	/// Seconds since the request was sent.
+	pub age_secs: Option<u64>,
 }
+
+/// Sync status, as part of a `ProtocolSnapshot`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncSnapshot {
+	/// Sync state.
+	pub state: String,
+	/// Best block number seen among the peers.
+	pub best_seen_block: Option<u64>,
+}
+
 /// Syncing status and statistics
 #[derive(Clone)]
 pub struct ProtocolStatus<B: BlockT> {
@@ -73,6 +353,87 @@ pub struct ProtocolStatus<B: BlockT> {
 	pub num_peers: usize,
 	/// Total number of active peers.
 	pub num_active_peers: usize,
+	/// Number of distinct best block hashes reported by the peers at the highest best block
+	/// number. A value above 1 is an early indication of a fork.
+	pub distinct_best_hashes: usize,
+	/// Number of blocks waiting in the import queue.
+	pub importing_blocks: usize,
+	/// Whether the peers at our best block number all report another best block, in which
+	/// case we are likely on a minority fork.
+	pub on_minority_fork: bool,
+}
+
+/// Work done by a `tick` of the protocol, so that the caller can tick less often while idle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TickOutcome {
+	/// Number of peers dropped for not answering a request or the handshake in time.
+	pub peers_dropped: usize,
+	/// Number of block requests which timed out.
+	pub requests_timed_out: usize,
+	/// Number of expired consensus messages collected.
+	pub messages_collected: usize,
+}
+
+impl TickOutcome {
+	/// Whether the tick did nothing.
+	pub fn is_idle(&self) -> bool {
+		*self == TickOutcome::default()
+	}
+}
+
+/// Summary of the node health, in the shape expected by health-check endpoints.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Health {
+	/// Whether the node is doing major syncing.
+	pub is_syncing: bool,
+	/// Number of connected peers.
+	pub peers: usize,
+	/// Whether the node is expected to have peers.
+	pub should_have_peers: bool,
+}
+
+/// Why a packet received from a peer was not handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketError {
+	/// The packet could not be decoded. The peer has been disabled.
+	Malformed,
+	/// The packet is a message of the given type, unknown to this version. It has been ignored.
+	Unknown(u8),
+	/// A batch contained another batch. The peer has been disabled.
+	NestedBatch,
+	/// A consensus message exceeded the configured maximum size. The peer has been disabled.
+	OversizedConsensusMessage,
+	/// A block response did not match the pending request of the peer. It has been dropped.
+	UnexpectedResponse,
+	/// A block response chunk, or a request for chunks, was invalid. The peer has been disabled.
+	InvalidChunk,
+	/// A status carried the given protocol version, which we don't support. The peer has been
+	/// disabled.
+	UnsupportedVersion(u32),
+	/// A status was for another chain. The peer has been handled as configured for that.
+	GenesisMismatch,
+	/// A block digest was invalid. The peer has been disabled.
+	InvalidDigest,
+	/// A block announcement was for a block which can't be part of our chain. The peer has
+	/// been disabled.
+	InvalidAnnouncement,
+}
+
+impl fmt::Display for PacketError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			PacketError::Malformed => write!(f, "malformed packet"),
+			PacketError::Unknown(index) => write!(f, "unknown message type {}", index),
+			PacketError::NestedBatch => write!(f, "nested batch"),
+			PacketError::OversizedConsensusMessage => write!(f, "oversized consensus message"),
+			PacketError::UnexpectedResponse => write!(f, "unexpected block response"),
+			PacketError::InvalidChunk => write!(f, "invalid block response chunk"),
+			PacketError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+			PacketError::GenesisMismatch => write!(f, "genesis mismatch"),
+			PacketError::InvalidDigest => write!(f, "invalid block digest"),
+			PacketError::InvalidAnnouncement => write!(f, "invalid block announcement"),
+		}
+	}
 }
 
 /// Peer information
@@ -83,6 +444,9 @@ struct Peer<B: BlockT, H: ExHashT> {
 	roles: Roles,
 	/// Peer best block hash
 	best_hash: B::Hash,
+	/// Whether the best block hash is known. Compact statuses don't carry it, it is only known
+	/// once the peer announces it or we have its best block.
+	best_hash_known: bool,
 	/// Peer best block number
 	best_number: <B::Header as HeaderT>::Number,
 	/// Pending block request if any
@@ -95,6 +459,90 @@ struct Peer<B: BlockT, H: ExHashT> {
 	known_blocks: HashSet<B::Hash>,
 	/// Request counter,
 	next_request_id: message::RequestId,
+	/// Session nonce from the peer's status.
+	session_nonce: u64,
+	/// Chunks received so far for the pending block request, if it is answered in chunks.
+	block_response_chunks: Option<ChunkedResponse<B>>,
+	/// Heads of the forks recently announced by this peer.
+	announced_heads: HashMap<B::Hash, NumberFor<B>>,
+	/// Extrinsics from this peer accepted by the pool, minus the rejected ones.
+	extrinsics_reputation: i32,
+	/// Number of block responses not matching the id of the pending request.
+	mismatched_responses: u32,
+	/// Number of block responses received while no request was pending.
+	unsolicited_responses: u32,
+	/// Number of block requests received asking for a suspiciously large number of blocks.
+	suspicious_requests: u32,
+	/// Maximum number of blocks served to this peer in a response.
+	serve_limit: u32,
+	/// Blocks of a response with compact bodies preceding the first block whose body had to
+	/// be requested in full.
+	compact_body_prefix: Option<Vec<message::BlockData<B>>>,
+	/// Optional requests this peer is served.
+	features: PeerFeatures,
+	/// Identity of the peer, if known.
+	peer_id: Option<PeerId>,
+	/// Reason the peer gave for leaving, if it said goodbye.
+	goodbye: Option<message::GoodbyeReason>,
+	/// Lowest block the peer serves, as notified by the peer.
+	available_from: NumberFor<B>,
+	/// When the handshake with the peer completed.
+	connected_at: time::Instant,
+	/// When the reputation of the peer last decayed.
+	reputation_decayed_at: time::Instant,
+	/// When the peer last requested blocks, answered our requests or announced a block.
+	last_active: time::Instant,
+}
+
+impl<B: BlockT, H: ExHashT> Peer<B, H> {
+	/// Value of staying connected to the peer, used to pick the peer to evict above the soft
+	/// peer limit.
+	fn score(&self) -> i64 {
+		let mut score = self.extrinsics_reputation as i64 - self.mismatched_responses as i64 - self.suspicious_requests as i64;
+		if self.roles.contains(Roles::AUTHORITY) {
+			score += AUTHORITY_SCORE;
+		}
+		score
+	}
+
+	/// Move the reputation toward neutral by the decay fraction for every decay interval
+	/// elapsed until `now`.
+	fn decay_reputation(&mut self, decay: &ReputationDecay, now: time::Instant) {
+		if now <= self.reputation_decayed_at {
+			return;
+		}
+		let to_ms = |d: time::Duration| d.as_secs() * 1_000 + d.subsec_millis() as u64;
+		let elapsed = to_ms(now - self.reputation_decayed_at);
+		let interval = cmp::max(to_ms(decay.interval), 1);
+		let steps = elapsed / interval;
+		if steps == 0 {
+			return;
+		}
+		let kept = (1.0 - decay.fraction).max(0.0).powi(cmp::min(steps, i32::max_value() as u64) as i32);
+		// truncating toward zero, so that small reputations reach neutral
+		self.extrinsics_reputation = (self.extrinsics_reputation as f64 * kept) as i32;
+		self.reputation_decayed_at = now - time::Duration::from_millis(elapsed % interval);
+	}
+}
+
+/// Chunks of a block response being received.
+struct ChunkedResponse<B: BlockT> {
+	/// Id of the block request.
+	id: message::RequestId,
+	/// Received chunks, by index.
+	chunks: Vec<Option<Vec<message::BlockData<B>>>>,
+	/// When the last chunk was received, or missing chunks were last requested.
+	last_activity: time::Instant,
+	/// Finality proof sent with the last chunk.
+	finality_proof: Option<message::generic::FinalityProof<B::Header>>,
+	/// Fork divergence sent with the last chunk.
+	fork_divergence: Option<u64>,
+}
+
+impl<B: BlockT> ChunkedResponse<B> {
+	fn missing(&self) -> Vec<u32> {
+		self.chunks.iter().enumerate().filter(|&(_, c)| c.is_none()).map(|(i, _)| i as u32).collect()
+	}
 }
 
 /// Info about a peer's known state.
@@ -108,6 +556,8 @@ pub struct PeerInfo<B: BlockT> {
 	pub best_hash: B::Hash,
 	/// Peer best block number
 	pub best_number: <B::Header as HeaderT>::Number,
+	/// Number of packets waiting to be sent to the peer. Zero if unknown.
+	pub send_queue_len: usize,
 }
 
 /// Context for a network-specific handler.
@@ -129,6 +579,19 @@ pub trait Context<B: BlockT> {
 pub(crate) struct ProtocolContext<'a, B: 'a + BlockT, H: 'a + ExHashT> {
 	io: &'a mut SyncIo,
 	context_data: &'a ContextData<B, H>,
+	actions: ContextActions,
+	// Encoded messages waiting to be sent in batches.
+	pending_messages: Vec<(NodeIndex, Vec<u8>)>,
+	// Hashes of the encoded messages sent to each peer, when dropping duplicates.
+	sent_messages: HashSet<(NodeIndex, B::Hash)>,
+}
+
+/// Number of actions taken through a context, added to the protocol counters when it is dropped.
+#[derive(Default)]
+struct ContextActions {
+	messages_sent: u64,
+	peers_dropped: u64,
+	peers_banned: u64,
 }
 
 impl<'a, B: BlockT + 'a, H: 'a + ExHashT> ProtocolContext<'a, B, H> {
@@ -136,16 +599,60 @@ impl<'a, B: BlockT + 'a, H: 'a + ExHashT> ProtocolContext<'a, B, H> {
 		ProtocolContext {
 			io,
 			context_data,
+			actions: Default::default(),
+			pending_messages: Vec::new(),
+			sent_messages: HashSet::new(),
 		}
 	}
 
 	/// Send a message to a peer.
 	pub fn send_message(&mut self, who: NodeIndex, message: Message<B>) {
-		send_message(&self.context_data.peers, self.io, who, message)
+		for data in encode_message(self.context_data, who, message) {
+			if self.context_data.dedup_messages && !self.sent_messages.insert((who, HashFor::<B>::hash(&data))) {
+				trace!(target: "sync", "Dropping duplicate message to {}", who);
+				self.context_data.note_metric("context.duplicates_dropped", 1);
+				continue;
+			}
+			self.actions.messages_sent += 1;
+			if self.context_data.batch_messages {
+				self.pending_messages.push((who, data));
+			} else {
+				self.context_data.note_traffic(who, |t| t.bytes_sent += data.len() as u64);
+				self.io.send(who, data);
+			}
+		}
+	}
+
+	/// Send pending messages, coalescing consecutive messages to the same peer into a batch.
+	fn flush_messages(&mut self) {
+		let mut pending = mem::replace(&mut self.pending_messages, Vec::new()).into_iter().peekable();
+		while let Some((who, data)) = pending.next() {
+			let mut batch = vec![data];
+			let mut can_batch = None;
+			while pending.peek().map_or(false, |&(next, _)| next == who)
+				&& *can_batch.get_or_insert_with(|| self.context_data.peer_version(who) >= BATCH_AND_GOODBYE_VERSION)
+			{
+				batch.push(pending.next().expect("Next message has been peeked above; qed").1);
+			}
+			let data = if batch.len() == 1 {
+				batch.pop().expect("Batch has exactly one message; qed")
+			} else {
+				self.context_data.note_metric("context.batches_sent", 1);
+				let message: Message<B> = GenericMessage::Batch(batch);
+				message.encode()
+			};
+			self.context_data.note_traffic(who, |t| t.bytes_sent += data.len() as u64);
+			self.io.send(who, data);
+		}
 	}
 
 	/// Point out that a peer has been malign or irresponsible or appeared lazy.
 	pub fn report_peer(&mut self, who: NodeIndex, reason: Severity) {
+		self.flush_messages();
+		match reason {
+			Severity::Bad(_) => self.actions.peers_banned += 1,
+			Severity::Useless(_) | Severity::Timeout => self.actions.peers_dropped += 1,
+		}
 		self.io.report_peer(who, reason);
 	}
 
@@ -157,11 +664,27 @@ impl<'a, B: BlockT + 'a, H: 'a + ExHashT> ProtocolContext<'a, B, H> {
 				protocol_version: p.protocol_version,
 				best_hash: p.best_hash,
 				best_number: p.best_number,
+				send_queue_len: self.io.send_queue_len(peer),
 			}
 		})
 	}
 }
 
+impl<'a, B: BlockT + 'a, H: 'a + ExHashT> Drop for ProtocolContext<'a, B, H> {
+	fn drop(&mut self) {
+		self.flush_messages();
+		if self.actions.messages_sent > 0 {
+			self.context_data.note_metric("context.messages_sent", self.actions.messages_sent);
+		}
+		if self.actions.peers_dropped > 0 {
+			self.context_data.note_metric("context.peers_dropped", self.actions.peers_dropped);
+		}
+		if self.actions.peers_banned > 0 {
+			self.context_data.note_metric("context.peers_banned", self.actions.peers_banned);
+		}
+	}
+}
+
 impl<'a, B: BlockT + 'a, H: ExHashT + 'a> Context<B> for ProtocolContext<'a, B, H> {
 	fn send_message(&mut self, who: NodeIndex, message: Message<B>) {
 		ProtocolContext::send_message(self, who, message);
@@ -185,6 +708,46 @@ pub(crate) struct ContextData<B: BlockT, H: ExHashT> {
 	// All connected peers
 	peers: RwLock<HashMap<NodeIndex, Peer<B, H>>>,
 	pub chain: Arc<Client<B>>,
+	// Ask peers to split large block responses into chunks.
+	chunked_block_responses: bool,
+	// Coalesce messages sent through a context into batches.
+	batch_messages: bool,
+	// Drop identical messages sent to the same peer through a context.
+	dedup_messages: bool,
+	// Protocol counters.
+	metrics: RwLock<ProtocolMetrics>,
+	// Data exchanged with each peer, kept apart from `peers` so that it can be updated while
+	// the peers are locked.
+	traffic: RwLock<HashMap<NodeIndex, PeerTraffic>>,
+	// Maximum of block requests in flight across all peers.
+	global_max_inflight_requests: Option<usize>,
+	// Block requests held back by the global in-flight limit, at most one per peer.
+	deferred_requests: RwLock<VecDeque<(NodeIndex, message::BlockRequest<B>)>>,
+}
+
+impl<B: BlockT, H: ExHashT> ContextData<B, H> {
+	fn note_metric(&self, key: &'static str, value: u64) {
+		*self.metrics.write().entry(key).or_insert(0) += value;
+	}
+
+	fn note_traffic<F: FnOnce(&mut PeerTraffic)>(&self, who: NodeIndex, f: F) {
+		f(self.traffic.write().entry(who).or_insert_with(Default::default));
+	}
+
+	/// Protocol version used by a peer, the current one for peers still handshaking.
+	fn peer_version(&self, who: NodeIndex) -> u32 {
+		self.peers.read().get(&who).map_or(CURRENT_VERSION, |p| p.protocol_version)
+	}
+
+	/// Number of block requests waiting for a response, across all peers.
+	fn in_flight_requests(&self) -> usize {
+		self.peers.read().values().filter(|p| p.block_request.is_some()).count()
+	}
+
+	/// Whether a block request can be sent without exceeding the global in-flight limit.
+	fn has_request_slot(&self) -> bool {
+		self.global_max_inflight_requests.map_or(true, |limit| self.in_flight_requests() < limit)
+	}
 }
 
 impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
@@ -197,25 +760,80 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 		transaction_pool: Arc<TransactionPool<H, B>>,
 		specialization: S,
 	) -> error::Result<Self> {
-		let info = chain.info()?;
-		let sync = ChainSync::new(config.roles, &info, import_queue);
+		let info = chain_info(&*chain)?;
+		let sync = ChainSync::new(&config, &info, import_queue);
+		let extrinsics_importer = match config.extrinsics_import_queue {
+			Some(queue_size) => Some(ExtrinsicsImporter::spawn(transaction_pool.clone(), queue_size)?),
+			None => None,
+		};
+		let chunked_block_responses = config.chunked_block_responses;
+		let batch_messages = config.batch_messages;
+		let dedup_messages = config.dedup_context_messages;
+		let global_max_inflight_requests = config.global_max_inflight_requests;
+		let session_nonce = time::SystemTime::now().duration_since(time::UNIX_EPOCH)
+			.map(|d| d.as_secs() * 1_000 + d.subsec_millis() as u64)
+			.unwrap_or(0);
 		let protocol = Protocol {
+			tunable_config: RwLock::new(TunableProtocolConfig::from(&config)),
+			pending_config: Mutex::new(None),
 			config: config,
 			context_data: ContextData {
 				peers: RwLock::new(HashMap::new()),
 				chain,
+				chunked_block_responses,
+				batch_messages,
+				dedup_messages,
+				metrics: RwLock::new(HashMap::new()),
+				traffic: RwLock::new(HashMap::new()),
+				global_max_inflight_requests,
+				deferred_requests: RwLock::new(VecDeque::new()),
 			},
 			on_demand,
 			genesis_hash: info.chain.genesis_hash,
+			session_nonce,
 			sync: Arc::new(RwLock::new(sync)),
 			specialization: RwLock::new(specialization),
 			consensus_gossip: RwLock::new(ConsensusGossip::new()),
+			serving_chain: None,
 			handshaking_peers: RwLock::new(HashMap::new()),
 			transaction_pool: transaction_pool,
+			extrinsics_importer,
+			last_imported: RwLock::new(None),
+			deferred_extrinsics: RwLock::new(None),
+			pending_announcements: RwLock::new(Vec::new()),
+			block_sources: RwLock::new(LinkedHashMap::new()),
+			connection_history: RwLock::new(LinkedHashMap::new()),
+			served_chunked_responses: RwLock::new(LinkedHashMap::new()),
+			early_announcements: RwLock::new(LinkedHashMap::new()),
+			propagated_extrinsics: RwLock::new(LinkedHashMap::new()),
+			restored_propagation: RwLock::new(HashMap::new()),
+			notified_pruning: RwLock::new(None),
+			pending_announces: RwLock::new(Vec::new()),
+			served_blocks: RwLock::new(LinkedHashMap::new()),
+			imported_extrinsic_digests: RwLock::new(LinkedHashMap::new()),
+			maintenance_cursor: RwLock::new(0),
+			bans: RwLock::new(HashMap::new()),
+			served_response_bytes: RwLock::new(0),
+			deferred_block_requests: RwLock::new(Vec::new()),
+			in_flight_responses: RwLock::new(HashMap::new()),
+			pending_chunks: RwLock::new(VecDeque::new()),
+			on_minority_fork: RwLock::new(false),
 		};
 		Ok(protocol)
 	}
 
+	/// Serve blocks and proofs to peers from the given client, such as a read-only replica
+	/// of our database, so that serving doesn't contend with block import.
+	pub fn with_serving_chain(mut self, chain: Arc<Client<B>>) -> Self {
+		self.serving_chain = Some(chain);
+		self
+	}
+
+	/// Client blocks and proofs are served from.
+	fn serving_chain(&self) -> &Client<B> {
+		&**self.serving_chain.as_ref().unwrap_or(&self.context_data.chain)
+	}
+
 	pub(crate) fn context_data(&self) -> &ContextData<B, H> {
 		&self.context_data
 	}
@@ -230,52 +848,264 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 
 	/// Returns protocol status
 	pub fn status(&self) -> ProtocolStatus<B> {
+		// counted before locking the peers, the lock isn't reentrant
+		let num_peers = self.peer_count();
+		let num_active_peers = self.active_peer_count();
 		let sync = self.sync.read();
 		let peers = self.context_data.peers.read();
+		let max_best_number = peers.values().filter(|p| p.best_hash_known).map(|p| p.best_number).max();
+		let distinct_best_hashes = peers.values()
+			.filter(|p| p.best_hash_known && Some(p.best_number) == max_best_number)
+			.map(|p| p.best_hash)
+			.collect::<HashSet<_>>()
+			.len();
 		ProtocolStatus {
 			sync: sync.status(),
-			num_peers: peers.values().count(),
-			num_active_peers: peers.values().filter(|p| p.block_request.is_some()).count(),
+			num_peers,
+			num_active_peers,
+			distinct_best_hashes,
+			importing_blocks: sync.import_queue().status().importing_count,
+			on_minority_fork: self.minority_fork_best(&*peers).is_some(),
+		}
+	}
+
+	/// Our best block hash, if the peers at our best block number all report another best
+	/// block.
+	fn minority_fork_best(&self, peers: &HashMap<NodeIndex, Peer<B, H>>) -> Option<B::Hash> {
+		let best = self.context_data.chain.info().ok()?.chain;
+		let mut at_our_height = peers.values().filter(|p| p.best_hash_known && p.best_number == best.best_number).peekable();
+		if at_our_height.peek().is_none() || at_our_height.any(|p| p.best_hash == best.best_hash) {
+			None
+		} else {
+			Some(best.best_hash)
+		}
+	}
+
+	/// Warn and notify the configured hook when we get on a minority fork.
+	fn check_minority_fork(&self) {
+		let best = self.minority_fork_best(&*self.context_data.peers.read());
+		let was_on_minority_fork = mem::replace(&mut *self.on_minority_fork.write(), best.is_some());
+		if let (Some(hash), false) = (best, was_on_minority_fork) {
+			warn!(target: "sync", "All peers at our best block disagree with it ({}), we are likely on a minority fork", hash);
+			self.note_metric("sync.minority_forks", 1);
+			if let Some(ref hook) = self.config.on_minority_fork_hook {
+				hook(&hash.encode());
+			}
 		}
 	}
 
-	pub fn handle_packet(&self, io: &mut SyncIo, who: NodeIndex, mut data: &[u8]) {
-		let message: Message<B> = match Decode::decode(&mut data) {
+	/// Returns a read-only snapshot of the protocol state for debugging. All the locks involved
+	/// are held together, so the sections are consistent with each other.
+	pub fn debug_snapshot(&self) -> ProtocolSnapshot {
+		let sync = self.sync.read();
+		let peers = self.context_data.peers.read();
+		let traffic = self.context_data.traffic.read();
+		let propagated_extrinsics = self.propagated_extrinsics.read();
+		let metrics = self.context_data.metrics.read();
+
+		let now = time::Instant::now();
+		let mut indices: Vec<_> = peers.keys().cloned().collect();
+		indices.sort();
+		let peer_snapshots = indices.into_iter().map(|index| {
+			let peer = &peers[&index];
+			let stats = traffic.get(&index).cloned().unwrap_or_default();
+			PeerSnapshot {
+				index,
+				peer_id: peer.peer_id.as_ref().map(|id| id.to_base58()),
+				protocol_version: peer.protocol_version,
+				roles: peer.roles.bits(),
+				best_hash: format!("{:?}", peer.best_hash),
+				best_number: peer.best_number.as_(),
+				pending_request: peer.block_request.as_ref().map(|request| RequestSnapshot {
+					id: request.id,
+					from: match request.from {
+						message::FromBlock::Hash(ref hash) => format!("{:?}", hash),
+						message::FromBlock::Number(ref number) => number.as_().to_string(),
+					},
+					max: request.max,
+					age_secs: peer.request_timestamp.map(|t| now.duration_since(t).as_secs()),
+				}),
+				known_extrinsics: peer.known_extrinsics.len(),
+				known_blocks: peer.known_blocks.len(),
+				connected_secs: now.duration_since(peer.connected_at).as_secs(),
+				bytes_received: stats.bytes_received,
+				bytes_sent: stats.bytes_sent,
+				blocks_served: stats.blocks_served,
+				blocks_received: stats.blocks_received,
+				reputation: peer.extrinsics_reputation,
+			}
+		}).collect::<Vec<_>>();
+
+		let status = sync.status();
+		ProtocolSnapshot {
+			outstanding_requests: peer_snapshots.iter().filter(|p| p.pending_request.is_some()).count(),
+			peers: peer_snapshots,
+			sync: SyncSnapshot {
+				state: format!("{:?}", status.state),
+				best_seen_block: status.best_seen_block.map(|n| n.as_()),
+			},
+			propagated_extrinsics: propagated_extrinsics.len(),
+			metrics: metrics.iter().map(|(key, value)| (key.to_string(), *value)).collect(),
+		}
+	}
+
+	/// Returns the number of connected peers.
+	pub fn peer_count(&self) -> usize {
+		self.context_data.peers.read().len()
+	}
+
+	/// Returns the number of connected peers we are waiting for blocks from.
+	pub fn active_peer_count(&self) -> usize {
+		self.context_data.peers.read().values().filter(|p| p.block_request.is_some()).count()
+	}
+
+	/// Returns the status of the import queue.
+	pub fn import_queue_status(&self) -> ImportQueueStatus<B> {
+		self.sync.read().import_queue().status()
+	}
+
+	/// Returns a health summary of the node.
+	pub fn health(&self) -> Health {
+		Health {
+			is_syncing: self.sync.read().status().is_major_syncing(),
+			peers: self.peer_count(),
+			should_have_peers: !self.config.isolated,
+		}
+	}
+
+	/// Number of distinct fork heads announced by the connected peers.
+	pub fn announced_forks(&self) -> usize {
+		self.context_data.peers.read().values()
+			.flat_map(|peer| peer.announced_heads.keys())
+			.collect::<HashSet<_>>()
+			.len()
+	}
+
+	/// Returns the peer that first informed us about a recently seen block.
+	pub fn block_source(&self, hash: &B::Hash) -> Option<NodeIndex> {
+		self.block_sources.read().get(hash).cloned()
+	}
+
+	fn note_block_source(&self, hash: B::Hash, who: NodeIndex) {
+		let mut block_sources = self.block_sources.write();
+		if !block_sources.contains_key(&hash) {
+			block_sources.insert(hash, who);
+			if block_sources.len() > MAX_BLOCK_SOURCES {
+				block_sources.pop_front();
+			}
+		}
+	}
+
+	/// Schedule an update of the runtime-tunable part of the config. The update is applied on
+	/// the next tick; a later update replaces a pending one.
+	pub fn update_config(&self, config: TunableProtocolConfig) {
+		*self.pending_config.lock() = Some(config);
+	}
+
+	/// Returns the runtime-tunable part of the config currently in effect.
+	pub fn tunable_config(&self) -> TunableProtocolConfig {
+		self.tunable_config.read().clone()
+	}
+
+	/// Returns a snapshot of the protocol counters.
+	pub fn metrics(&self) -> ProtocolMetrics {
+		let mut metrics = self.context_data.metrics.read().clone();
+		metrics.insert("requests.in_flight", self.context_data.in_flight_requests() as u64);
+		metrics
+	}
+
+	fn note_metric(&self, key: &'static str, value: u64) {
+		self.context_data.note_metric(key, value);
+	}
+
+	/// Average size of the block responses served and received so far, computed from the
+	/// `block_responses.*` metrics.
+	pub fn block_response_sizes(&self) -> BlockResponseSizes {
+		let metrics = self.context_data.metrics.read();
+		let average = |responses: &'static str, blocks: &'static str| match metrics.get(&responses) {
+			Some(&count) if count > 0 => Some(metrics.get(&blocks).cloned().unwrap_or(0) as f64 / count as f64),
+			_ => None,
+		};
+		BlockResponseSizes {
+			served: average("block_responses.served", "block_responses.served_blocks"),
+			received: average("block_responses.received", "block_responses.received_blocks"),
+		}
+	}
+
+	/// Handle a packet received from a peer. Misbehaving peers are reported before the
+	/// error is returned, so callers only need to handle it for their own bookkeeping.
+	pub fn handle_packet(&self, io: &mut SyncIo, who: NodeIndex, data: &[u8]) -> Result<(), PacketError> {
+		self.context_data.note_traffic(who, |t| t.bytes_received += data.len() as u64);
+		self.dispatch_packet(io, who, data)
+	}
+
+	fn dispatch_packet(&self, io: &mut SyncIo, who: NodeIndex, data: &[u8]) -> Result<(), PacketError> {
+		let packet = data;
+		let message: Message<B> = match decode_message::<B>(self.context_data.peer_version(who), data) {
 			Some(m) => m,
+			None if !self.config.strict_messages && message::is_future_message(packet) => {
+				trace!(target: "sync", "Ignoring packet of unknown type {} from {}", packet[0], who);
+				self.note_metric("messages.unknown", 1);
+				return Err(PacketError::Unknown(packet[0]));
+			},
 			None => {
 				trace!(target: "sync", "Invalid packet from {}", who);
 				io.report_peer(who, Severity::Bad("Peer sent us a packet with invalid format"));
-				return;
+				return Err(PacketError::Malformed);
 			}
 		};
 
+		self.note_metric(message_metric_key(&message), 1);
 		match message {
-			GenericMessage::Status(s) => self.on_status_message(io, who, s),
+			GenericMessage::Status(s) => return self.on_status_message(io, who, s),
+			GenericMessage::CompactStatus(s) => return self.on_compact_status_message(io, who, s),
 			GenericMessage::BlockRequest(r) => self.on_block_request(io, who, r),
 			GenericMessage::BlockResponse(r) => {
 				let request = {
 					let mut peers = self.context_data.peers.write();
 					if let Some(ref mut peer) = peers.get_mut(&who) {
-						peer.request_timestamp = None;
-						match mem::replace(&mut peer.block_request, None) {
-							Some(r) => r,
+						match peer.block_request.as_ref().map(|request| request.id) {
+							Some(id) if id == r.id => {},
+							Some(id) => {
+								trace!(target: "sync", "Ignoring mismatched response packet from {} (expected {} got {})", who, id, r.id);
+								self.note_metric("responses.mismatched_id", 1);
+								peer.mismatched_responses += 1;
+								if peer.mismatched_responses > MAX_MISMATCHED_RESPONSES {
+									io.report_peer(who, Severity::Bad("Too many responses with mismatched request ids"));
+								}
+								return Err(PacketError::UnexpectedResponse);
+							},
 							None => {
-								io.report_peer(who, Severity::Bad("Unexpected response packet received from peer"));
-								return;
+								// Only the first one is worth reporting, the peer is being disconnected.
+								peer.unsolicited_responses += 1;
+								if peer.unsolicited_responses == 1 {
+									io.report_peer(who, Severity::Bad("Unexpected response packet received from peer"));
+								} else {
+									self.note_metric("responses.unsolicited_dropped", 1);
+								}
+								return Err(PacketError::UnexpectedResponse);
 							}
 						}
+						peer.request_timestamp = None;
+						peer.last_active = time::Instant::now();
+						mem::replace(&mut peer.block_request, None).expect("Request id has been checked above; qed")
 					} else {
-						io.report_peer(who, Severity::Bad("Unexpected packet received from peer"));
-						return;
+						// The peer disconnected while its response was in flight.
+						trace!(target: "sync", "Dropping block response from disconnected peer {}", who);
+						self.note_metric("responses.disconnected_peer", 1);
+						return Err(PacketError::UnexpectedResponse);
 					}
 				};
-				if request.id != r.id {
-					trace!(target: "sync", "Ignoring mismatched response packet from {} (expected {} got {})", who, request.id, r.id);
-					return;
-				}
+				self.send_deferred_requests(io);
 				self.on_block_response(io, who, request, r);
 			},
-			GenericMessage::BlockAnnounce(announce) => self.on_block_announce(io, who, announce),
+			GenericMessage::BlockResponseChunk(chunk) => return self.on_block_response_chunk(io, who, chunk),
+			GenericMessage::BlockChunkRequest(request) => return self.on_block_chunk_request(io, who, request),
+			GenericMessage::BlockDigest(digest) => return self.on_block_digest(io, who, digest),
+			GenericMessage::Batch(messages) => return self.on_batch(io, who, messages),
+			GenericMessage::PruningNotification(notification) => self.on_pruning_notification(who, notification),
+			GenericMessage::Goodbye(reason) => self.on_goodbye(who, reason),
+			GenericMessage::BlockAnnounce(announce) => return self.on_block_announce(io, who, announce),
 			GenericMessage::Transactions(m) => self.on_extrinsics(io, who, m),
 			GenericMessage::RemoteCallRequest(request) => self.on_remote_call_request(io, who, request),
 			GenericMessage::RemoteCallResponse(response) => self.on_remote_call_response(io, who, response),
@@ -285,15 +1115,41 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 			GenericMessage::RemoteHeaderResponse(response) => self.on_remote_header_response(io, who, response),
 			GenericMessage::RemoteChangesRequest(request) => self.on_remote_changes_request(io, who, request),
 			GenericMessage::RemoteChangesResponse(response) => self.on_remote_changes_response(io, who, response),
+			GenericMessage::AuthoritySetRequest(request) => self.on_authority_set_request(io, who, request),
 			GenericMessage::Consensus(topic, msg) => {
+				if self.config.max_consensus_message_size.map_or(false, |max| msg.len() > max) {
+					trace!(target: "gossip", "Oversized consensus message from {}: {} bytes", who, msg.len());
+					self.note_metric("messages.oversized_consensus", 1);
+					io.report_peer(who, Severity::Bad("Peer sent an oversized consensus message"));
+					return Err(PacketError::OversizedConsensusMessage);
+				}
+				if self.tunable_config.read().trace_consensus_messages {
+					info!(target: "gossip", "{}", consensus_trace_line::<B>(who, &topic, &msg));
+				}
 				self.consensus_gossip.write().on_incoming(&mut ProtocolContext::new(&self.context_data, io), who, topic, msg);	
 			},
 			other => self.specialization.write().on_message(&mut ProtocolContext::new(&self.context_data, io), who, &mut Some(other)),
 		}
+		Ok(())
+	}
+
+	/// Dispatch the messages of a batch, returning the first error.
+	fn on_batch(&self, io: &mut SyncIo, who: NodeIndex, messages: Vec<Vec<u8>>) -> Result<(), PacketError> {
+		trace!(target: "sync", "Batch of {} messages from {}", messages.len(), who);
+		let mut result = Ok(());
+		for data in messages {
+			if data.first() == Some(&message::BATCH_MESSAGE_INDEX) {
+				io.report_peer(who, Severity::Bad("Peer sent a nested batch"));
+				return Err(PacketError::NestedBatch);
+			}
+			result = result.and(self.dispatch_packet(io, who, &data));
+		}
+		result
 	}
 
-	pub fn send_message(&self, io: &mut SyncIo, who: NodeIndex, message: Message<B>) {
-		send_message::<B, H>(&self.context_data.peers, io, who, message)
+	/// Send a message to a peer. Returns `false` if it could not be queued.
+	pub fn send_message(&self, io: &mut SyncIo, who: NodeIndex, message: Message<B>) -> bool {
+		send_message::<B, H>(&self.context_data, io, who, message)
 	}
 
 	pub fn gossip_consensus_message(&self, io: &mut SyncIo, topic: B::Hash, message: Vec<u8>) {
@@ -303,15 +1159,208 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 		});
 	}
 
+	/// Import a justification received apart from its block, e.g. through gossip. The block
+	/// must be known. Returns whether the justification has been accepted and stored. The peer
+	/// it came from, if any, is reported when it is rejected.
+	pub fn import_justification(
+		&self,
+		io: &mut SyncIo,
+		who: Option<PeerId>,
+		hash: B::Hash,
+		justification: Justification,
+	) -> bool {
+		let id = BlockId::Hash(hash);
+		let block = self.context_data.chain.header(&id)
+			.and_then(|header| self.context_data.chain.body(&id).map(|body| header.map(|header| (header, body))));
+		let accepted = match block {
+			Ok(Some((header, body))) => {
+				// the queue may call back into sync
+				let import_queue = self.sync.read().import_queue();
+				let imported = import_queue.import_justification(header, body, justification);
+				if !imported {
+					self.report_peer_id(io, who, Severity::Bad("Peer sent us a bad justification"));
+				}
+				imported
+			},
+			Ok(None) => {
+				trace!(target: "sync", "Ignoring justification of unknown block {}", hash);
+				false
+			},
+			Err(e) => {
+				debug!(target: "sync", "Error reading block {} to import its justification: {:?}", hash, e);
+				false
+			},
+		};
+		self.note_metric(if accepted { "justifications.imported" } else { "justifications.rejected" }, 1);
+		accepted
+	}
+
+	/// Download a block needed by consensus ahead of the blocks requested by sync.
+	pub fn request_priority_block(&self, io: &mut SyncIo, hash: B::Hash, number: NumberFor<B>) {
+		self.sync.write().request_priority_block(&mut ProtocolContext::new(&self.context_data, io), hash, number);
+	}
+
 	/// Called when a new peer is connected
 	pub fn on_peer_connected(&self, io: &mut SyncIo, who: NodeIndex) {
 		trace!(target: "sync", "Connected {}: {}", who, io.peer_debug_info(who));
+		if self.is_banned(io, who) {
+			io.report_peer(who, Severity::Useless("Node is banned"));
+			return;
+		}
+		if self.note_connection(io, who) {
+			io.report_peer(who, Severity::Useless("Node reconnects too often"));
+			return;
+		}
 		self.handshaking_peers.write().insert(who, time::Instant::now());
 		self.send_status(io, who);
 	}
 
+	/// Record a new connection of the node behind `who`. Returns true if the node is on
+	/// cooldown and the connection should be refused.
+	fn note_connection(&self, io: &SyncIo, who: NodeIndex) -> bool {
+		let peer_id = match io.peer_id(who) {
+			Some(peer_id) => peer_id,
+			None => return false,
+		};
+		let now = time::Instant::now();
+		let mut history = self.connection_history.write();
+		let cooling_down = {
+			let entry = history.entry(peer_id).or_insert_with(Default::default);
+			while entry.connected.front().map_or(false, |t| now.duration_since(*t).as_secs() >= RECONNECT_WINDOW_SEC) {
+				entry.connected.pop_front();
+			}
+			entry.connected.push_back(now);
+			if entry.connected.len() > MAX_RECONNECTS && entry.cooldown_until.map_or(true, |t| t <= now) {
+				debug!(target: "sync", "Node behind {} reconnected {} times recently, refusing connections", who, entry.connected.len());
+				entry.cooldown_until = Some(now + time::Duration::from_secs(RECONNECT_COOLDOWN_SEC));
+				entry.connected.clear();
+			}
+			entry.cooldown_until.map_or(false, |t| t > now)
+		};
+		while history.len() > MAX_CONNECTION_HISTORY {
+			history.pop_front();
+		}
+		cooling_down
+	}
+
+	/// Handle the status of a peer on another chain, as decided by the configured handler.
+	fn on_genesis_mismatch(&self, io: &mut SyncIo, who: NodeIndex, status: message::Status<B>) {
+		let action = match (self.config.genesis_mismatch_handler.as_ref(), io.peer_id(who)) {
+			(Some(handler), Some(peer_id)) => handler(&peer_id, status.genesis_hash.as_ref()),
+			_ => GenesisMismatchAction::Disable,
+		};
+		match action {
+			GenesisMismatchAction::Disable => {
+				io.report_peer(who, Severity::Bad(&format!("Peer is on different chain (our genesis: {} theirs: {})", self.genesis_hash, status.genesis_hash)));
+			},
+			GenesisMismatchAction::Ignore => {
+				trace!(target: "sync", "Ignoring peer {} on different chain (genesis: {})", who, status.genesis_hash);
+				self.handshaking_peers.write().remove(&who);
+			},
+			GenesisMismatchAction::RouteToSpecialization => {
+				debug!(target: "sync", "Routing peer {} on different chain (genesis: {}) to specialization", who, status.genesis_hash);
+				self.handshaking_peers.write().remove(&who);
+				self.specialization.write().on_foreign_connect(&mut ProtocolContext::new(&self.context_data, io), who, status);
+			},
+		}
+	}
+
+	/// Whether the node behind `who` is on cooldown for reconnecting too often.
+	fn is_cooling_down(&self, io: &SyncIo, who: NodeIndex) -> bool {
+		let now = time::Instant::now();
+		io.peer_id(who)
+			.and_then(|peer_id| self.connection_history.read().get(&peer_id).and_then(|h| h.cooldown_until))
+			.map_or(false, |t| t > now)
+	}
+
+	/// Refuse connections from the given node for the given duration, disconnecting it if it is
+	/// connected. The ban replaces any earlier ban of the node.
+	pub fn ban_node(&self, io: &mut SyncIo, peer_id: PeerId, duration: time::Duration) {
+		let now = time::Instant::now();
+		debug!(target: "sync", "Banning node {} for {}s", peer_id.to_base58(), duration.as_secs());
+		{
+			let mut bans = self.bans.write();
+			bans.retain(|_, until| *until > now);
+			bans.insert(peer_id.clone(), now + duration);
+		}
+		let connected: Vec<NodeIndex> = self.context_data.peers.read().iter()
+			.filter(|&(_, peer)| peer.peer_id.as_ref() == Some(&peer_id))
+			.map(|(who, _)| *who)
+			.collect();
+		for who in connected {
+			self.disconnect_peer(io, who, message::GoodbyeReason::Banned);
+		}
+	}
+
+	/// Report the connections of the given peer, if any.
+	fn report_peer_id(&self, io: &mut SyncIo, peer_id: Option<PeerId>, reason: Severity) {
+		let peer_id = match peer_id {
+			Some(peer_id) => peer_id,
+			None => return,
+		};
+		let connected: Vec<NodeIndex> = self.context_data.peers.read().iter()
+			.filter(|&(_, peer)| peer.peer_id.as_ref() == Some(&peer_id))
+			.map(|(who, _)| *who)
+			.collect();
+		for who in connected {
+			io.report_peer(who, reason);
+		}
+	}
+
+	/// Returns the banned nodes, with the remaining time of their ban.
+	pub fn banned_nodes(&self) -> Vec<(PeerId, time::Duration)> {
+		let now = time::Instant::now();
+		self.bans.read().iter()
+			.filter(|&(_, until)| *until > now)
+			.map(|(peer_id, until)| (peer_id.clone(), until.duration_since(now)))
+			.collect()
+	}
+
+	/// Returns the connected peers we have announced the block to or learned it from.
+	///
+	/// This is meant for diagnostics: it goes through the known blocks of every peer.
+	pub fn peers_informed_of_block(&self, hash: &B::Hash) -> Vec<PeerId> {
+		self.context_data.peers.read().values()
+			.filter(|peer| peer.known_blocks.contains(hash))
+			.filter_map(|peer| peer.peer_id.clone())
+			.collect()
+	}
+
+	/// Whether the node behind `who` is banned. Expired bans are lifted.
+	fn is_banned(&self, io: &SyncIo, who: NodeIndex) -> bool {
+		let peer_id = match io.peer_id(who) {
+			Some(peer_id) => peer_id,
+			None => return false,
+		};
+		let mut bans = self.bans.write();
+		match bans.get(&peer_id).cloned() {
+			Some(until) if until > time::Instant::now() => true,
+			Some(_) => {
+				trace!(target: "sync", "Ban of the node behind {} expired", who);
+				bans.remove(&peer_id);
+				false
+			},
+			None => false,
+		}
+	}
+
+	/// Disconnect a peer, telling it why first.
+	pub fn disconnect_peer(&self, io: &mut SyncIo, who: NodeIndex, reason: message::GoodbyeReason) {
+		trace!(target: "sync", "Saying goodbye to {}: {:?}", who, reason);
+		self.send_message(io, who, GenericMessage::Goodbye(reason));
+		io.report_peer(who, Severity::Useless(&format!("Disconnected: {:?}", reason)));
+	}
+
+	fn on_goodbye(&self, who: NodeIndex, reason: message::GoodbyeReason) {
+		debug!(target: "sync", "Peer {} is leaving: {:?}", who, reason);
+		if let Some(peer) = self.context_data.peers.write().get_mut(&who) {
+			peer.goodbye = Some(reason);
+		}
+	}
+
 	/// Called by peer when it is disconnecting
-	pub fn on_peer_disconnected(&self, io: &mut SyncIo, peer: NodeIndex) {
+	/// Returns the summary of the session if the peer had completed the handshake.
+	pub fn on_peer_disconnected(&self, io: &mut SyncIo, peer: NodeIndex) -> Option<PeerSession> {
 		trace!(target: "sync", "Disconnecting {}: {}", peer, io.peer_debug_info(peer));
 
 
@@ -319,47 +1368,552 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 		let mut sync = self.sync.write();
 		let mut spec = self.specialization.write();
 
-		let removed = {
+		let connected_at = {
 			let mut peers = self.context_data.peers.write();
 			let mut handshaking_peers = self.handshaking_peers.write();
 			handshaking_peers.remove(&peer);
-			peers.remove(&peer).is_some()
+			peers.remove(&peer).map(|p| (p.connected_at, p.goodbye))
 		};
-		if removed {
+		if connected_at.is_some() {
 			let mut context = ProtocolContext::new(&self.context_data, io);
 			self.consensus_gossip.write().peer_disconnected(&mut context, peer);
 			sync.peer_disconnected(&mut context, peer);
 			spec.on_disconnect(&mut context, peer);
 			self.on_demand.as_ref().map(|s| s.on_disconnect(peer));
 		}
+
+		self.in_flight_responses.write().remove(&peer);
+		self.pending_chunks.write().retain(|&(who, _)| who != peer);
+		self.context_data.deferred_requests.write().retain(|&(who, _)| who != peer);
+		self.send_deferred_requests(io);
+		let traffic = self.context_data.traffic.write().remove(&peer).unwrap_or_default();
+		connected_at.map(|(connected_at, goodbye)| {
+			let session = PeerSession {
+				duration: connected_at.elapsed(),
+				bytes_received: traffic.bytes_received,
+				bytes_sent: traffic.bytes_sent,
+				blocks_served: traffic.blocks_served,
+				blocks_received: traffic.blocks_received,
+				goodbye,
+			};
+			match goodbye {
+				Some(reason) => debug!(target: "sync", "Peer {} left ({:?}): {}", peer, reason, session),
+				None => debug!(target: "sync", "Peer {} disconnected unexpectedly: {}", peer, session),
+			}
+			session
+		})
 	}
 
 	fn on_block_request(&self, io: &mut SyncIo, peer: NodeIndex, request: message::BlockRequest<B>) {
 		trace!(target: "sync", "BlockRequest {} from {}: from {:?} to {:?} max {:?}", request.id, peer, request.from, request.to, request.max);
-		let mut blocks = Vec::new();
+		if let Some(p) = self.context_data.peers.write().get_mut(&peer) {
+			p.last_active = time::Instant::now();
+			if request.max.map_or(false, |max| max > SUSPICIOUS_REQUEST_MAX) {
+				debug!(target: "sync", "BlockRequest {} from {} asks for up to {:?} blocks", request.id, peer, request.max);
+				p.suspicious_requests += 1;
+				self.note_metric("requests.suspicious_max", 1);
+			}
+		}
+		if self.is_response_budget_spent(io) {
+			trace!(target: "sync", "Deferring BlockRequest {} from {}, response budget spent", request.id, peer);
+			self.note_metric("requests.deferred", 1);
+			self.deferred_block_requests.write().push((peer, request));
+			return;
+		}
+		self.serve_block_request(io, peer, request);
+	}
+
+	/// Whether the block responses served since the last tick used up the response budget, or
+	/// the ones still waiting to be sent reach the in-flight limit.
+	fn is_response_budget_spent(&self, io: &SyncIo) -> bool {
+		self.config.block_response_budget.map_or(false, |budget| *self.served_response_bytes.read() >= budget)
+			|| self.config.max_in_flight_response_bytes.map_or(false, |limit| self.in_flight_response_bytes(io) >= limit)
+	}
+
+	/// Bytes of the block responses which may still be waiting to be sent. As the network
+	/// only tells how many packets are queued to a peer, a response is considered sent once
+	/// there are fewer packets queued to its peer than responses sent to it after it.
+	fn in_flight_response_bytes(&self, io: &SyncIo) -> u64 {
+		let mut in_flight = self.in_flight_responses.write();
+		in_flight.retain(|&who, sizes| {
+			let queued = io.send_queue_len(who);
+			while sizes.len() > queued {
+				sizes.pop_front();
+			}
+			!sizes.is_empty()
+		});
+		in_flight.values().flat_map(|sizes| sizes.iter()).sum()
+	}
+
+	/// Send a block response, counting it against the response budget and in-flight limit.
+	fn send_block_response(&self, io: &mut SyncIo, peer: NodeIndex, message: Message<B>) {
+		if self.config.block_response_budget.is_some() || self.config.max_in_flight_response_bytes.is_some() {
+			let size = message.encode().len() as u64;
+			if self.config.block_response_budget.is_some() {
+				*self.served_response_bytes.write() += size;
+			}
+			if self.config.max_in_flight_response_bytes.is_some() {
+				self.in_flight_responses.write().entry(peer).or_insert_with(VecDeque::new).push_back(size);
+			}
+		}
+		self.send_message(io, peer, message)
+	}
+
+	/// Serve the block requests deferred for being over the response budget, with a fresh
+	/// budget. Requests of trusted peers go first, then those of higher-scoring peers.
+	fn serve_deferred_block_requests(&self, io: &mut SyncIo) {
+		*self.served_response_bytes.write() = 0;
+		let mut deferred = mem::replace(&mut *self.deferred_block_requests.write(), Vec::new());
+		if deferred.is_empty() {
+			return;
+		}
+		{
+			let peers = self.context_data.peers.read();
+			deferred.retain(|&(who, _)| peers.contains_key(&who));
+			deferred.sort_by_key(|&(who, _)| {
+				let trusted = io.peer_id(who).map_or(false, |id| self.config.trusted_peers.contains(&id));
+				let score = peers.get(&who).map_or(0, |p| p.score());
+				cmp::Reverse((trusted, score))
+			});
+		}
+		let mut deferred = deferred.into_iter();
+		while !self.is_response_budget_spent(io) {
+			match deferred.next() {
+				Some((who, request)) => self.serve_block_request(io, who, request),
+				None => break,
+			}
+		}
+		let remaining: Vec<_> = deferred.collect();
+		if !remaining.is_empty() {
+			trace!(target: "sync", "Deferring {} block requests to the next tick", remaining.len());
+			let mut pending = self.deferred_block_requests.write();
+			let received = mem::replace(&mut *pending, remaining);
+			pending.extend(received);
+		}
+	}
+
+	fn serve_block_request(&self, io: &mut SyncIo, peer: NodeIndex, mut request: message::BlockRequest<B>) {
+		let servable = self.config.servable_block_attributes | message::BlockAttributes::CHUNKED | message::BlockAttributes::BEST_EFFORT;
+		if !servable.contains(request.fields) {
+			debug!(target: "sync", "Not serving {:?} requested by {}", request.fields - servable, peer);
+			self.note_metric("requests.unservable_attributes", 1);
+			request.fields &= servable;
+		}
+		let syncing = self.config.serve_during_sync != ServeDuringSync::Always
+			&& self.sync.read().status().state != SyncState::Idle;
+		if syncing && self.config.serve_during_sync == ServeDuringSync::HeadersOnly {
+			trace!(target: "sync", "Serving only headers to {} while syncing", peer);
+			request.fields &= message::BlockAttributes::HEADER | message::BlockAttributes::CHUNKED | message::BlockAttributes::BEST_EFFORT;
+		}
+		let refused_syncing = syncing && self.config.serve_during_sync == ServeDuringSync::Never;
+		if refused_syncing || self.is_too_busy_for(&request) {
+			trace!(target: "sync", "Too busy to serve BlockRequest {} from {}", request.id, peer);
+			self.note_metric(if refused_syncing { "requests.refused_syncing" } else { "requests.refused_busy" }, 1);
+			self.send_message(io, peer, GenericMessage::BlockResponse(message::generic::BlockResponse {
+				id: request.id,
+				blocks: Vec::new(),
+				finality_proof: None,
+				busy: true,
+				fork_divergence: None,
+			}));
+			return;
+		}
+		self.adapt_serve_limit(io, peer);
+		let (blocks, fork_divergence) = self.block_data(peer, &request);
+		if let Some(number) = fork_divergence {
+			trace!(target: "sync", "Requested fork of {} diverges from our chain at #{}", peer, number);
+		}
+		let fork_divergence = fork_divergence.map(|n| n.as_());
+		self.note_metric("block_responses.served", 1);
+		self.note_metric("block_responses.served_blocks", blocks.len() as u64);
+		self.context_data.note_traffic(peer, |t| t.blocks_served += blocks.len() as u64);
+		let finality_proof = if request.fields.contains(message::BlockAttributes::JUSTIFICATION) {
+			self.finality_proof(&blocks)
+		} else {
+			None
+		};
+		if request.fields.contains(message::BlockAttributes::CHUNKED) && blocks.len() > BLOCK_RESPONSE_CHUNK_SIZE {
+			let count = blocks.chunks(BLOCK_RESPONSE_CHUNK_SIZE).len() as u32;
+			trace!(target: "sync", "Sending {} blocks in {} chunks", blocks.len(), count);
+			let chunks: Vec<_> = blocks.chunks(BLOCK_RESPONSE_CHUNK_SIZE).enumerate().map(|(index, chunk)| {
+				let index = index as u32;
+				GenericMessage::BlockResponseChunk(message::generic::BlockResponseChunk {
+					id: request.id,
+					index,
+					count,
+					blocks: chunk.to_vec(),
+					finality_proof: if index + 1 == count { finality_proof.clone() } else { None },
+					fork_divergence: if index + 1 == count { fork_divergence } else { None },
+				})
+			}).collect();
+			{
+				let mut served = self.served_chunked_responses.write();
+				served.insert((peer, request.id), chunks.clone());
+				while served.len() > MAX_SERVED_CHUNKED_REQUESTS {
+					served.pop_front();
+				}
+			}
+			if self.config.interleave_chunked_responses {
+				{
+					let mut pending = self.pending_chunks.write();
+					match pending.iter().position(|&(who, _)| who == peer) {
+						Some(position) => pending[position].1.extend(chunks),
+						None => pending.push_back((peer, chunks.into_iter().collect())),
+					}
+				}
+				self.send_pending_chunks(io);
+			} else {
+				for chunk in chunks {
+					self.send_block_response(io, peer, chunk);
+				}
+			}
+			return;
+		}
+		let response = message::generic::BlockResponse {
+			id: request.id,
+			blocks: blocks,
+			finality_proof,
+			busy: false,
+			fork_divergence,
+		};
+		trace!(target: "sync", "Sending BlockResponse with {} blocks", response.blocks.len());
+		self.send_block_response(io, peer, GenericMessage::BlockResponse(response))
+	}
+
+	/// Send the chunks of block responses waiting for their turn, one chunk to each peer in
+	/// turn, as long as the send queue of the peer has room.
+	fn send_pending_chunks(&self, io: &mut SyncIo) {
+		loop {
+			let round: Vec<(NodeIndex, Message<B>)> = {
+				let mut pending = self.pending_chunks.write();
+				let round = pending.iter_mut()
+					.filter(|&&mut (who, _)| io.send_queue_len(who) < MAX_QUEUED_CHUNKS)
+					.filter_map(|&mut (who, ref mut chunks)| chunks.pop_front().map(|chunk| (who, chunk)))
+					.collect();
+				pending.retain(|&(_, ref chunks)| !chunks.is_empty());
+				round
+			};
+			if round.is_empty() {
+				break;
+			}
+			for (who, chunk) in round {
+				self.send_block_response(io, who, chunk);
+			}
+		}
+	}
+
+	fn on_block_chunk_request(&self, io: &mut SyncIo, peer: NodeIndex, request: message::BlockChunkRequest) -> Result<(), PacketError> {
+		trace!(target: "sync", "BlockChunkRequest {} from {}: chunks {:?}", request.id, peer, request.chunks);
+		// no response has more chunks than blocks
+		if request.chunks.len() > MAX_BLOCK_DATA_RESPONSE as usize {
+			io.report_peer(peer, Severity::Bad("Peer requested too many block response chunks"));
+			return Err(PacketError::InvalidChunk);
+		}
+		let mut indices = request.chunks;
+		indices.sort();
+		indices.dedup();
+		let chunks: Vec<Message<B>> = {
+			let served = self.served_chunked_responses.read();
+			let chunks = match served.get(&(peer, request.id)) {
+				Some(chunks) => chunks,
+				None => {
+					trace!(target: "sync", "Ignoring chunk request for unknown response {} from {}", request.id, peer);
+					return Ok(());
+				}
+			};
+			if indices.last().map_or(false, |&index| index as usize >= chunks.len()) {
+				trace!(target: "sync", "Ignoring chunks out of the {} of response {} requested by {}", chunks.len(), request.id, peer);
+			}
+			indices.into_iter().filter_map(|index| chunks.get(index as usize).cloned()).collect()
+		};
+		for chunk in chunks {
+			self.send_block_response(io, peer, chunk);
+		}
+		Ok(())
+	}
+
+	fn on_block_response_chunk(&self, io: &mut SyncIo, peer: NodeIndex, chunk: message::BlockResponseChunk<B>) -> Result<(), PacketError> {
+		trace!(target: "sync", "BlockResponseChunk {} from {}: {}/{} with {} blocks",
+			chunk.id, peer, chunk.index + 1, chunk.count, chunk.blocks.len());
+		if chunk.index >= chunk.count || chunk.count as usize > MAX_BLOCK_DATA_RESPONSE as usize {
+			io.report_peer(peer, Severity::Bad("Peer sent an invalid block response chunk"));
+			return Err(PacketError::InvalidChunk);
+		}
+		let mut missing = None;
+		let complete = {
+			let mut peers = self.context_data.peers.write();
+			let p = match peers.get_mut(&peer) {
+				Some(p) => p,
+				None => {
+					trace!(target: "sync", "Dropping block response chunk from disconnected peer {}", peer);
+					self.note_metric("responses.disconnected_peer", 1);
+					return Ok(());
+				}
+			};
+			if p.block_request.as_ref().map_or(true, |r| r.id != chunk.id) {
+				trace!(target: "sync", "Ignoring mismatched response chunk from {} (got {})", peer, chunk.id);
+				return Ok(());
+			}
+			let known = p.block_response_chunks.as_ref()
+				.map_or(false, |c| c.id == chunk.id && c.chunks.len() == chunk.count as usize);
+			let mut pending = if known {
+				p.block_response_chunks.take().expect("Pending chunks have been checked above; qed")
+			} else {
+				ChunkedResponse {
+					id: chunk.id,
+					chunks: (0..chunk.count).map(|_| None).collect(),
+					last_activity: time::Instant::now(),
+					finality_proof: None,
+					fork_divergence: None,
+				}
+			};
+			if chunk.index + 1 == chunk.count {
+				// Chunks arrive in order, anything still missing after the last one has been lost
+				let lost: Vec<_> = pending.missing().into_iter().filter(|i| *i != chunk.index).collect();
+				if !lost.is_empty() {
+					missing = Some((chunk.id, lost));
+				}
+			}
+			pending.chunks[chunk.index as usize] = Some(chunk.blocks);
+			pending.last_activity = time::Instant::now();
+			if chunk.finality_proof.is_some() {
+				pending.finality_proof = chunk.finality_proof;
+			}
+			if chunk.fork_divergence.is_some() {
+				pending.fork_divergence = chunk.fork_divergence;
+			}
+			if pending.chunks.iter().all(|c| c.is_some()) {
+				p.request_timestamp = None;
+				let request = p.block_request.take().expect("Request id has been checked above; qed");
+				let blocks = pending.chunks.into_iter().flat_map(|c| c.unwrap_or_default()).collect();
+				Some((request, message::generic::BlockResponse {
+					id: chunk.id,
+					blocks,
+					finality_proof: pending.finality_proof,
+					busy: false,
+					fork_divergence: pending.fork_divergence,
+				}))
+			} else {
+				p.block_response_chunks = Some(pending);
+				None
+			}
+		};
+		if let Some((id, chunks)) = missing {
+			trace!(target: "sync", "Requesting lost chunks {:?} of response {} from {}", chunks, id, peer);
+			self.send_message(io, peer, GenericMessage::BlockChunkRequest(message::BlockChunkRequest { id, chunks }));
+		}
+		if let Some((request, response)) = complete {
+			self.on_block_response(io, peer, request, response);
+		}
+		Ok(())
+	}
+
+	/// Request again the missing chunks of responses that stalled.
+	fn resend_chunk_requests(&self, io: &mut SyncIo) {
+		let now = time::Instant::now();
+		let mut stalled = Vec::new();
+		for (who, peer) in self.context_data.peers.write().iter_mut() {
+			if let Some(ref mut pending) = peer.block_response_chunks {
+				if now.duration_since(pending.last_activity).as_secs() >= CHUNK_RESEND_TIMEOUT_SEC {
+					pending.last_activity = now;
+					stalled.push((*who, pending.id, pending.missing()));
+				}
+			}
+		}
+		for (who, id, chunks) in stalled {
+			trace!(target: "sync", "Requesting stalled chunks {:?} of response {} from {}", chunks, id, who);
+			self.send_message(io, who, GenericMessage::BlockChunkRequest(message::BlockChunkRequest { id, chunks }));
+		}
+	}
+
+	/// Rebuild the bodies of blocks received with compact bodies from the extrinsics in our
+	/// pool. If extrinsics are missing, the blocks from the first incomplete one on are
+	/// requested again with full bodies and `None` is returned.
+	fn complete_compact_bodies(
+		&self,
+		io: &mut SyncIo,
+		peer: NodeIndex,
+		request: &message::BlockRequest<B>,
+		mut response: message::BlockResponse<B>,
+	) -> Option<message::BlockResponse<B>> {
+		let prefix = self.context_data.peers.write().get_mut(&peer).and_then(|p| p.compact_body_prefix.take());
+		if let Some(mut blocks) = prefix {
+			blocks.append(&mut response.blocks);
+			response.blocks = blocks;
+		}
+		if !request.fields.contains(message::BlockAttributes::COMPACT_BODY) || request.fields.contains(message::BlockAttributes::BODY) {
+			return Some(response);
+		}
+
+		let pool: HashMap<B::Hash, B::Extrinsic> = self.transaction_pool.transactions().into_iter()
+			.map(|(_, extrinsic)| (HashFor::<B>::hash_of(&extrinsic), extrinsic))
+			.collect();
+		let mut incomplete = None;
+		for (index, block) in response.blocks.iter_mut().enumerate() {
+			let body = block.compact_body.as_ref()
+				.map(|hashes| hashes.iter().map(|hash| pool.get(hash).cloned()).collect::<Option<Vec<_>>>());
+			match body {
+				Some(Some(body)) => block.body = Some(body),
+				// a peer ignoring compact bodies may still send full ones
+				None if block.body.is_some() => (),
+				_ => {
+					incomplete = Some(index);
+					break;
+				},
+			}
+		}
+		let index = match incomplete {
+			Some(index) => index,
+			None => return Some(response),
+		};
+		trace!(target: "sync", "Body of block {} from {} is missing or incomplete, requesting full bodies",
+			response.blocks[index].hash, peer);
+		let mut fields = request.fields;
+		fields.remove(message::BlockAttributes::COMPACT_BODY);
+		fields.insert(message::BlockAttributes::BODY);
+		let full_request = message::generic::BlockRequest {
+			id: 0,
+			fields,
+			from: message::FromBlock::Hash(response.blocks[index].hash),
+			to: None,
+			direction: request.direction.clone(),
+			max: Some((response.blocks.len() - index) as u32),
+		};
+		self.send_message(io, peer, GenericMessage::BlockRequest(full_request));
+		response.blocks.truncate(index);
+		if let Some(ref mut p) = self.context_data.peers.write().get_mut(&peer) {
+			p.compact_body_prefix = Some(response.blocks);
+		}
+		None
+	}
+
+	/// Whether we are too loaded to serve `request`. Requests for a single block, such as
+	/// ancestry searches, are cheap and always served.
+	fn is_too_busy_for(&self, request: &message::BlockRequest<B>) -> bool {
+		match self.config.busy_import_queue_len {
+			Some(limit) if request.max.map_or(true, |max| max > 1) =>
+				self.import_queue_status().importing_count > limit,
+			_ => false,
+		}
+	}
+
+	/// Proof that the highest of the given blocks is finalized, if it is: our finalized head
+	/// with its justification.
+	fn finality_proof(&self, blocks: &[message::BlockData<B>]) -> Option<message::generic::FinalityProof<B::Header>> {
+		let chain = self.serving_chain();
+		let info = chain.info().ok()?;
+		let highest = [blocks.first()?, blocks.last()?].iter()
+			.filter_map(|b| chain.header(&BlockId::Hash(b.hash)).ok().and_then(|h| h))
+			.max_by_key(|h| *h.number())?;
+		let number = *highest.number();
+		if number > info.chain.finalized_number || chain.block_hash(number).ok().and_then(|h| h) != Some(highest.hash()) {
+			return None;
+		}
+		let finalized = BlockId::Hash(info.chain.finalized_hash);
+		Some(message::generic::FinalityProof {
+			header: chain.header(&finalized).ok().and_then(|h| h)?,
+			justification: chain.justification(&finalized).ok().and_then(|j| j)?,
+		})
+	}
+
+	/// Number of blocks served in a response to a peer without history.
+	fn default_serve_limit(&self) -> u32 {
+		cmp::max(self.config.min_block_response_len, cmp::min(MAX_BLOCK_DATA_RESPONSE, self.config.max_block_response_len))
+	}
+
+	/// Adapt the number of blocks served to the peer to how fast it receives them: the limit
+	/// grows while our previous responses have been sent by the time it requests more, and
+	/// shrinks when they pile up in its send queue.
+	fn adapt_serve_limit(&self, io: &SyncIo, who: NodeIndex) {
+		let queued = io.send_queue_len(who);
+		let (min, max) = (self.config.min_block_response_len, self.config.max_block_response_len);
+		if let Some(peer) = self.context_data.peers.write().get_mut(&who) {
+			let limit = if queued == 0 {
+				cmp::min(peer.serve_limit.saturating_mul(2), max)
+			} else if queued >= SLOW_PEER_SEND_QUEUE_LEN {
+				cmp::max(peer.serve_limit / 2, min)
+			} else {
+				peer.serve_limit
+			};
+			if limit != peer.serve_limit {
+				trace!(target: "sync", "Serving up to {} blocks per response to {}", limit, who);
+				peer.serve_limit = limit;
+			}
+		}
+	}
+
+	/// Collect the block data requested by `request`. Ascending sequences stop where our chain
+	/// diverges from the fork they started on, the number of the first block left out is
+	/// returned along with the data.
+	fn block_data(&self, peer: NodeIndex, request: &message::BlockRequest<B>) -> (Vec<message::BlockData<B>>, Option<NumberFor<B>>) {
+		let mut blocks: Vec<message::BlockData<B>> = Vec::new();
+		let mut fork_divergence = None;
 		let mut id = match request.from {
 			message::FromBlock::Hash(h) => BlockId::Hash(h),
 			message::FromBlock::Number(n) => BlockId::Number(n),
 		};
-		let max = cmp::min(request.max.unwrap_or(u32::max_value()), MAX_BLOCK_DATA_RESPONSE) as usize;
+		// With only the genesis block, it is the answer to any descending request by number,
+		// rather than an empty response the peer could take for us not having the genesis.
+		if let (message::Direction::Descending, &message::FromBlock::Number(n)) = (request.direction, &request.from) {
+			let genesis_only = self.serving_chain().info().ok().map_or(false, |info| info.chain.best_number == As::sa(0));
+			if n > As::sa(0) && genesis_only {
+				trace!(target: "sync", "Serving the genesis block to {} for descending request from #{}", peer, n);
+				id = BlockId::Number(As::sa(0));
+			}
+		}
+		let (peer_version, serve_limit) = self.context_data.peers.read().get(&peer)
+			.map_or((0, self.default_serve_limit()), |p| (p.protocol_version, p.serve_limit));
+		let max = cmp::min(request.max.unwrap_or(u32::max_value()), serve_limit) as usize;
 		// TODO: receipts, etc.
 		let get_header = request.fields.contains(message::BlockAttributes::HEADER);
-		let get_body = request.fields.contains(message::BlockAttributes::BODY);
+		let serve_bodies = peer_version >= self.config.min_version_for_block_bodies;
+		if !serve_bodies && request.fields.contains(message::BlockAttributes::BODY) {
+			trace!(target: "sync", "Not serving bodies to {} using protocol version {}", peer, peer_version);
+		}
+		let get_body = serve_bodies && request.fields.contains(message::BlockAttributes::BODY);
 		let get_justification = request.fields.contains(message::BlockAttributes::JUSTIFICATION);
-		while let Some(header) = self.context_data.chain.header(&id).unwrap_or(None) {
+		let get_compact_body = request.fields.contains(message::BlockAttributes::COMPACT_BODY);
+		let lowest_number = if request.fields.contains(message::BlockAttributes::BEST_EFFORT) {
+			self.serving_chain().info().ok()
+				.map_or(0, |info| info.chain.best_number.as_().saturating_sub(BEST_EFFORT_MAX_DEPTH))
+		} else {
+			0
+		};
+		while let Some(header) = self.serving_chain().header(&id).unwrap_or(None) {
 			if blocks.len() >= max {
 				break;
 			}
 			let number = header.number().clone();
+			if request.direction == message::Direction::Ascending
+				&& blocks.last().map_or(false, |b| b.hash != *header.parent_hash())
+			{
+				fork_divergence = Some(number);
+				break;
+			}
+			if number.as_() < lowest_number {
+				trace!(target: "sync", "Not serving block #{} to best-effort request from {}", number, peer);
+				break;
+			}
 			let hash = header.hash();
-			let justification = if get_justification { self.context_data.chain.justification(&BlockId::Hash(hash)).unwrap_or(None) } else { None };
+			if number == As::sa(0) && hash != self.genesis_hash {
+				warn!(target: "sync", "Block #0 in our store is {}, not our genesis {}; not serving it to {}", hash, self.genesis_hash, peer);
+				self.note_metric("requests.wrong_genesis", 1);
+				break;
+			}
+			let (justification, body) = self.served_block_data(hash, get_justification, get_body || get_compact_body);
+			let compact_body = if get_compact_body {
+				body.as_ref().map(|body| body.iter().map(|e| HashFor::<B>::hash_of(e)).collect())
+			} else {
+				None
+			};
 			let block_data = message::generic::BlockData {
 				hash: hash,
 				header: if get_header { Some(header) } else { None },
-				body: if get_body { self.context_data.chain.body(&BlockId::Hash(hash)).unwrap_or(None) } else { None },
+				body: if get_body { body } else { None },
 				receipt: None,
 				message_queue: None,
 				justification,
+				compact_body,
 			};
 			blocks.push(block_data);
 			match request.direction {
@@ -372,15 +1926,70 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 				}
 			}
 		}
-		let response = message::generic::BlockResponse {
-			id: request.id,
-			blocks: blocks,
-		};
-		trace!(target: "sync", "Sending BlockResponse with {} blocks", response.blocks.len());
-		self.send_message(io, peer, GenericMessage::BlockResponse(response))
+		(blocks, fork_divergence)
+	}
+
+	/// Justification and body of a block we serve, as requested, read from the cache of served
+	/// blocks when possible. Only data that exists is cached, as a justification may be added
+	/// to a block later.
+	fn served_block_data(&self, hash: B::Hash, get_justification: bool, get_body: bool) -> (Option<Justification>, Option<Vec<B::Extrinsic>>) {
+		if !get_justification && !get_body {
+			return (None, None);
+		}
+		let (mut justification, mut body) = self.served_blocks.write().get_refresh(&hash)
+			.map_or((None, None), |b| (b.justification.clone(), b.body.clone()));
+		let mut cached = justification.is_some() || body.is_some();
+		if get_justification && justification.is_none() {
+			justification = self.serving_chain().justification(&BlockId::Hash(hash)).unwrap_or(None);
+			cached = false;
+		}
+		if get_body && body.is_none() {
+			body = self.serving_chain().body(&BlockId::Hash(hash)).unwrap_or(None);
+			cached = false;
+		}
+		if cached {
+			self.note_metric("block_cache.hits", 1);
+		} else if justification.is_some() || body.is_some() {
+			let mut served_blocks = self.served_blocks.write();
+			served_blocks.insert(hash, message::generic::BlockData {
+				hash,
+				header: None,
+				body: body.clone(),
+				receipt: None,
+				message_queue: None,
+				justification: justification.clone(),
+				compact_body: None,
+			});
+			if served_blocks.len() > MAX_CACHED_SERVED_BLOCKS {
+				served_blocks.pop_front();
+			}
+		}
+		(
+			if get_justification { justification } else { None },
+			if get_body { body } else { None },
+		)
 	}
 
 	fn on_block_response(&self, io: &mut SyncIo, peer: NodeIndex, request: message::BlockRequest<B>, response: message::BlockResponse<B>) {
+		let response = match self.complete_compact_bodies(io, peer, &request, response) {
+			Some(response) => response,
+			None => return,
+		};
+		if self.config.require_complete_blocks {
+			if let Some(block) = response.blocks.iter().find(|b| !block_has_fields(b, request.fields)) {
+				trace!(target: "sync", "Block {} from {} is missing requested data", block.hash, peer);
+				self.note_metric("responses.incomplete", 1);
+				io.report_peer(peer, Severity::Bad("Peer sent blocks without the requested data"));
+				return;
+			}
+		}
+		if let Some(number) = response.fork_divergence {
+			trace!(target: "sync", "Chain of {} diverges from the requested fork at #{}", peer, number);
+			self.note_metric("responses.fork_divergence", 1);
+		}
+		self.note_metric("block_responses.received", 1);
+		self.note_metric("block_responses.received_blocks", response.blocks.len() as u64);
+		self.context_data.note_traffic(peer, |t| t.blocks_received += response.blocks.len() as u64);
 		// TODO: validate response
 		let blocks_range = match (
 				response.blocks.first().and_then(|b| b.header.as_ref().map(|h| h.number())),
@@ -392,6 +2001,9 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 			};
 		trace!(target: "sync", "BlockResponse {} from {} with {} blocks{}",
 			response.id, peer, response.blocks.len(), blocks_range);
+		for block in &response.blocks {
+			self.note_block_source(block.hash, peer);
+		}
 
 		// import_queue.import_blocks also acquires sync.write();
 		// Break the cycle by doing these separately from the outside;
@@ -402,6 +2014,15 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 
 		if let Some((origin, new_blocks)) = new_blocks {
 			let import_queue = self.sync.read().import_queue();
+			// blocks may have been queued from another source while the response was in flight
+			let count = new_blocks.len();
+			let new_blocks: Vec<_> = new_blocks.into_iter()
+				.filter(|b| !import_queue.is_importing(&b.block.hash))
+				.collect();
+			if new_blocks.len() < count {
+				trace!(target: "sync", "Skipping {} blocks from {} already in the import queue", count - new_blocks.len(), peer);
+				self.note_metric("blocks.already_queued", (count - new_blocks.len()) as u64);
+			}
 			import_queue.import_blocks(origin, new_blocks);
 		}
 
@@ -409,64 +2030,229 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 	}
 
 	/// Perform time based maintenance.
-	pub fn tick(&self, io: &mut SyncIo) {
-		self.consensus_gossip.write().collect_garbage(|_| true);
-		self.maintain_peers(io);
+	pub fn tick(&self, io: &mut SyncIo) -> TickOutcome {
+		if let Some(config) = self.pending_config.lock().take() {
+			debug!(target: "sync", "Applying protocol config update: {:?}", config);
+			*self.tunable_config.write() = config;
+		}
+		self.import_deferred_extrinsics(io);
+		self.repropagate_extrinsics_at(io, time::Instant::now());
+		self.serve_deferred_block_requests(io);
+		self.send_deferred_requests(io);
+		self.deliver_block_announces(io);
+		let messages_collected = self.collect_consensus_garbage();
+		self.announce_pending_blocks(io);
+		self.collect_imported_extrinsics();
+		let outcome = self.maintain_peers_at(io, time::Instant::now());
+		self.check_minority_fork();
+		self.resend_chunk_requests(io);
+		self.send_pending_chunks(io);
 		self.on_demand.as_ref().map(|s| s.maintain_peers(io));
+		TickOutcome { messages_collected, ..outcome }
+	}
+
+	/// Send the block requests deferred by the global in-flight limit while there is room.
+	/// Requests superseded by a newer one sent to the same peer are dropped.
+	fn send_deferred_requests(&self, io: &mut SyncIo) {
+		while self.context_data.has_request_slot() {
+			let next = self.context_data.deferred_requests.write().pop_front();
+			match next {
+				Some((who, request)) => {
+					let superseded = self.context_data.peers.read().get(&who).map_or(true, |p| p.block_request.is_some());
+					if superseded {
+						trace!(target: "sync", "Dropping deferred block request to {}, superseded", who);
+						continue;
+					}
+					self.send_message(io, who, GenericMessage::BlockRequest(request))
+				},
+				None => break,
+			}
+		}
+	}
+
+	/// Collect the expired consensus messages, unless the gossip is locked for too long, in
+	/// which case it is left for the next tick. Returns the number of collected messages.
+	fn collect_consensus_garbage(&self) -> usize {
+		match self.consensus_gossip.try_write_for(time::Duration::from_millis(CONSENSUS_GC_LOCK_TIMEOUT_MS)) {
+			Some(mut gossip) => gossip.collect_garbage(|_| true),
+			None => {
+				warn!(target: "gossip", "Consensus gossip locked for more than {}ms, skipping garbage collection", CONSENSUS_GC_LOCK_TIMEOUT_MS);
+				self.note_metric("gossip.garbage_collection_skipped", 1);
+				0
+			},
+		}
 	}
 
-	fn maintain_peers(&self, io: &mut SyncIo) {
-		let tick = time::Instant::now();
+	/// Drop peers which didn't answer a request or complete the handshake in time. A peer
+	/// is only reported once, so this can be called at any rate.
+	pub fn maintain_peers(&self, io: &mut SyncIo) {
+		self.maintain_peers_at(io, time::Instant::now());
+	}
+
+	/// `maintain_peers` as if called at `tick`, which is not earlier than the last request.
+	pub(crate) fn maintain_peers_at(&self, io: &mut SyncIo, tick: time::Instant) -> TickOutcome {
+		let config = self.tunable_config();
 		let mut aborting = Vec::new();
+		let mut requests_timed_out = 0;
 		{
-			let peers = self.context_data.peers.read();
-			let handshaking_peers = self.handshaking_peers.read();
-			for (who, timestamp) in peers.iter()
-				.filter_map(|(id, peer)| peer.request_timestamp.as_ref().map(|r| (id, r)))
-				.chain(handshaking_peers.iter()) {
+			let mut peers = self.context_data.peers.write();
+			let mut handshaking_peers = self.handshaking_peers.write();
+			let checked = self.peers_to_maintain(&*peers);
+			for who in checked {
+				let peer = peers.get_mut(&who).expect("`who` is one of the keys of `peers`; qed");
+				let timed_out = match (peer.request_timestamp, peer.block_request.as_ref()) {
+					(Some(timestamp), Some(request)) => tick - timestamp > block_request_timeout(&config, request),
+					_ => false,
+				};
+				if timed_out {
+					trace!(target: "sync", "Request timeout {}", who);
+					peer.request_timestamp = None;
+					requests_timed_out += 1;
+					aborting.push(who);
+				}
+				if let Some(ref decay) = self.config.reputation_decay {
+					peer.decay_reputation(decay, tick);
+				}
+			}
+			handshaking_peers.retain(|who, timestamp| {
 				if (tick - *timestamp).as_secs() > REQUEST_TIMEOUT_SEC {
-					trace!(target: "sync", "Timeout {}", who);
+					trace!(target: "sync", "Handshake timeout {}", who);
 					aborting.push(*who);
+					false
+				} else {
+					true
 				}
-			}
+			});
 		}
 
 		self.specialization.write().maintain_peers(&mut ProtocolContext::new(&self.context_data, io));
+		let peers_dropped = aborting.len();
 		for p in aborting {
 			io.report_peer(p, Severity::Timeout);
 		}
+		TickOutcome { peers_dropped, requests_timed_out, messages_collected: 0 }
+	}
+
+	/// Peers checked by the next `maintain_peers`: all of them, or the configured number of
+	/// peers following the ones checked last time, by index.
+	fn peers_to_maintain(&self, peers: &HashMap<NodeIndex, Peer<B, H>>) -> Vec<NodeIndex> {
+		let mut order: Vec<NodeIndex> = peers.keys().cloned().collect();
+		let budget = match self.config.peers_maintained_per_tick {
+			Some(budget) if budget < order.len() => budget,
+			_ => return order,
+		};
+		order.sort();
+		let mut cursor = self.maintenance_cursor.write();
+		let start = order.iter().position(|who| *who >= *cursor).unwrap_or(0);
+		*cursor = order[(start + budget) % order.len()];
+		trace!(target: "sync", "Maintaining {} of {} peers, from {}", budget, order.len(), order[start]);
+		order.iter().cycle().skip(start).take(budget).cloned().collect()
 	}
 
 	#[allow(dead_code)]
-	pub fn peer_info(&self, peer: NodeIndex) -> Option<PeerInfo<B>> {
+	pub fn peer_info(&self, io: &SyncIo, peer: NodeIndex) -> Option<PeerInfo<B>> {
 		self.context_data.peers.read().get(&peer).map(|p| {
 			PeerInfo {
 				roles: p.roles,
 				protocol_version: p.protocol_version,
 				best_hash: p.best_hash,
 				best_number: p.best_number,
+				send_queue_len: io.send_queue_len(peer),
+			}
+		})
+	}
+
+	/// Genesis hash of our chain.
+	pub fn genesis_hash(&self) -> B::Hash {
+		self.genesis_hash
+	}
+
+	/// Our own state, as advertised to peers in the status message. `None` if the chain info
+	/// could not be read.
+	pub fn local_node_info(&self) -> Option<PeerInfo<B>> {
+		self.context_data.chain.info().ok().map(|info| {
+			PeerInfo {
+				roles: self.config.roles,
+				protocol_version: CURRENT_VERSION,
+				best_hash: info.chain.best_hash,
+				best_number: info.chain.best_number,
+				send_queue_len: 0,
 			}
 		})
 	}
 
 	/// Called by peer to report status
-	fn on_status_message(&self, io: &mut SyncIo, who: NodeIndex, status: message::Status<B>) {
+	/// Handle the status of a light client, as a `Status` with the omitted fields filled in.
+	/// The best hash is ours at the best number of the peer, if we have that block.
+	fn on_compact_status_message(&self, io: &mut SyncIo, who: NodeIndex, status: message::CompactStatus<B>) -> Result<(), PacketError> {
+		if status.version < COMPACT_STATUS_VERSION {
+			io.report_peer(who, Severity::Bad(&format!("Peer sent a compact status with protocol version {}", status.version)));
+			return Err(PacketError::UnsupportedVersion(status.version));
+		}
+		let best_hash = self.context_data.chain.block_hash(status.best_number).ok()
+			.and_then(|hash| hash);
+		self.on_status(io, who, message::generic::Status {
+			version: status.version,
+			roles: Roles::LIGHT,
+			best_number: status.best_number,
+			best_hash: best_hash.unwrap_or_default(),
+			genesis_hash: status.genesis_hash,
+			chain_status: Vec::new(),
+			session_nonce: 0,
+		}, best_hash.is_some())
+	}
+
+	fn on_status_message(&self, io: &mut SyncIo, who: NodeIndex, status: message::Status<B>) -> Result<(), PacketError> {
+		self.on_status(io, who, status, true)
+	}
+
+	/// Handle the status of a peer, whose best hash is only a placeholder if not known.
+	fn on_status(&self, io: &mut SyncIo, who: NodeIndex, status: message::Status<B>, best_hash_known: bool) -> Result<(), PacketError> {
 		trace!(target: "sync", "New peer {} {:?}", who, status);
 
-		{
+		let (restarted, stale_best_hash, regression) = self.context_data.peers.read().get(&who)
+			.map_or((false, false, 0), |peer| (
+				status.session_nonce > peer.session_nonce,
+				best_hash_known && peer.best_hash_known && status.best_number > peer.best_number && status.best_hash == peer.best_hash,
+				peer.best_number.as_().saturating_sub(status.best_number.as_()),
+			));
+		if stale_best_hash {
+			io.report_peer(who, Severity::Bad("Peer reported a higher best block with the same hash"));
+			return Ok(());
+		}
+		if restarted {
+			if !self.check_best_regression(io, who, regression) {
+				return Ok(());
+			}
+			debug!(target: "sync", "Peer {} restarted its session, replacing its state", who);
+			self.on_peer_disconnected(io, who);
+		}
+
+		if self.is_cooling_down(io, who) {
+			io.report_peer(who, Severity::Useless("Node reconnects too often"));
+			return Ok(());
+		}
+
+		if self.is_banned(io, who) {
+			io.report_peer(who, Severity::Useless("Node is banned"));
+			return Ok(());
+		}
+
+		if status.genesis_hash != self.genesis_hash {
+			self.on_genesis_mismatch(io, who, status);
+			return Err(PacketError::GenesisMismatch);
+		}
+
+		let evicted = {
 			let mut peers = self.context_data.peers.write();
 			let mut handshaking_peers = self.handshaking_peers.write();
 			if peers.contains_key(&who) {
 				debug!(target: "sync", "Unexpected status packet from {}:{}", who, io.peer_debug_info(who));
-				return;
-			}
-			if status.genesis_hash != self.genesis_hash {
-				io.report_peer(who, Severity::Bad(&format!("Peer is on different chain (our genesis: {} theirs: {})", self.genesis_hash, status.genesis_hash)));
-				return;
+				return Ok(());
 			}
-			if status.version != CURRENT_VERSION {
+			if status.version < MIN_VERSION || status.version > CURRENT_VERSION {
 				io.report_peer(who, Severity::Bad(&format!("Peer using unsupported protocol version {}", status.version)));
-				return;
+				return Err(PacketError::UnsupportedVersion(status.version));
 			}
 			if self.config.roles & Roles::LIGHT == Roles::LIGHT {
 				let self_best_block = self.context_data.chain.info().ok()
@@ -475,31 +2261,119 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 				let blocks_difference = self_best_block.as_().checked_sub(status.best_number.as_()).unwrap_or(0);
 				if blocks_difference > LIGHT_MAXIMAL_BLOCKS_DIFFERENCE {
 					io.report_peer(who, Severity::Useless("Peer is far behind us and will unable to serve light requests"));
-					return;
+					return Ok(());
 				}
 			}
 
+			let peer_id = io.peer_id(who);
+			let restored = peer_id.as_ref().and_then(|id| self.restored_propagation.write().remove(id));
+			let (known_extrinsics, known_blocks) = match restored {
+				Some(known) => {
+					trace!(target: "sync", "Restoring {} extrinsics and {} blocks known to {}", known.extrinsics.len(), known.blocks.len(), who);
+					(known.extrinsics, known.blocks)
+				},
+				None => (HashSet::new(), HashSet::new()),
+			};
 			let peer = Peer {
 				protocol_version: status.version,
 				roles: status.roles,
 				best_hash: status.best_hash,
+				best_hash_known,
 				best_number: status.best_number,
 				block_request: None,
 				request_timestamp: None,
-				known_extrinsics: HashSet::new(),
-				known_blocks: HashSet::new(),
+				known_extrinsics,
+				known_blocks,
 				next_request_id: 0,
+				session_nonce: status.session_nonce,
+				block_response_chunks: None,
+				announced_heads: HashMap::new(),
+				extrinsics_reputation: 0,
+				mismatched_responses: 0,
+				unsolicited_responses: 0,
+				suspicious_requests: 0,
+				serve_limit: self.default_serve_limit(),
+				compact_body_prefix: None,
+				features: self.peer_features(io, who, status.roles),
+				peer_id,
+				goodbye: None,
+				available_from: Zero::zero(),
+				connected_at: time::Instant::now(),
+				reputation_decayed_at: time::Instant::now(),
+				last_active: time::Instant::now(),
+			};
+			let evicted = match self.config.soft_peer_limit {
+				Some(limit) if peers.len() >= limit => {
+					// among equally scored peers, the one idle for the longest goes first
+					let score = peer.score();
+					peers.iter()
+						.map(|(who, p)| (*who, p.score(), p.last_active))
+						.min_by_key(|&(_, s, last_active)| (s, last_active))
+						.and_then(|(worst, worst_score, _)| if worst_score < score { Some(worst) } else { None })
+				},
+				_ => None,
 			};
 			peers.insert(who.clone(), peer);
 			handshaking_peers.remove(&who);
 			debug!(target: "sync", "Connected {} {}", who, io.peer_debug_info(who));
+			evicted
+		};
+
+		if let Some(worst) = evicted {
+			debug!(target: "sync", "Soft peer limit reached, evicting {} in favour of {}", worst, who);
+			self.note_metric("peers.evicted", 1);
+			self.disconnect_peer(io, worst, message::GoodbyeReason::TooManyPeers);
+			self.on_peer_disconnected(io, worst);
+		}
+
+		{
+			let mut context = ProtocolContext::new(&self.context_data, io);
+			self.on_demand.as_ref().map(|s| s.on_connect(who, status.roles, status.best_number));
+			// sync learns about the peer once its best hash is known
+			if best_hash_known {
+				self.sync.write().new_peer(&mut context, who);
+			}
+			self.consensus_gossip.write().new_peer(&mut context, who, status.roles);
+			self.specialization.write().on_connect(&mut context, who, status);
+			if let Some(available_from) = *self.notified_pruning.read() {
+				context.send_message(who, GenericMessage::PruningNotification(message::generic::PruningNotification { available_from }));
+			}
+			if self.config.block_digests {
+				if let Some(digest) = self.block_digest() {
+					context.send_message(who, GenericMessage::BlockDigest(digest));
+				}
+			}
+		}
+
+		let early_announcements = io.peer_id(who).and_then(|peer_id| self.early_announcements.write().remove(&peer_id));
+		for (received, announce) in early_announcements.into_iter().flat_map(|a| a) {
+			if received.elapsed() < time::Duration::from_secs(EARLY_ANNOUNCEMENT_TIMEOUT_SEC) {
+				// the peer is reported for invalid ones
+				let _ = self.on_block_announce(io, who, announce);
+			}
+		}
+		Ok(())
+	}
+
+	/// Digest of the most recent blocks of our best chain.
+	fn block_digest(&self) -> Option<message::BlockDigest> {
+		let chain = &self.context_data.chain;
+		let best_number = chain.info().ok()?.chain.best_number.as_();
+		let mut digest = message::BlockDigest::new(message::BLOCK_DIGEST_SIZE);
+		for number in best_number.saturating_sub(message::BLOCK_DIGEST_DEPTH - 1)..best_number + 1 {
+			digest.insert(chain.block_hash(As::sa(number)).ok()??.as_ref());
 		}
+		Some(digest)
+	}
 
-		let mut context = ProtocolContext::new(&self.context_data, io);
-		self.on_demand.as_ref().map(|s| s.on_connect(who, status.roles, status.best_number));
-		self.sync.write().new_peer(&mut context, who);
-		self.consensus_gossip.write().new_peer(&mut context, who, status.roles);
-		self.specialization.write().on_connect(&mut context, who, status);
+	fn on_block_digest(&self, io: &mut SyncIo, who: NodeIndex, digest: message::BlockDigest) -> Result<(), PacketError> {
+		trace!(target: "sync", "Received block digest from {}", who);
+		if digest.bits.is_empty() || digest.bits.len() > message::MAX_BLOCK_DIGEST_SIZE {
+			io.report_peer(who, Severity::Bad("Peer sent an invalid block digest"));
+			return Err(PacketError::InvalidDigest);
+		}
+		self.sync.write().on_block_digest(who, digest);
+		Ok(())
 	}
 
 	/// Called when peer sends us new extrinsics
@@ -510,14 +2384,111 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 			return;
 		}
 		trace!(target: "sync", "Received {} extrinsics from {}", extrinsics.len(), who);
-		let mut peers = self.context_data.peers.write();
-		if let Some(ref mut peer) = peers.get_mut(&who) {
-			for t in extrinsics {
-				if let Some(hash) = self.transaction_pool.import(&t) {
-					peer.known_extrinsics.insert(hash);
-				} else {
-					trace!(target: "sync", "Extrinsic rejected");
+		let reputation = match self.context_data.peers.read().get(&who) {
+			Some(peer) => peer.extrinsics_reputation,
+			None => return,
+		};
+
+		// The pool is being updated after a reorg, hold the extrinsics back until it settles
+		if let Some(ref mut deferred) = *self.deferred_extrinsics.write() {
+			let count = deferred.iter().map(|&(_, ref e)| e.len()).sum::<usize>();
+			if count + extrinsics.len() > MAX_DEFERRED_EXTRINSICS {
+				trace!(target: "sync", "Dropping {} extrinsics from {} received during reorg", extrinsics.len(), who);
+			} else {
+				trace!(target: "sync", "Deferring {} extrinsics from {} until reorg is processed", extrinsics.len(), who);
+				deferred.push((who, extrinsics));
+			}
+			return;
+		}
+
+		let extrinsics = match self.extrinsics_importer {
+			Some(ref importer) => match importer.batches.lock().try_send((who, reputation, extrinsics)) {
+				Ok(()) => return,
+				Err(mpsc::TrySendError::Full((_, _, extrinsics))) |
+				Err(mpsc::TrySendError::Disconnected((_, _, extrinsics))) => {
+					trace!(target: "sync", "Extrinsics import queue is unavailable, importing {} extrinsics in place", extrinsics.len());
+					extrinsics
+				},
+			},
+			None => extrinsics,
+		};
+		let imported = import_extrinsics(&*self.transaction_pool, reputation, &extrinsics);
+		self.note_imported_extrinsics(who, extrinsics.len(), imported);
+	}
+
+	fn import_deferred_extrinsics(&self, io: &mut SyncIo) {
+		let deferred = self.deferred_extrinsics.write().take();
+		for (who, extrinsics) in deferred.into_iter().flat_map(|d| d) {
+			self.on_extrinsics(io, who, extrinsics);
+		}
+	}
+
+	/// Record the outcome of importing `received` extrinsics from `who`, of which the ones
+	/// with the given pool hashes and digests were accepted.
+	fn note_imported_extrinsics(&self, who: NodeIndex, received: usize, imported: Vec<(H, B::Hash)>) {
+		// Other peers may know a colliding hash for another extrinsic only, forget it so that
+		// this one gets propagated.
+		let collisions: Vec<H> = imported.iter()
+			.filter(|&&(ref hash, digest)| self.is_pool_hash_collision(who, hash, digest))
+			.map(|&(ref hash, _)| hash.clone())
+			.collect();
+		let source = {
+			let mut peers = self.context_data.peers.write();
+			if !collisions.is_empty() {
+				for (_, peer) in peers.iter_mut().filter(|&(other, _)| *other != who) {
+					for hash in &collisions {
+						peer.known_extrinsics.remove(hash);
+					}
+				}
+			}
+			match peers.get_mut(&who) {
+				Some(peer) => {
+					let rejected = received.saturating_sub(imported.len());
+					peer.extrinsics_reputation = peer.extrinsics_reputation
+						.saturating_add(imported.len() as i32)
+						.saturating_sub(rejected as i32);
+					peer.known_extrinsics.extend(imported.iter().map(|&(ref hash, _)| hash.clone()));
+					peer.peer_id.clone()
+				},
+				None => None,
+			}
+		};
+		// The peers lock is released, the hook may call back into the protocol.
+		if let (Some(hook), Some(source)) = (self.config.on_transaction_imported_hook.as_ref(), source) {
+			for &(_, digest) in &imported {
+				hook(digest.as_ref(), source.clone());
+			}
+		}
+	}
+
+	/// Whether the pool returned `hash` for an extrinsic with the given digest, while it
+	/// returned the same hash for a different extrinsic before. The latest digest is kept.
+	fn is_pool_hash_collision(&self, who: NodeIndex, hash: &H, digest: B::Hash) -> bool {
+		let mut digests = self.imported_extrinsic_digests.write();
+		match digests.get(hash).cloned() {
+			Some(known) if known != digest => {
+				warn!(target: "sync", "Transaction pool returned hash {:?} for different extrinsics ({} and {}) received from {}", hash, known, digest, who);
+				self.note_metric("extrinsics.hash_collisions", 1);
+				digests.insert(hash.clone(), digest);
+				true
+			},
+			Some(_) => false,
+			None => {
+				digests.insert(hash.clone(), digest);
+				if digests.len() > MAX_IMPORTED_EXTRINSIC_DIGESTS {
+					digests.pop_front();
 				}
+				false
+			},
+		}
+	}
+
+	/// Record the extrinsics imported by the background importer as known to their senders.
+	fn collect_imported_extrinsics(&self) {
+		if let Some(ref importer) = self.extrinsics_importer {
+			let imported: Vec<_> = importer.imported.lock().try_iter().collect();
+			for (who, received, accepted) in imported {
+				self.note_imported_extrinsics(who, received, accepted);
 			}
 		}
 	}
@@ -531,31 +2502,177 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 			return;
 		}
 
+		self.collect_imported_extrinsics();
 		let extrinsics = self.transaction_pool.transactions();
 
 		let mut propagated_to = HashMap::new();
-		let mut peers = self.context_data.peers.write();
-		for (who, ref mut peer) in peers.iter_mut() {
-			let (hashes, to_send): (Vec<_>, Vec<_>) = extrinsics
-				.iter()
-				.filter(|&(ref hash, _)| peer.known_extrinsics.insert(hash.clone()))
-				.cloned()
-				.unzip();
+		let peers = self.context_data.peers.read();
+		let order = self.broadcast_order(&*peers);
+
+		// Go through the pool once, sorting the extrinsics into one bucket per peer. An extrinsic
+		// is cloned for all of its recipients but the last one, which takes it.
+		let mut buckets: Vec<(Vec<H>, Vec<B::Extrinsic>)> = order.iter().map(|_| (Vec::new(), Vec::new())).collect();
+		let mut recipients = Vec::with_capacity(order.len());
+		for (hash, extrinsic) in extrinsics {
+			if !self.transaction_pool.is_ready(&hash) {
+				continue;
+			}
+			recipients.clear();
+			for (index, who) in order.iter().enumerate() {
+				let peer = peers.get(who).expect("`who` is one of the keys of `peers`; qed");
+				if !peer.known_extrinsics.contains(&hash) {
+					recipients.push(index);
+				}
+			}
+			if let Some((&last, others)) = recipients.split_last() {
+				for &index in others {
+					buckets[index].0.push(hash.clone());
+					buckets[index].1.push(extrinsic.clone());
+				}
+				buckets[last].0.push(hash);
+				buckets[last].1.push(extrinsic);
+			}
+		}
+		drop(peers);
 
+		// Peers are only marked as knowing the extrinsics once they have been queued to them,
+		// so that a failed send doesn't prevent sending them again. Sending takes the lock of
+		// the peers, so it is only taken again afterwards.
+		let mut sent = Vec::with_capacity(order.len());
+		for (who, (hashes, to_send)) in order.into_iter().zip(buckets) {
 			if !to_send.is_empty() {
-				let node_id = io.peer_id(*who).map(|id| id.to_base58());
+				trace!(target: "sync", "Sending {} transactions to {}", to_send.len(), who);
+				if !self.send_message(io, who, GenericMessage::Transactions(to_send)) {
+					debug!(target: "sync", "Failed to send transactions to {}", who);
+					continue;
+				}
+				sent.push((who, hashes));
+			}
+		}
+
+		{
+			let mut peers = self.context_data.peers.write();
+			for (who, hashes) in sent {
+				self.note_propagated_extrinsics(&hashes);
+				if let Some(ref mut peer) = peers.get_mut(&who) {
+					peer.known_extrinsics.extend(hashes.iter().cloned());
+				}
+				let node_id = io.peer_id(who).map(|id| id.to_base58());
 				if let Some(id) = node_id {
 					for hash in hashes {
 						propagated_to.entry(hash).or_insert_with(Vec::new).push(id.clone());
 					}
 				}
-				trace!(target: "sync", "Sending {} transactions to {}", to_send.len(), who);
-				self.send_message(io, *who, GenericMessage::Transactions(to_send));
 			}
 		}
 		self.transaction_pool.on_broadcasted(propagated_to);
 	}
 
+	/// Propagate again the ready extrinsics last propagated longer than the configured age
+	/// before `now`, forgetting that peers know them.
+	pub(crate) fn repropagate_extrinsics_at(&self, io: &mut SyncIo, now: time::Instant) {
+		let age = match self.config.extrinsics_repropagation_age {
+			Some(age) => age,
+			None => return,
+		};
+		let stale: Vec<H> = {
+			let propagated = self.propagated_extrinsics.read();
+			self.transaction_pool.transactions().into_iter()
+				.map(|(hash, _)| hash)
+				.filter(|hash| propagated.get(hash).map_or(false, |sent| *sent + age <= now))
+				.collect()
+		};
+		if stale.is_empty() {
+			return;
+		}
+		trace!(target: "sync", "Propagating {} extrinsics again", stale.len());
+		self.note_metric("extrinsics.repropagated", stale.len() as u64);
+		for peer in self.context_data.peers.write().values_mut() {
+			for hash in &stale {
+				peer.known_extrinsics.remove(hash);
+			}
+		}
+		self.propagate_extrinsics(io);
+	}
+
+	fn note_propagated_extrinsics(&self, hashes: &[H]) {
+		let now = time::Instant::now();
+		let mut propagated = self.propagated_extrinsics.write();
+		for hash in hashes {
+			propagated.insert(hash.clone(), now);
+		}
+		while propagated.len() > MAX_PROPAGATED_EXTRINSICS {
+			propagated.pop_front();
+		}
+	}
+
+	/// Called when blocks below `available_from` have been pruned. Peers are notified when the
+	/// boundary advanced significantly since the last notification.
+	pub fn on_blocks_pruned(&self, io: &mut SyncIo, available_from: NumberFor<B>) {
+		{
+			let mut notified = self.notified_pruning.write();
+			let significant = match *notified {
+				Some(n) => available_from > n && (available_from - n).as_() >= PRUNING_NOTIFICATION_INTERVAL,
+				None => true,
+			};
+			if !significant {
+				return;
+			}
+			*notified = Some(available_from);
+		}
+		trace!(target: "sync", "Notifying peers of blocks pruned below #{}", available_from);
+		let peers: Vec<NodeIndex> = self.context_data.peers.read().keys().cloned().collect();
+		for who in peers {
+			self.send_message(io, who, GenericMessage::PruningNotification(message::generic::PruningNotification { available_from }));
+		}
+	}
+
+	fn on_pruning_notification(&self, who: NodeIndex, notification: message::generic::PruningNotification<NumberFor<B>>) {
+		trace!(target: "sync", "Peer {} serves blocks from #{}", who, notification.available_from);
+		if let Some(peer) = self.context_data.peers.write().get_mut(&who) {
+			if notification.available_from > peer.available_from {
+				peer.available_from = notification.available_from;
+			}
+		}
+	}
+
+	/// Lowest block the peer serves, as notified by the peer.
+	pub fn peer_available_from(&self, who: NodeIndex) -> Option<NumberFor<B>> {
+		self.context_data.peers.read().get(&who).map(|p| p.available_from)
+	}
+
+	/// Items known to the connected peers whose identity is known.
+	pub fn export_propagation_state(&self) -> PropagationSnapshot<B, H> {
+		let peers = self.context_data.peers.read().values()
+			.filter_map(|peer| peer.peer_id.clone().map(|id| (id, KnownItems {
+				extrinsics: peer.known_extrinsics.clone(),
+				blocks: peer.known_blocks.clone(),
+			})))
+			.collect();
+		PropagationSnapshot { peers }
+	}
+
+	/// Restore the items known to peers exported before a restart. The known items of a peer are
+	/// restored when it connects.
+	pub fn import_propagation_state(&self, snapshot: PropagationSnapshot<B, H>) {
+		self.restored_propagation.write().extend(snapshot.peers);
+	}
+
+	/// Whether the extrinsic has recently been sent to at least one peer.
+	pub fn is_extrinsic_propagated(&self, hash: &H) -> bool {
+		self.propagated_extrinsics.read().contains_key(hash)
+	}
+
+	/// Order in which connected peers are visited when broadcasting. With a seed configured,
+	/// the order only depends on the seed and the set of peers.
+	fn broadcast_order(&self, peers: &HashMap<NodeIndex, Peer<B, H>>) -> Vec<NodeIndex> {
+		let mut order: Vec<NodeIndex> = peers.keys().cloned().collect();
+		if let Some(seed) = self.config.peer_order_seed {
+			order.sort_by_key(|who| (*who as u64 ^ seed).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+		}
+		order
+	}
+
 	/// Send Status message
 	fn send_status(&self, io: &mut SyncIo, who: NodeIndex) {
 		if let Ok(info) = self.context_data.chain.info() {
@@ -566,11 +2683,28 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 				best_number: info.chain.best_number,
 				best_hash: info.chain.best_hash,
 				chain_status: self.specialization.read().status(),
+				session_nonce: self.session_nonce,
 			};
 			self.send_message(io, who, GenericMessage::Status(status))
 		}
 	}
 
+	/// Disconnect all peers, e.g. to isolate the node during an attack, and forget about them.
+	/// Unlike `abort`, sync and consensus keep their state and are ready for the next peers.
+	pub fn disconnect_all(&self, io: &mut SyncIo) {
+		let connected: Vec<NodeIndex> = self.context_data.peers.read().keys().cloned().collect();
+		let handshaking: Vec<NodeIndex> = self.handshaking_peers.read().keys().cloned().collect();
+		debug!(target: "sync", "Disconnecting all {} peers", connected.len() + handshaking.len());
+		for who in connected {
+			self.disconnect_peer(io, who, message::GoodbyeReason::Shutdown);
+			self.on_peer_disconnected(io, who);
+		}
+		for who in handshaking {
+			io.report_peer(who, Severity::Useless("Disconnecting all peers"));
+			self.on_peer_disconnected(io, who);
+		}
+	}
+
 	pub fn abort(&self) {
 		let mut sync = self.sync.write();
 		let mut spec = self.specialization.write();
@@ -581,6 +2715,7 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 		spec.on_abort();
 		peers.clear();
 		handshaking_peers.clear();
+		self.context_data.deferred_requests.write().clear();
 		consensus_gossip.abort();
 	}
 
@@ -593,20 +2728,179 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 		self.abort();
 	}
 
-	pub fn on_block_announce(&self, io: &mut SyncIo, who: NodeIndex, announce: message::BlockAnnounce<B::Header>) {
+	pub fn on_block_announce(&self, io: &mut SyncIo, who: NodeIndex, announce: message::BlockAnnounce<B::Header>) -> Result<(), PacketError> {
 		let header = announce.header;
 		let hash = header.hash();
-		{
+		if let Err(reason) = self.check_announced_header(&header) {
+			io.report_peer(who, Severity::Bad(&format!("Peer announced impossible block {}: {}", hash, reason)));
+			return Err(PacketError::InvalidAnnouncement);
+		}
+		if !self.context_data.peers.read().contains_key(&who) {
+			self.note_early_announcement(io, who, header);
+			return Ok(());
+		}
+		let (too_many_forks, best_hash_learned) = {
 			let mut peers = self.context_data.peers.write();
-			if let Some(ref mut peer) = peers.get_mut(&who) {
+			let outcome = if let Some(ref mut peer) = peers.get_mut(&who) {
 				peer.known_blocks.insert(hash.clone());
+				peer.last_active = time::Instant::now();
+				let number = *header.number();
+				let best_hash_learned = !peer.best_hash_known && number >= peer.best_number;
+				// lower announcements are of forks, not regressions
+				if number > peer.best_number || best_hash_learned {
+					peer.best_number = number;
+					peer.best_hash = hash;
+					peer.best_hash_known = true;
+				}
+				peer.announced_heads.remove(header.parent_hash());
+				peer.announced_heads.retain(|_, n| *n >= number || number - *n <= As::sa(ANNOUNCED_FORK_DEPTH));
+				peer.announced_heads.insert(hash, number);
+				(peer.announced_heads.len() > MAX_ANNOUNCED_FORKS, best_hash_learned)
+			} else {
+				(false, false)
+			};
+			if let Some(limit) = self.config.max_announced_forks {
+				let evicted = evict_announced_forks(&mut *peers, limit);
+				if evicted > 0 {
+					trace!(target: "sync", "Forgetting {} least supported announced forks", evicted);
+					self.note_metric("announces.evicted_forks", evicted as u64);
+				}
 			}
+			outcome
+		};
+		if too_many_forks {
+			io.report_peer(who, Severity::Bad("Peer announced too many forks"));
+			return Ok(());
+		}
+		if best_hash_learned {
+			trace!(target: "sync", "Best hash of {} is now known", who);
+			self.sync.write().new_peer(&mut ProtocolContext::new(&self.context_data, io), who);
 		}
 		self.on_demand.as_ref().map(|s| s.on_block_announce(who, *header.number()));
-		self.sync.write().on_block_announce(&mut ProtocolContext::new(&self.context_data, io), who, hash, &header);
+		// Likely a block we announced, coming back. Sync still learns the peer's best block from
+		// it, but has nothing to download.
+		if let Ok(BlockStatus::InChain) = self.context_data.chain.block_status(&BlockId::Hash(hash)) {
+			trace!(target: "sync", "Announcement of block {} already in our chain from {}", hash, who);
+			self.note_metric("announces.already_in_chain", 1);
+		} else {
+			self.note_block_source(hash, who);
+		}
+		match self.config.announce_batch_size {
+			Some(batch_size) => {
+				let full = {
+					let mut pending = self.pending_announces.write();
+					pending.push((who, hash, header));
+					pending.len() >= batch_size
+				};
+				if full {
+					self.deliver_block_announces(io);
+				}
+			},
+			None => self.sync.write().on_block_announce(&mut ProtocolContext::new(&self.context_data, io), who, hash, &header),
+		}
+		Ok(())
+	}
+
+	/// Deliver the buffered block announcements to sync at once.
+	fn deliver_block_announces(&self, io: &mut SyncIo) {
+		let announces = mem::replace(&mut *self.pending_announces.write(), Vec::new());
+		if announces.is_empty() {
+			return;
+		}
+		trace!(target: "sync", "Delivering {} block announcements to sync", announces.len());
+		self.note_metric("sync.announce_batches", 1);
+		self.sync.write().on_block_announces(&mut ProtocolContext::new(&self.context_data, io), announces);
+	}
+
+	/// Handle a block announcement as if it was received from the connected peer with the given
+	/// ID, e.g. to point sync to a known good block. Returns `false` if no such peer is connected.
+	#[cfg(any(test, feature = "block-announce-injection"))]
+	pub fn inject_block_announce(&self, io: &mut SyncIo, peer_id: &PeerId, announce: message::BlockAnnounce<B::Header>) -> bool {
+		let who = self.context_data.peers.read().iter()
+			.find(|&(_, peer)| peer.peer_id.as_ref() == Some(peer_id))
+			.map(|(who, _)| *who);
+		match who {
+			Some(who) => {
+				debug!(target: "sync", "Injecting block announce {} from {}", announce.header.hash(), who);
+				let _ = self.on_block_announce(io, who, announce);
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Check how many blocks below the highest best block it reported, through its status or
+	/// announcements, a peer reports its best block in the status of a new session. Shallow
+	/// regressions are reorgs on the peer's side, peers regressing deeper are reported.
+	/// Returns `false` if the peer has been reported.
+	fn check_best_regression(&self, io: &mut SyncIo, who: NodeIndex, regression: u64) -> bool {
+		if regression > MAX_BEST_REGRESSION {
+			io.report_peer(who, Severity::Bad(&format!("Peer's best block regressed by {} blocks", regression)));
+			return false;
+		}
+		if regression > 0 {
+			trace!(target: "sync", "Best block of {} regressed by {} blocks", who, regression);
+			self.note_metric("peers.best_regressions", 1);
+		}
+		true
+	}
+
+	/// Buffer an announcement from a node which has not completed the handshake yet.
+	fn note_early_announcement(&self, io: &SyncIo, who: NodeIndex, header: B::Header) {
+		if !self.handshaking_peers.read().contains_key(&who) {
+			return;
+		}
+		let peer_id = match io.peer_id(who) {
+			Some(peer_id) => peer_id,
+			None => return,
+		};
+		trace!(target: "sync", "Buffering block announce from {} received before its status", who);
+		let mut early_announcements = self.early_announcements.write();
+		{
+			let announcements = early_announcements.entry(peer_id).or_insert_with(Vec::new);
+			if announcements.len() < MAX_EARLY_ANNOUNCEMENTS {
+				announcements.push((time::Instant::now(), message::BlockAnnounce { header }));
+			}
+		}
+		while early_announcements.len() > MAX_EARLY_ANNOUNCING_NODES {
+			early_announcements.pop_front();
+		}
+	}
+
+	/// Cheap sanity check of an announced header, rejecting headers that can't be part of our chain.
+	fn check_announced_header(&self, header: &B::Header) -> Result<(), &'static str> {
+		if header.number().is_zero() {
+			if header.hash() != self.genesis_hash {
+				return Err("announced block 0 is not our genesis");
+			}
+			return Err("genesis block is never announced");
+		}
+		if *header.parent_hash() == Default::default() {
+			return Err("empty parent hash");
+		}
+		if let Ok(Some(parent)) = self.context_data.chain.header(&BlockId::Hash(*header.parent_hash())) {
+			if *parent.number() + As::sa(1) != *header.number() {
+				return Err("block number does not follow its parent");
+			}
+		}
+		Ok(())
 	}
 
 	pub fn on_block_imported(&self, io: &mut SyncIo, hash: B::Hash, header: &B::Header) {
+		{
+			let mut last_imported = self.last_imported.write();
+			let previous = *last_imported;
+			if previous.map_or(false, |previous| previous != hash && previous != *header.parent_hash()) {
+				trace!(target: "sync", "Reorg to {:?}, deferring peer extrinsics", hash);
+				self.served_blocks.write().clear();
+				let mut deferred = self.deferred_extrinsics.write();
+				if deferred.is_none() {
+					*deferred = Some(Vec::new());
+				}
+			}
+			*last_imported = Some(hash);
+		}
+
 		self.sync.write().update_chain_info(&header);
 		self.specialization.write().on_block_imported(
 			&mut ProtocolContext::new(&self.context_data, io),
@@ -619,28 +2913,104 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 			return;
 		}
 
-		// send out block announcements
-		let mut peers = self.context_data.peers.write();
+		// peers would request the body of the announced block, don't announce it before we can serve it
+		if !self.is_body_available(&hash) {
+			trace!(target: "sync", "Deferring announcement of block {:?} until its body is available", hash);
+			let mut pending = self.pending_announcements.write();
+			if pending.len() >= MAX_PENDING_ANNOUNCEMENTS {
+				pending.remove(0);
+			}
+			pending.push((hash, header.clone()));
+			return;
+		}
+
+		self.announce_block(io, hash, header);
+	}
+
+	fn is_body_available(&self, hash: &B::Hash) -> bool {
+		self.context_data.chain.body(&BlockId::Hash(*hash)).ok().map_or(false, |body| body.is_some())
+	}
+
+	fn announce_pending_blocks(&self, io: &mut SyncIo) {
+		let ready: Vec<_> = {
+			let mut pending = self.pending_announcements.write();
+			let (ready, waiting): (Vec<_>, Vec<_>) = mem::replace(&mut *pending, Vec::new())
+				.into_iter()
+				.partition(|&(ref hash, _)| self.is_body_available(hash));
+			*pending = waiting;
+			ready
+		};
+		for (hash, header) in ready {
+			self.announce_block(io, hash, &header);
+		}
+	}
+
+	fn announce_block(&self, io: &mut SyncIo, hash: B::Hash, header: &B::Header) {
+		let recipients: Vec<NodeIndex> = {
+			let peers = self.context_data.peers.read();
+			let recipients = self.broadcast_order(&*peers).into_iter()
+				.filter(|who| !peers.get(who).expect("`who` is one of the keys of `peers`; qed").known_blocks.contains(&hash))
+				.collect();
+			recipients
+		};
 
-		for (who, ref mut peer) in peers.iter_mut() {
-			if peer.known_blocks.insert(hash.clone()) {
-				trace!(target: "sync", "Announcing block {:?} to {}", hash, who);
-				self.send_message(io, *who, GenericMessage::BlockAnnounce(message::BlockAnnounce {
-					header: header.clone()
-				}));
+		// Sending takes the lock of the peers, so it is only taken again afterwards to remember
+		// the peers know the block once the announcement has been queued.
+		let mut announced = Vec::with_capacity(recipients.len());
+		for who in recipients {
+			trace!(target: "sync", "Announcing block {:?} to {}", hash, who);
+			let sent = self.send_message(io, who, GenericMessage::BlockAnnounce(message::BlockAnnounce {
+				header: header.clone()
+			}));
+			if sent {
+				announced.push(who);
 			}
 		}
+
+		let mut peers = self.context_data.peers.write();
+		for who in announced {
+			if let Some(ref mut peer) = peers.get_mut(&who) {
+				peer.known_blocks.insert(hash.clone());
+			}
+		}
+	}
+
+	/// Optional requests served to a peer with the given roles. Authorities and trusted peers
+	/// are served everything.
+	fn peer_features(&self, io: &SyncIo, who: NodeIndex, roles: Roles) -> PeerFeatures {
+		let trusted = roles.contains(Roles::AUTHORITY)
+			|| io.peer_id(who).map_or(false, |id| self.config.trusted_peers.contains(&id));
+		if trusted {
+			PeerFeatures::all()
+		} else {
+			self.config.untrusted_peer_features
+		}
+	}
+
+	/// Whether the peer is served requests of the given kind.
+	fn serves(&self, who: NodeIndex, feature: PeerFeatures) -> bool {
+		self.context_data.peers.read().get(&who).map_or(false, |p| p.features.contains(feature))
+	}
+
+	fn note_refused_request(&self, kind: &str, id: message::RequestId, who: NodeIndex) {
+		trace!(target: "sync", "Not serving remote {} request {} to untrusted peer {}", kind, id, who);
+		self.note_metric("requests.refused_untrusted", 1);
 	}
 
 	fn on_remote_call_request(&self, io: &mut SyncIo, who: NodeIndex, request: message::RemoteCallRequest<B::Hash>) {
 		trace!(target: "sync", "Remote call request {} from {} ({} at {})", request.id, who, request.method, request.block);
-		let proof = match self.context_data.chain.execution_proof(&request.block, &request.method, &request.data) {
-			Ok((_, proof)) => proof,
-			Err(error) => {
-				trace!(target: "sync", "Remote call request {} from {} ({} at {}) failed with: {}",
-					request.id, who, request.method, request.block, error);
-				Default::default()
-			},
+		let proof = if !self.serves(who, PeerFeatures::REMOTE_CALL) {
+			self.note_refused_request("call", request.id, who);
+			Default::default()
+		} else {
+			match self.serving_chain().execution_proof(&request.block, &request.method, &request.data) {
+				Ok((_, proof)) => proof,
+				Err(error) => {
+					trace!(target: "sync", "Remote call request {} from {} ({} at {}) failed with: {}",
+						request.id, who, request.method, request.block, error);
+					Default::default()
+				},
+			}
 		};
 
 		self.send_message(io, who, GenericMessage::RemoteCallResponse(message::RemoteCallResponse {
@@ -656,13 +3026,18 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 	fn on_remote_read_request(&self, io: &mut SyncIo, who: NodeIndex, request: message::RemoteReadRequest<B::Hash>) {
 		trace!(target: "sync", "Remote read request {} from {} ({} at {})",
 			request.id, who, request.key.to_hex(), request.block);
-		let proof = match self.context_data.chain.read_proof(&request.block, &request.key) {
-			Ok(proof) => proof,
-			Err(error) => {
-				trace!(target: "sync", "Remote read request {} from {} ({} at {}) failed with: {}",
-					request.id, who, request.key.to_hex(), request.block, error);
-				Default::default()
-			},
+		let proof = if !self.serves(who, PeerFeatures::REMOTE_READ) {
+			self.note_refused_request("read", request.id, who);
+			Default::default()
+		} else {
+			match self.serving_chain().read_proof(&request.block, &request.key) {
+				Ok(proof) => proof,
+				Err(error) => {
+					trace!(target: "sync", "Remote read request {} from {} ({} at {}) failed with: {}",
+						request.id, who, request.key.to_hex(), request.block, error);
+					Default::default()
+				},
+			}
 		};
 		self.send_message(io, who, GenericMessage::RemoteReadResponse(message::RemoteReadResponse {
 			id: request.id, proof,
@@ -676,13 +3051,18 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 	fn on_remote_header_request(&self, io: &mut SyncIo, who: NodeIndex, request: message::RemoteHeaderRequest<NumberFor<B>>) {
 		trace!(target: "sync", "Remote header proof request {} from {} ({})",
 			request.id, who, request.block);
-		let (header, proof) = match self.context_data.chain.header_proof(request.block) {
-			Ok((header, proof)) => (Some(header), proof),
-			Err(error) => {
-				trace!(target: "sync", "Remote header proof request {} from {} ({}) failed with: {}",
-					request.id, who, request.block, error);
-				(Default::default(), Default::default())
-			},
+		let (header, proof) = if !self.serves(who, PeerFeatures::REMOTE_HEADER) {
+			self.note_refused_request("header", request.id, who);
+			(Default::default(), Default::default())
+		} else {
+			match self.serving_chain().header_proof(request.block) {
+				Ok((header, proof)) => (Some(header), proof),
+				Err(error) => {
+					trace!(target: "sync", "Remote header proof request {} from {} ({}) failed with: {}",
+						request.id, who, request.block, error);
+					(Default::default(), Default::default())
+				},
+			}
 		};
  		self.send_message(io, who, GenericMessage::RemoteHeaderResponse(message::RemoteHeaderResponse {
 			id: request.id, header, proof,
@@ -697,18 +3077,24 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 	fn on_remote_changes_request(&self, io: &mut SyncIo, who: NodeIndex, request: message::RemoteChangesRequest<B::Hash>) {
 		trace!(target: "sync", "Remote changes proof request {} from {} for key {} ({}..{})",
 			request.id, who, request.key.to_hex(), request.first, request.last);
-		let proof = match self.context_data.chain.key_changes_proof(request.first, request.last, request.min, request.max, &request.key) {
-			Ok(proof) => proof,
-			Err(error) => {
-				trace!(target: "sync", "Remote changes proof request {} from {} for key {} ({}..{}) failed with: {}",
-					request.id, who, request.key.to_hex(), request.first, request.last, error);
-				ChangesProof::<B::Header> {
-					max_block: Zero::zero(),
-					proof: vec![],
-					roots: BTreeMap::new(),
-					roots_proof: vec![],
-				}
-			},
+		let empty_proof = || ChangesProof::<B::Header> {
+			max_block: Zero::zero(),
+			proof: vec![],
+			roots: BTreeMap::new(),
+			roots_proof: vec![],
+		};
+		let proof = if !self.serves(who, PeerFeatures::REMOTE_CHANGES) {
+			self.note_refused_request("changes", request.id, who);
+			empty_proof()
+		} else {
+			match self.serving_chain().key_changes_proof(request.first, request.last, request.min, request.max, &request.key) {
+				Ok(proof) => proof,
+				Err(error) => {
+					trace!(target: "sync", "Remote changes proof request {} from {} for key {} ({}..{}) failed with: {}",
+						request.id, who, request.key.to_hex(), request.first, request.last, error);
+					empty_proof()
+				},
+			}
 		};
  		self.send_message(io, who, GenericMessage::RemoteChangesResponse(message::RemoteChangesResponse {
 			id: request.id,
@@ -725,6 +3111,38 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 		self.on_demand.as_ref().map(|s| s.on_remote_changes_response(io, who, response));
 	}
 
+	/// Answer with the authority set at our best block. Light clients have no state to read it
+	/// from and answer with an empty set, as do nodes not serving the peer. Responses are
+	/// left to the specialization.
+	fn on_authority_set_request(&self, io: &mut SyncIo, who: NodeIndex, request: message::AuthoritySetRequest) {
+		trace!(target: "sync", "Authority set request {} from {}", request.id, who);
+		let unserved = || (Zero::zero(), Default::default(), Vec::new());
+		let (number, hash, authorities) = if self.config.roles & Roles::LIGHT == Roles::LIGHT {
+			trace!(target: "sync", "Not serving authority set request {} from {} as a light client", request.id, who);
+			unserved()
+		} else if !self.serves(who, PeerFeatures::AUTHORITY_SET) {
+			self.note_refused_request("authority set", request.id, who);
+			unserved()
+		} else {
+			let chain = self.serving_chain();
+			let set = chain.info().and_then(|info| {
+				let best = info.chain;
+				chain.authorities(&BlockId::Hash(best.best_hash))
+					.map(|authorities| (best.best_number, best.best_hash, authorities))
+			});
+			match set {
+				Ok(set) => set,
+				Err(error) => {
+					trace!(target: "sync", "Authority set request {} from {} failed with: {}", request.id, who, error);
+					unserved()
+				},
+			}
+		};
+		self.send_message(io, who, GenericMessage::AuthoritySetResponse(message::AuthoritySetResponse {
+			id: request.id, number, hash, authorities,
+		}));
+	}
+
 
 	/// Execute a closure with access to a network context and specialization.
 	pub fn with_spec<F, U>(&self, io: &mut SyncIo, f: F) -> U
@@ -734,20 +3152,235 @@ impl<B: BlockT, S: NetworkSpecialization<B>, H: ExHashT> Protocol<B, S, H> {
 	}
 }
 
-fn send_message<B: BlockT, H: ExHashT>(peers: &RwLock<HashMap<NodeIndex, Peer<B, H>>>, io: &mut SyncIo, who: NodeIndex, mut message: Message<B>) {
+/// Whether the block data contains the header and body, if they are in `fields`.
+fn block_has_fields<B: BlockT>(block: &message::BlockData<B>, fields: message::BlockAttributes) -> bool {
+	(!fields.contains(message::BlockAttributes::HEADER) || block.header.is_some())
+		&& (!fields.contains(message::BlockAttributes::BODY) || block.body.is_some())
+}
+
+/// Time a peer is given to answer a block request, growing with the amount of requested data.
+fn block_request_timeout<B: BlockT>(config: &TunableProtocolConfig, request: &message::BlockRequest<B>) -> time::Duration {
+	let blocks = cmp::min(request.max.unwrap_or(MAX_BLOCK_DATA_RESPONSE), MAX_BLOCK_DATA_RESPONSE) as u64;
+	let per_block_ms = if request.fields.contains(message::BlockAttributes::BODY) {
+		BLOCK_REQUEST_TIMEOUT_PER_HEADER_MS + BLOCK_REQUEST_TIMEOUT_PER_BODY_MS
+	} else {
+		BLOCK_REQUEST_TIMEOUT_PER_HEADER_MS
+	};
+	let timeout = config.min_block_request_timeout + time::Duration::from_millis(blocks * per_block_ms);
+	cmp::min(timeout, config.max_block_request_timeout)
+}
+
+/// Read the chain info, retrying a few times so that a database which is momentarily not ready
+/// doesn't abort startup.
+fn chain_info<B: BlockT>(chain: &Client<B>) -> error::Result<ClientInfo<B>> {
+	let mut attempt = 1;
+	loop {
+		match chain.info() {
+			Ok(info) => return Ok(info),
+			Err(ref e) if attempt < CHAIN_INFO_ATTEMPTS => {
+				debug!(target: "sync", "Error reading chain info (attempt {}): {:?}", attempt, e);
+				thread::sleep(time::Duration::from_millis(CHAIN_INFO_RETRY_DELAY_MS));
+				attempt += 1;
+			},
+			Err(e) => return Err(e.into()),
+		}
+	}
+}
+
+/// Imports extrinsics received from peers on a separate thread, so that large batches don't
+/// block the network thread.
+struct ExtrinsicsImporter<B: BlockT, H: ExHashT> {
+	batches: Mutex<mpsc::SyncSender<(NodeIndex, i32, Vec<B::Extrinsic>)>>,
+	imported: Mutex<mpsc::Receiver<(NodeIndex, usize, Vec<(H, B::Hash)>)>>,
+}
+
+impl<B: BlockT, H: ExHashT> ExtrinsicsImporter<B, H> {
+	/// Start the import thread, accepting up to `queue_size` pending batches. The thread
+	/// stops once the importer is dropped.
+	fn spawn(transaction_pool: Arc<TransactionPool<H, B>>, queue_size: usize) -> error::Result<Self> {
+		let (batches_tx, batches_rx) = mpsc::sync_channel::<(NodeIndex, i32, Vec<B::Extrinsic>)>(queue_size);
+		let (imported_tx, imported_rx) = mpsc::channel();
+		thread::Builder::new().name("extrinsics-import".to_string()).spawn(move || {
+			for (who, reputation, extrinsics) in batches_rx {
+				let imported = import_extrinsics(&*transaction_pool, reputation, &extrinsics);
+				if imported_tx.send((who, extrinsics.len(), imported)).is_err() {
+					break;
+				}
+			}
+		})?;
+
+		Ok(ExtrinsicsImporter {
+			batches: Mutex::new(batches_tx),
+			imported: Mutex::new(imported_rx),
+		})
+	}
+}
+
+/// Import extrinsics received from a peer with the given reputation into the pool, returning
+/// the pool hashes of the accepted ones along with the digests of their encoding.
+fn import_extrinsics<B: BlockT, H: ExHashT>(transaction_pool: &TransactionPool<H, B>, reputation: i32, extrinsics: &[B::Extrinsic]) -> Vec<(H, B::Hash)> {
+	extrinsics.iter().filter_map(|t| {
+		let hash = transaction_pool.import(reputation, t);
+		if hash.is_none() {
+			trace!(target: "sync", "Extrinsic rejected");
+		}
+		hash.map(|hash| (hash, HashFor::<B>::hash_of(t)))
+	}).collect()
+}
+
+/// Forget the fork heads announced by the fewest peers, lowest first, until at most `limit`
+/// distinct heads are left. Returns the number of forgotten heads.
+fn evict_announced_forks<B: BlockT, H: ExHashT>(peers: &mut HashMap<NodeIndex, Peer<B, H>>, limit: usize) -> usize {
+	let mut support: HashMap<B::Hash, (usize, NumberFor<B>)> = HashMap::new();
+	for peer in peers.values() {
+		for (hash, number) in &peer.announced_heads {
+			support.entry(*hash).or_insert((0, *number)).0 += 1;
+		}
+	}
+	if support.len() <= limit {
+		return 0;
+	}
+	let mut forks: Vec<_> = support.into_iter().collect();
+	forks.sort_by_key(|&(_, support)| support);
+	let excess = forks.len() - limit;
+	let evicted: HashSet<B::Hash> = forks.into_iter().take(excess).map(|(hash, _)| hash).collect();
+	for peer in peers.values_mut() {
+		peer.announced_heads.retain(|hash, _| !evicted.contains(hash));
+	}
+	evicted.len()
+}
+
+fn consensus_trace_line<B: BlockT>(who: NodeIndex, topic: &B::Hash, message: &[u8]) -> String {
+	format!("consensus-message peer={} topic={} hash={:?} len={}",
+		who, topic, HashFor::<B>::hash(message), message.len())
+}
+
+fn message_metric_key<B: BlockT>(message: &Message<B>) -> &'static str {
+	match *message {
+		GenericMessage::Status(_) => "messages.status",
+		GenericMessage::BlockRequest(_) => "messages.block_request",
+		GenericMessage::BlockResponse(_) => "messages.block_response",
+		GenericMessage::BlockAnnounce(_) => "messages.block_announce",
+		GenericMessage::Transactions(_) => "messages.transactions",
+		GenericMessage::Consensus(..) => "messages.consensus",
+		GenericMessage::RemoteCallRequest(_) => "messages.remote_call_request",
+		GenericMessage::RemoteCallResponse(_) => "messages.remote_call_response",
+		GenericMessage::RemoteReadRequest(_) => "messages.remote_read_request",
+		GenericMessage::RemoteReadResponse(_) => "messages.remote_read_response",
+		GenericMessage::RemoteHeaderRequest(_) => "messages.remote_header_request",
+		GenericMessage::RemoteHeaderResponse(_) => "messages.remote_header_response",
+		GenericMessage::RemoteChangesRequest(_) => "messages.remote_changes_request",
+		GenericMessage::RemoteChangesResponse(_) => "messages.remote_changes_response",
+		GenericMessage::BlockResponseChunk(_) => "messages.block_response_chunk",
+		GenericMessage::BlockChunkRequest(_) => "messages.block_chunk_request",
+		GenericMessage::BlockDigest(_) => "messages.block_digest",
+		GenericMessage::Batch(_) => "messages.batch",
+		GenericMessage::PruningNotification(_) => "messages.pruning_notification",
+		GenericMessage::Goodbye(_) => "messages.goodbye",
+		GenericMessage::AuthoritySetRequest(_) => "messages.authority_set_request",
+		GenericMessage::AuthoritySetResponse(_) => "messages.authority_set_response",
+		GenericMessage::CompactStatus(_) => "messages.compact_status",
+		GenericMessage::ChainSpecific(_) => "messages.chain_specific",
+	}
+}
+
+fn send_message<B: BlockT, H: ExHashT>(context_data: &ContextData<B, H>, io: &mut SyncIo, who: NodeIndex, message: Message<B>) -> bool {
+	let mut sent = true;
+	for data in encode_message(context_data, who, message) {
+		let len = data.len() as u64;
+		if io.send(who, data) {
+			context_data.note_traffic(who, |t| t.bytes_sent += len);
+		} else {
+			sent = false;
+		}
+	}
+	sent
+}
+
+/// Note an outgoing message in the peer state and encode it in the shape the protocol
+/// version of the peer understands, which may take several messages or none. The version
+/// is only looked up for messages added or changed since version 1.
+fn encode_message<B: BlockT, H: ExHashT>(context_data: &ContextData<B, H>, who: NodeIndex, message: Message<B>) -> Vec<Vec<u8>> {
+	let legacy = || context_data.peer_version(who) < EXTENDED_FORMATS_VERSION;
+	let mut message = match message {
+		GenericMessage::BlockResponse(response) => {
+			if legacy() {
+				let response: ::message::v1::BlockResponse<_, _, _> = response.into();
+				let mut data = vec![::message::BLOCK_RESPONSE_MESSAGE_INDEX];
+				response.encode_to(&mut data);
+				return vec![data];
+			}
+			GenericMessage::BlockResponse(response)
+		},
+		message => message,
+	};
 	match &mut message {
+		&mut GenericMessage::Batch(ref mut messages) if context_data.peer_version(who) < BATCH_AND_GOODBYE_VERSION => {
+			trace!(target: "sync", "Sending {} batched messages one by one to {}", messages.len(), who);
+			return mem::replace(messages, Vec::new());
+		},
+		&mut GenericMessage::Goodbye(ref reason) if context_data.peer_version(who) < BATCH_AND_GOODBYE_VERSION => {
+			trace!(target: "sync", "Not saying goodbye ({:?}) to {}, its protocol version predates it", reason, who);
+			return Vec::new();
+		},
 		&mut GenericMessage::BlockRequest(ref mut r) => {
-			let mut peers = peers.write();
+			if context_data.chunked_block_responses {
+				r.fields |= ::message::BlockAttributes::CHUNKED;
+			}
+			if legacy() {
+				r.fields &= ::message::v1::block_attributes();
+			}
+			let mut peers = context_data.peers.write();
+			if let Some(limit) = context_data.global_max_inflight_requests {
+				let idle = peers.get(&who).map_or(false, |p| p.block_request.is_none());
+				if idle && peers.values().filter(|p| p.block_request.is_some()).count() >= limit {
+					trace!(target: "sync", "Deferring block request to {}, {} requests in flight", who, limit);
+					context_data.note_metric("requests.globally_deferred", 1);
+					let mut deferred = context_data.deferred_requests.write();
+					deferred.retain(|&(w, _)| w != who);
+					deferred.push_back((who, r.clone()));
+					return Vec::new();
+				}
+			}
 			if let Some(ref mut peer) = peers.get_mut(&who) {
+				// a request deferred earlier is superseded by this one
+				context_data.deferred_requests.write().retain(|&(w, _)| w != who);
 				r.id = peer.next_request_id;
 				peer.next_request_id = peer.next_request_id + 1;
 				peer.block_request = Some(r.clone());
 				peer.request_timestamp = Some(time::Instant::now());
+				peer.block_response_chunks = None;
+				peer.compact_body_prefix = None;
 			}
 		},
 		_ => (),
 	}
-	io.send(who, message.encode());
+	let data = message.encode();
+	if data[0] > ::message::V1_LAST_MESSAGE_INDEX && data[0] != 255 && legacy() {
+		trace!(target: "sync", "Not sending message {} to {}, its protocol version predates it", data[0], who);
+		return Vec::new();
+	}
+	vec![data]
+}
+
+/// Decode a message from a peer using the given protocol version. Statuses are decoded
+/// according to the version they carry, the version of the peer is not known before.
+fn decode_message<B: BlockT>(version: u32, data: &[u8]) -> Option<Message<B>> {
+	let (index, body) = data.split_first()?;
+	match *index {
+		message::STATUS_MESSAGE_INDEX => {
+			if <u32 as Decode>::decode(&mut &body[..])? < EXTENDED_FORMATS_VERSION {
+				let status: message::v1::Status<B::Hash, NumberFor<B>> = Decode::decode(&mut &body[..])?;
+				return Some(GenericMessage::Status(status.into()));
+			}
+		},
+		message::BLOCK_RESPONSE_MESSAGE_INDEX if version < EXTENDED_FORMATS_VERSION => {
+			let response: message::v1::BlockResponse<B::Header, B::Hash, B::Extrinsic> = Decode::decode(&mut &body[..])?;
+			return Some(GenericMessage::BlockResponse(response.into()));
+		},
+		_ => (),
+	}
+	Decode::decode(&mut &data[..])
 }
 
 /// Construct a simple protocol that is composed of several sub protocols.
@@ -848,3 +3481,40 @@ macro_rules! construct_simple_protocol {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use test_client::runtime::Block;
+	use config::{ProtocolConfig, TunableProtocolConfig};
+	use message::{self, BlockAttributes, Direction, FromBlock};
+	use super::{block_request_timeout, consensus_trace_line};
+
+	fn block_request(fields: BlockAttributes, max: Option<u32>) -> message::BlockRequest<Block> {
+		message::generic::BlockRequest {
+			id: 0,
+			fields,
+			from: FromBlock::Number(1),
+			to: None,
+			direction: Direction::Ascending,
+			max,
+		}
+	}
+
+	#[test]
+	fn block_request_timeout_grows_with_requested_data() {
+		let config = TunableProtocolConfig::from(&ProtocolConfig::default());
+		let small = block_request_timeout(&config, &block_request(BlockAttributes::HEADER, Some(1)));
+		let large = block_request_timeout(&config, &block_request(BlockAttributes::HEADER | BlockAttributes::BODY, Some(128)));
+		assert!(small >= config.min_block_request_timeout);
+		assert!(large > small);
+		assert!(large <= config.max_block_request_timeout);
+	}
+
+	#[test]
+	fn consensus_trace_line_contains_context() {
+		let line = consensus_trace_line::<Block>(3, &Default::default(), &[1, 2, 3]);
+		assert!(line.starts_with("consensus-message peer=3 topic="));
+		assert!(line.contains(" hash="));
+		assert!(line.ends_with(" len=3"));
+	}
+}
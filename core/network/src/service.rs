@@ -25,12 +25,13 @@ use network_libp2p::{start_service, Service as NetworkService, ServiceEvent as N
 use network_libp2p::{RegisteredProtocol, parse_str_addr, Protocol as Libp2pProtocol};
 use io::NetSyncIo;
 use consensus_gossip::ConsensusGossip;
-use protocol::{self, Protocol, ProtocolContext, Context, ProtocolStatus};
-use config::Params;
+use protocol::{self, Protocol, ProtocolContext, Context, ProtocolStatus, Health};
+use config::{Params, TunableProtocolConfig};
 use error::Error;
 use specialization::NetworkSpecialization;
 use import_queue::ImportQueue;
-use runtime_primitives::traits::{Block as BlockT};
+use runtime_primitives::traits::{Block as BlockT, NumberFor};
+use runtime_primitives::Justification;
 use tokio::{runtime::Runtime, timer::Interval};
 
 /// Type that represents fetch completion future.
@@ -43,6 +44,10 @@ const PROPAGATE_TIMEOUT: Duration = Duration::from_millis(5000);
 pub trait SyncProvider<B: BlockT>: Send + Sync {
 	/// Get sync status
 	fn status(&self) -> ProtocolStatus<B>;
+	/// Get node health summary
+	fn health(&self) -> Health;
+	/// Get the number of connected peers, without building the whole status
+	fn peer_count(&self) -> usize;
 }
 
 /// Minimum Requirements for a Hash within Networking
@@ -53,10 +58,17 @@ impl<T> ExHashT for T where T: ::std::hash::Hash + Eq + ::std::fmt::Debug + Clon
 pub trait TransactionPool<H: ExHashT, B: BlockT>: Send + Sync {
 	/// Get transactions from the pool that are ready to be propagated.
 	fn transactions(&self) -> Vec<(H, B::Extrinsic)>;
-	/// Import a transaction into the pool.
-	fn import(&self, transaction: &B::Extrinsic) -> Option<H>;
+	/// Import a transaction into the pool. `reputation` is the reputation of the peer the
+	/// transaction was received from: the number of its transactions that were accepted minus
+	/// the number of rejected ones. A full pool may use it to decide which transactions to keep.
+	fn import(&self, reputation: i32, transaction: &B::Extrinsic) -> Option<H>;
 	/// Notify the pool about transactions broadcast.
 	fn on_broadcasted(&self, propagations: HashMap<H, Vec<String>>);
+	/// Whether the transaction is still ready to be propagated. Checked right before sending,
+	/// as transactions returned by `transactions` may have been invalidated by a reorg since.
+	fn is_ready(&self, _hash: &H) -> bool {
+		true
+	}
 }
 
 /// Service able to execute closure in the network context.
@@ -86,15 +98,19 @@ impl<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT> Service<B, S,
 		protocol_id: ProtocolId,
 		import_queue: Arc<I>,
 	) -> Result<Arc<Service<B, S, H>>, Error> {
-		let handler = Arc::new(Protocol::new(
+		let handler = Protocol::new(
 			params.config,
 			params.chain,
 			import_queue.clone(),
 			params.on_demand,
 			params.transaction_pool,
 			params.specialization,
-		)?);
-		let versions = [(protocol::CURRENT_VERSION as u8)];
+		)?;
+		let handler = Arc::new(match params.serving_chain {
+			Some(serving_chain) => handler.with_serving_chain(serving_chain),
+			None => handler,
+		});
+		let versions: Vec<u8> = (protocol::MIN_VERSION..protocol::CURRENT_VERSION + 1).map(|v| v as u8).collect();
 		let registered = RegisteredProtocol::new(protocol_id, &versions[..]);
 		let (thread, network) = start_thread(params.network_config, handler.clone(), registered)?;
 
@@ -126,6 +142,11 @@ impl<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT> Service<B, S,
 		self.handler.propagate_extrinsics(&mut NetSyncIo::new(&self.network, self.protocol_id));
 	}
 
+	/// Whether the transaction has recently been sent to at least one peer.
+	pub fn is_transaction_propagated(&self, hash: H) -> bool {
+		self.handler.is_extrinsic_propagated(&hash)
+	}
+
 	/// Send a consensus message through the gossip
 	pub fn gossip_consensus_message(&self, topic: B::Hash, message: Vec<u8>) {
 		self.handler.gossip_consensus_message(
@@ -133,6 +154,37 @@ impl<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT> Service<B, S,
 			topic,
 			message)
 	}
+	/// Import a justification received apart from its block, from the given peer if any.
+	/// Returns whether it has been accepted.
+	pub fn import_justification(&self, who: Option<PeerId>, hash: B::Hash, justification: Justification) -> bool {
+		self.handler.import_justification(&mut NetSyncIo::new(&self.network, self.protocol_id), who, hash, justification)
+	}
+
+	/// Download a block needed by consensus ahead of the blocks requested by sync.
+	pub fn request_priority_block(&self, hash: B::Hash, number: NumberFor<B>) {
+		self.handler.request_priority_block(&mut NetSyncIo::new(&self.network, self.protocol_id), hash, number)
+	}
+
+	/// Refuse connections from the given node for the given duration.
+	pub fn ban_node(&self, peer_id: PeerId, duration: Duration) {
+		self.handler.ban_node(&mut NetSyncIo::new(&self.network, self.protocol_id), peer_id, duration)
+	}
+
+	/// Returns the banned nodes, with the remaining time of their ban.
+	pub fn banned_nodes(&self) -> Vec<(PeerId, Duration)> {
+		self.handler.banned_nodes()
+	}
+
+	/// Returns the connected peers which know the given block. Meant for diagnostics.
+	pub fn peers_informed_of_block(&self, hash: &B::Hash) -> Vec<PeerId> {
+		self.handler.peers_informed_of_block(hash)
+	}
+
+	/// Disconnect all peers, keeping the sync state. The network connects to new peers.
+	pub fn disconnect_all(&self) {
+		self.handler.disconnect_all(&mut NetSyncIo::new(&self.network, self.protocol_id))
+	}
+
 	/// Execute a closure with the chain-specific network specialization.
 	pub fn with_spec<F, U>(&self, f: F) -> U
 		where F: FnOnce(&mut S, &mut Context<B>) -> U
@@ -140,6 +192,11 @@ impl<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT> Service<B, S,
 		self.handler.with_spec(&mut NetSyncIo::new(&self.network, self.protocol_id), f)
 	}
 
+	/// Schedule an update of the runtime-tunable part of the protocol config.
+	pub fn update_config(&self, config: TunableProtocolConfig) {
+		self.handler.update_config(config)
+	}
+
 	/// access the underlying consensus gossip handler
 	pub fn consensus_gossip<'a>(&'a self) -> &'a RwLock<ConsensusGossip<B>> {
 		self.handler.consensus_gossip()
@@ -175,6 +232,16 @@ impl<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT> SyncProvider<
 	fn status(&self) -> ProtocolStatus<B> {
 		self.handler.status()
 	}
+
+	/// Get node health summary
+	fn health(&self) -> Health {
+		self.handler.health()
+	}
+
+	/// Get the number of connected peers
+	fn peer_count(&self) -> usize {
+		self.handler.peer_count()
+	}
 }
 
 /// Trait for managing network
@@ -202,7 +269,7 @@ impl<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT> ManageNetwork
 		let disconnected = self.network.lock().deny_unreserved_peers();
 		let mut net_sync = NetSyncIo::new(&self.network, self.protocol_id);
 		for node_index in disconnected {
-			self.handler.on_peer_disconnected(&mut net_sync, node_index)
+			self.handler.on_peer_disconnected(&mut net_sync, node_index);
 		}
 	}
 
@@ -212,7 +279,7 @@ impl<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT> ManageNetwork
 		let disconnected = self.network.lock().remove_reserved_peer(peer);
 		if let Some(node_index) = disconnected {
 			let mut net_sync = NetSyncIo::new(&self.network, self.protocol_id);
-			self.handler.on_peer_disconnected(&mut net_sync, node_index)
+			self.handler.on_peer_disconnected(&mut net_sync, node_index);
 		}
 	}
 
@@ -338,14 +405,16 @@ fn run_thread<B: BlockT + 'static, S: NetworkSpecialization<B>, H: ExHashT>(
 				}
 			}
 			NetworkServiceEvent::OpenedCustomProtocol { node_index, version, .. } => {
-				debug_assert_eq!(version, protocol::CURRENT_VERSION as u8);
+				debug_assert!(version >= protocol::MIN_VERSION as u8 && version <= protocol::CURRENT_VERSION as u8);
 				protocol.on_peer_connected(&mut net_sync, node_index);
 			}
 			NetworkServiceEvent::ClosedCustomProtocol { node_index, .. } => {
 				protocol.on_peer_disconnected(&mut net_sync, node_index);
 			}
 			NetworkServiceEvent::CustomMessage { node_index, data, .. } => {
-				protocol.handle_packet(&mut net_sync, node_index, &data);
+				if let Err(error) = protocol.handle_packet(&mut net_sync, node_index, &data) {
+					debug!(target: "sync", "Error handling packet from {}: {}", node_index, error);
+				}
 			}
 		};
 
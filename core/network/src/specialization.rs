@@ -17,8 +17,11 @@
 //! Specializations of the substrate network protocol to allow more complex forms of communication.
 
 use ::NodeIndex;
+use codec::{Encode, Decode};
+use network_libp2p::Severity;
 use runtime_primitives::traits::Block as BlockT;
-use protocol::Context;
+use message::generic::Message as GenericMessage;
+use protocol::{Context, PeerInfo};
 
 /// A specialization of the substrate network protocol. Handles events and sends messages.
 pub trait NetworkSpecialization<B: BlockT>: Send + Sync + 'static {
@@ -28,6 +31,10 @@ pub trait NetworkSpecialization<B: BlockT>: Send + Sync + 'static {
 	/// Called when a peer successfully handshakes.
 	fn on_connect(&mut self, ctx: &mut Context<B>, who: NodeIndex, status: ::message::Status<B>);
 
+	/// Called when a peer on another chain handshakes and is routed to the specialization,
+	/// see `ProtocolConfig::genesis_mismatch_handler`.
+	fn on_foreign_connect(&mut self, _ctx: &mut Context<B>, _who: NodeIndex, _status: ::message::Status<B>) { }
+
 	/// Called when a peer is disconnected. If the peer ID is unknown, it should be ignored.
 	fn on_disconnect(&mut self, ctx: &mut Context<B>, who: NodeIndex);
 
@@ -44,3 +51,152 @@ pub trait NetworkSpecialization<B: BlockT>: Send + Sync + 'static {
 	/// Not guaranteed to be called for every block, but will be most of the after major sync.
 	fn on_block_imported(&mut self, _ctx: &mut Context<B>, _hash: B::Hash, _header: &B::Header) { }
 }
+
+/// Identifier of a sub-protocol of a `MultiplexedSpecialization`, sent as the first byte of the
+/// `ChainSpecific` messages of the sub-protocol.
+pub type SubProtocolId = u8;
+
+/// Specialization running several sub-protocols side by side, each handled by its own
+/// specialization. `ChainSpecific` messages are routed by sub-protocol id, and the statuses of
+/// all the sub-protocols are exchanged in the handshake.
+pub struct MultiplexedSpecialization<B: BlockT> {
+	specializations: Vec<(SubProtocolId, Box<NetworkSpecialization<B>>)>,
+}
+
+impl<B: BlockT> MultiplexedSpecialization<B> {
+	/// Create a specialization without sub-protocols.
+	pub fn new() -> Self {
+		MultiplexedSpecialization { specializations: Vec::new() }
+	}
+
+	/// Register the specialization handling the given sub-protocol.
+	pub fn with<S: NetworkSpecialization<B>>(mut self, id: SubProtocolId, specialization: S) -> Self {
+		assert!(self.specializations.iter().all(|&(i, _)| i != id), "Sub-protocol {} is registered twice", id);
+		self.specializations.push((id, Box::new(specialization)));
+		self
+	}
+
+	/// Split a handshake status into the statuses of the sub-protocols.
+	fn sub_statuses(status: &::message::Status<B>, specializations: &[(SubProtocolId, Box<NetworkSpecialization<B>>)])
+		-> Vec<::message::Status<B>>
+	{
+		let statuses: Vec<(SubProtocolId, Vec<u8>)> = Decode::decode(&mut &status.chain_status[..]).unwrap_or_default();
+		specializations.iter().map(|&(id, _)| {
+			let mut status = status.clone();
+			status.chain_status = statuses.iter().find(|&&(i, _)| i == id).map(|&(_, ref s)| s.clone()).unwrap_or_default();
+			status
+		}).collect()
+	}
+}
+
+impl<B: BlockT> NetworkSpecialization<B> for MultiplexedSpecialization<B> {
+	fn status(&self) -> Vec<u8> {
+		self.specializations.iter()
+			.map(|&(id, ref specialization)| (id, specialization.status()))
+			.collect::<Vec<_>>()
+			.encode()
+	}
+
+	fn on_connect(&mut self, ctx: &mut Context<B>, who: NodeIndex, status: ::message::Status<B>) {
+		let statuses = Self::sub_statuses(&status, &self.specializations);
+		for (&mut (id, ref mut specialization), status) in self.specializations.iter_mut().zip(statuses) {
+			specialization.on_connect(&mut SubProtocolContext { inner: &mut *ctx, id }, who, status);
+		}
+	}
+
+	fn on_foreign_connect(&mut self, ctx: &mut Context<B>, who: NodeIndex, status: ::message::Status<B>) {
+		let statuses = Self::sub_statuses(&status, &self.specializations);
+		for (&mut (id, ref mut specialization), status) in self.specializations.iter_mut().zip(statuses) {
+			specialization.on_foreign_connect(&mut SubProtocolContext { inner: &mut *ctx, id }, who, status);
+		}
+	}
+
+	fn on_disconnect(&mut self, ctx: &mut Context<B>, who: NodeIndex) {
+		for &mut (id, ref mut specialization) in self.specializations.iter_mut() {
+			specialization.on_disconnect(&mut SubProtocolContext { inner: &mut *ctx, id }, who);
+		}
+	}
+
+	fn on_message(&mut self, ctx: &mut Context<B>, who: NodeIndex, message: &mut Option<::message::Message<B>>) {
+		let data = match message.take() {
+			Some(GenericMessage::ChainSpecific(data)) => data,
+			other => {
+				// not addressed to a sub-protocol, offered to each until one takes it
+				*message = other;
+				for &mut (id, ref mut specialization) in self.specializations.iter_mut() {
+					if message.is_none() {
+						break;
+					}
+					specialization.on_message(&mut SubProtocolContext { inner: &mut *ctx, id }, who, message);
+				}
+				return;
+			}
+		};
+		let (id, payload) = match data.split_first() {
+			Some((id, payload)) => (*id, payload.to_vec()),
+			None => {
+				ctx.report_peer(who, Severity::Bad("Peer sent a chain-specific message without a sub-protocol id"));
+				return;
+			}
+		};
+		match self.specializations.iter_mut().find(|&&mut (i, _)| i == id) {
+			Some(&mut (_, ref mut specialization)) => specialization.on_message(
+				&mut SubProtocolContext { inner: &mut *ctx, id },
+				who,
+				&mut Some(GenericMessage::ChainSpecific(payload)),
+			),
+			None => trace!(target: "sync", "Ignoring message for unknown sub-protocol {} from {}", id, who),
+		}
+	}
+
+	fn on_abort(&mut self) {
+		for &mut (_, ref mut specialization) in self.specializations.iter_mut() {
+			specialization.on_abort();
+		}
+	}
+
+	fn maintain_peers(&mut self, ctx: &mut Context<B>) {
+		for &mut (id, ref mut specialization) in self.specializations.iter_mut() {
+			specialization.maintain_peers(&mut SubProtocolContext { inner: &mut *ctx, id });
+		}
+	}
+
+	fn on_block_imported(&mut self, ctx: &mut Context<B>, hash: B::Hash, header: &B::Header) {
+		for &mut (id, ref mut specialization) in self.specializations.iter_mut() {
+			specialization.on_block_imported(&mut SubProtocolContext { inner: &mut *ctx, id }, hash, header);
+		}
+	}
+}
+
+/// Context given to a sub-protocol, prefixing its `ChainSpecific` messages with its id.
+struct SubProtocolContext<'a, B: BlockT + 'a> {
+	inner: &'a mut Context<B>,
+	id: SubProtocolId,
+}
+
+impl<'a, B: BlockT + 'a> Context<B> for SubProtocolContext<'a, B> {
+	fn client(&self) -> &::chain::Client<B> {
+		self.inner.client()
+	}
+
+	fn report_peer(&mut self, who: NodeIndex, reason: Severity) {
+		self.inner.report_peer(who, reason)
+	}
+
+	fn peer_info(&self, peer: NodeIndex) -> Option<PeerInfo<B>> {
+		self.inner.peer_info(peer)
+	}
+
+	fn send_message(&mut self, who: NodeIndex, message: ::message::Message<B>) {
+		let message = match message {
+			GenericMessage::ChainSpecific(data) => {
+				let mut prefixed = Vec::with_capacity(data.len() + 1);
+				prefixed.push(self.id);
+				prefixed.extend(data);
+				GenericMessage::ChainSpecific(prefixed)
+			},
+			other => other,
+		};
+		self.inner.send_message(who, message)
+	}
+}
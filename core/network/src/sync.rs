@@ -14,8 +14,10 @@
 // You should have received a copy of the GNU General Public License
 // along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use protocol::Context;
 use network_libp2p::{Severity, NodeIndex};
 use client::{BlockStatus, ClientInfo};
@@ -25,13 +27,21 @@ use blocks::{self, BlockCollection};
 use runtime_primitives::traits::{Block as BlockT, Header as HeaderT, As, NumberFor};
 use runtime_primitives::generic::BlockId;
 use message::{self, generic::Message as GenericMessage};
-use config::Roles;
+use config::{ProtocolConfig, Roles, SyncTieBreak};
 use import_queue::ImportQueue;
 
 // Maximum blocks to request in a single packet.
 const MAX_BLOCKS_TO_REQUEST: usize = 128;
+// Maximum headers to request in a single packet in headers-first mode.
+const MAX_HEADERS_TO_REQUEST: usize = 512;
 // Maximum blocks to store in the import queue.
 const MAX_IMPORTING_BLOCKS: usize = 2048;
+// Maximum packets waiting to be sent to a peer before we stop requesting blocks from it.
+const MAX_PEER_SEND_QUEUE_LEN: usize = 64;
+// Consecutive priority requests to a peer before a bulk sync request is let through.
+const MAX_PRIORITY_STREAK: u32 = 4;
+// Consecutive responses made of blocks we already have before a peer is dropped.
+const MAX_WASTED_RESPONSES: u32 = 3;
 
 struct PeerSync<B: BlockT> {
 	pub common_hash: B::Hash,
@@ -39,6 +49,14 @@ struct PeerSync<B: BlockT> {
 	pub best_hash: B::Hash,
 	pub best_number: NumberFor<B>,
 	pub state: PeerSyncState<B>,
+	/// Digest of the recent blocks of the peer, if it sent one.
+	pub known_blocks: Option<message::BlockDigest>,
+	/// Order in which we heard of the best block of the peer, relative to other peers.
+	pub best_seen_at: u64,
+	/// Number of priority requests sent to the peer since the last bulk sync request.
+	pub priority_streak: u32,
+	/// Number of consecutive responses of the peer which only had blocks we already have.
+	pub wasted_responses: u32,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -47,6 +65,7 @@ enum PeerSyncState<B: BlockT> {
 	Available,
 	DownloadingNew(NumberFor<B>),
 	DownloadingStale(B::Hash),
+	DownloadingHeaders(NumberFor<B>),
 }
 
 /// Relay chain sync strategy.
@@ -58,6 +77,25 @@ pub struct ChainSync<B: BlockT> {
 	best_queued_hash: B::Hash,
 	required_block_attributes: message::BlockAttributes,
 	import_queue: Arc<ImportQueue<B>>,
+	/// Never request blocks; see `ProtocolConfig::observer_mode`.
+	observer_mode: bool,
+	/// See `ProtocolConfig::sync_tie_break`.
+	tie_break: SyncTieBreak,
+	/// Number of best blocks reported by peers so far.
+	best_blocks_seen: u64,
+	/// Download headers before bodies; see `ProtocolConfig::headers_first_sync`.
+	headers_first: bool,
+	/// Headers being downloaded in headers-first mode.
+	headers: BlockCollection<B>,
+	/// Hashes of the downloaded headers above our best queued block, linked to each other.
+	/// Bodies are only requested for blocks in the skeleton.
+	skeleton: BTreeMap<NumberFor<B>, B::Hash>,
+	/// See `ProtocolConfig::sync_stall_timeout`.
+	stall_timeout: Option<Duration>,
+	/// Last time our best queued block advanced or we were not behind our peers.
+	last_progress: Instant,
+	/// Blocks requested by consensus, downloaded ahead of bulk sync.
+	priority_blocks: VecDeque<(B::Hash, NumberFor<B>)>,
 }
 
 /// Reported sync state.
@@ -66,7 +104,9 @@ pub enum SyncState {
 	/// Initial sync is complete, keep-up sync is active.
 	Idle,
 	/// Actively catching up with the chain.
-	Downloading
+	Downloading,
+	/// Behind the chain, but our best block has not advanced for a while.
+	Stalled,
 }
 
 /// Syncing status and statistics
@@ -84,17 +124,21 @@ impl<B: BlockT> Status<B> {
 	pub fn is_major_syncing(&self) -> bool {
 		match self.state {
 			SyncState::Idle => false,
-			SyncState::Downloading => true,
+			SyncState::Downloading | SyncState::Stalled => true,
 		}
 	}
 }
 
 impl<B: BlockT> ChainSync<B> {
 	/// Create a new instance.
-	pub(crate) fn new(role: Roles, info: &ClientInfo<B>, import_queue: Arc<ImportQueue<B>>) -> Self {
+	pub(crate) fn new(config: &ProtocolConfig, info: &ClientInfo<B>, import_queue: Arc<ImportQueue<B>>) -> Self {
 		let mut required_block_attributes = message::BlockAttributes::HEADER | message::BlockAttributes::JUSTIFICATION;
-		if role.intersects(Roles::FULL | Roles::AUTHORITY) {
-			required_block_attributes |= message::BlockAttributes::BODY;
+		if config.roles.intersects(Roles::FULL | Roles::AUTHORITY) {
+			required_block_attributes |= if config.compact_block_bodies {
+				message::BlockAttributes::COMPACT_BODY
+			} else {
+				message::BlockAttributes::BODY
+			};
 		}
 
 		ChainSync {
@@ -105,9 +149,78 @@ impl<B: BlockT> ChainSync<B> {
 			best_queued_number: info.best_queued_number.unwrap_or(info.chain.best_number),
 			required_block_attributes,
 			import_queue,
+			observer_mode: config.observer_mode,
+			tie_break: config.sync_tie_break,
+			best_blocks_seen: 0,
+			headers_first: config.headers_first_sync,
+			headers: BlockCollection::new(),
+			skeleton: BTreeMap::new(),
+			stall_timeout: config.sync_stall_timeout,
+			last_progress: Instant::now(),
+			priority_blocks: VecDeque::new(),
+		}
+	}
+
+	/// Whether peers report a best block well above our best queued block.
+	fn is_behind(&self) -> bool {
+		match self.best_seen_block() {
+			Some(n) => n > self.best_queued_number && n - self.best_queued_number > As::sa(5),
+			None => false,
+		}
+	}
+
+	/// Restart the stall timer if sync is making progress, that is our best queued block
+	/// advanced or we are not behind.
+	fn note_progress(&mut self, advanced: bool) {
+		if advanced || !self.is_behind() {
+			self.last_progress = Instant::now();
 		}
 	}
 
+	/// Highest block of the skeleton, or our best queued block if it is higher.
+	fn skeleton_best(&self) -> NumberFor<B> {
+		self.skeleton.keys().next_back().map_or(self.best_queued_number, |n| cmp::max(*n, self.best_queued_number))
+	}
+
+	/// Add downloaded headers to the skeleton. Fails with the number of the first header which
+	/// doesn't link to its parent in the skeleton, or to our best queued block for the first one.
+	fn extend_skeleton(
+		skeleton: &mut BTreeMap<NumberFor<B>, B::Hash>,
+		best_queued: (NumberFor<B>, B::Hash),
+		headers: Vec<blocks::BlockData<B>>,
+	) -> Result<(), NumberFor<B>> {
+		for data in headers {
+			let header = match data.block.header {
+				Some(header) => header,
+				None => continue,
+			};
+			let number = *header.number();
+			let linked = number == As::sa(0) || {
+				let parent_number = number - As::sa(1);
+				let parent = if parent_number == best_queued.0 { Some(&best_queued.1) } else { skeleton.get(&parent_number) };
+				parent.map_or(false, |parent| parent == header.parent_hash())
+			};
+			if !linked {
+				return Err(number);
+			}
+			skeleton.insert(number, header.hash());
+		}
+		Ok(())
+	}
+
+	/// Number of the first downloaded block, starting at `start`, whose hash differs from the
+	/// header of the skeleton at the same height.
+	fn off_skeleton(skeleton: &BTreeMap<NumberFor<B>, B::Hash>, start: NumberFor<B>, blocks: &[message::BlockData<B>]) -> Option<NumberFor<B>> {
+		blocks.iter().enumerate().filter_map(|(i, block)| {
+			let number = block.header.as_ref().map_or(start + As::sa(i as u64), |h| *h.number());
+			let hash = block.header.as_ref().map_or(block.hash, |h| h.hash());
+			match skeleton.get(&number) {
+				Some(expected) if *expected != hash => Some(number),
+				_ => None,
+			}
+		}).next()
+	}
+
 	fn best_seen_block(&self) -> Option<NumberFor<B>> {
 		self.peers.values().max_by_key(|p| p.best_number).map(|p| p.best_number)
 	}
@@ -120,9 +233,11 @@ impl<B: BlockT> ChainSync<B> {
 	/// Returns sync status.
 	pub(crate) fn status(&self) -> Status<B> {
 		let best_seen = self.best_seen_block();
-		let state = match &best_seen {
-			&Some(n) if n > self.best_queued_number && n - self.best_queued_number > As::sa(5) => SyncState::Downloading,
-			_ => SyncState::Idle,
+		let stalled = self.stall_timeout.map_or(false, |timeout| self.last_progress.elapsed() >= timeout);
+		let state = match (self.is_behind(), stalled) {
+			(true, true) => SyncState::Stalled,
+			(true, false) => SyncState::Downloading,
+			(false, _) => SyncState::Idle,
 		};
 		Status {
 			state: state,
@@ -132,7 +247,9 @@ impl<B: BlockT> ChainSync<B> {
 
 	/// Handle new connected peer.
 	pub(crate) fn new_peer(&mut self, protocol: &mut Context<B>, who: NodeIndex) {
+		self.note_progress(false);
 		if let Some(info) = protocol.peer_info(who) {
+			let best_seen_at = self.note_best_seen();
 			match (block_status(&*protocol.client(), &*self.import_queue, info.best_hash), info.best_number) {
 				(Err(e), _) => {
 					debug!(target:"sync", "Error reading blockchain: {:?}", e);
@@ -146,7 +263,7 @@ impl<B: BlockT> ChainSync<B> {
 				},
 				(Ok(BlockStatus::Unknown), _) => {
 					let our_best = self.best_queued_number;
-					if our_best > As::sa(0) {
+					if our_best > As::sa(0) && !self.observer_mode {
 						debug!(target:"sync", "New peer with unknown best hash {} ({}), searching for common ancestor.", info.best_hash, info.best_number);
 						self.peers.insert(who, PeerSync {
 							common_hash: self.genesis_hash,
@@ -154,10 +271,14 @@ impl<B: BlockT> ChainSync<B> {
 							best_hash: info.best_hash,
 							best_number: info.best_number,
 							state: PeerSyncState::AncestorSearch(our_best),
+							known_blocks: None,
+							best_seen_at,
+							priority_streak: 0,
+							wasted_responses: 0,
 						});
 						Self::request_ancestry(protocol, who, our_best)
 					} else {
-						// We are at genesis, just start downloading
+						// We are at genesis or never download, no need to search for a common ancestor
 						debug!(target:"sync", "New peer with best hash {} ({}).", info.best_hash, info.best_number);
 						self.peers.insert(who, PeerSync {
 							common_hash: self.genesis_hash,
@@ -165,6 +286,10 @@ impl<B: BlockT> ChainSync<B> {
 							best_hash: info.best_hash,
 							best_number: info.best_number,
 							state: PeerSyncState::Available,
+							known_blocks: None,
+							best_seen_at,
+							priority_streak: 0,
+							wasted_responses: 0,
 						});
 						self.download_new(protocol, who)
 					}
@@ -177,6 +302,10 @@ impl<B: BlockT> ChainSync<B> {
 						best_hash: info.best_hash,
 						best_number: info.best_number,
 						state: PeerSyncState::Available,
+						known_blocks: None,
+						best_seen_at,
+						priority_streak: 0,
+						wasted_responses: 0,
 					});
 				}
 			}
@@ -190,14 +319,52 @@ impl<B: BlockT> ChainSync<B> {
 		_request: message::BlockRequest<B>,
 		response: message::BlockResponse<B>
 	) -> Option<(BlockOrigin, Vec<blocks::BlockData<B>>)> {
+		if response.busy {
+			self.on_busy_response(protocol, who);
+			return None;
+		}
+		let skeleton_best = self.skeleton_best();
+		let wasted = match self.peers.get(&who).map(|p| p.state) {
+			Some(PeerSyncState::DownloadingNew(_)) | Some(PeerSyncState::DownloadingStale(_)) =>
+				!response.blocks.is_empty() && response.blocks.iter().all(|b| self.is_known_block(&*protocol, b.hash.clone())),
+			_ => false,
+		};
+		let mut off_skeleton = false;
 		let new_blocks = if let Some(ref mut peer) = self.peers.get_mut(&who) {
 			match peer.state {
 				PeerSyncState::DownloadingNew(start_block) => {
 					self.blocks.clear_peer_download(who);
 					peer.state = PeerSyncState::Available;
 
-					self.blocks.insert(start_block, response.blocks, who);
-					self.blocks.drain(self.best_queued_number + As::sa(1))
+					// the peer may well be on another valid fork, the blocks are requested again
+					// from the other peers
+					if let Some(number) = Self::off_skeleton(&self.skeleton, start_block, &response.blocks) {
+						trace!(target: "sync", "Block #{} from {} is on another fork than the skeleton", number, who);
+						off_skeleton = true;
+						vec![]
+					} else {
+						self.blocks.insert(start_block, response.blocks, who);
+						self.blocks.drain(self.best_queued_number + As::sa(1))
+					}
+				},
+				PeerSyncState::DownloadingHeaders(start_block) => {
+					self.headers.clear_peer_download(who);
+					peer.state = PeerSyncState::Available;
+
+					self.headers.insert(start_block, response.blocks, who);
+					let headers = self.headers.drain(skeleton_best + As::sa(1));
+					if let Err(number) = Self::extend_skeleton(&mut self.skeleton, (self.best_queued_number, self.best_queued_hash), headers) {
+						trace!(target: "sync", "Header #{} from {} doesn't link to the skeleton", number, who);
+						self.headers.clear();
+						if number == self.best_queued_number + As::sa(1) {
+							// the peer may be on another fork than our best block
+							protocol.report_peer(who, Severity::Useless("Sent headers not linked to our best block"));
+						} else {
+							protocol.report_peer(who, Severity::Bad("Sent headers not linked to their parents"));
+						}
+						return None;
+					}
+					vec![]
 				},
 				PeerSyncState::DownloadingStale(_) => {
 					peer.state = PeerSyncState::Available;
@@ -222,7 +389,7 @@ impl<B: BlockT> ChainSync<B> {
 								},
 								Ok(our_best) if n > As::sa(0) => {
 									trace!(target:"sync", "Ancestry block mismatch for peer {}: theirs: {} ({}), ours: {:?}", who, block.hash, n, our_best);
-									let n = n - As::sa(1);
+									let n = Self::next_ancestor_candidate(&*protocol.client(), peer, n);
 									peer.state = PeerSyncState::AncestorSearch(n);
 									Self::request_ancestry(protocol, who, n);
 									return None;
@@ -250,6 +417,13 @@ impl<B: BlockT> ChainSync<B> {
 		} else {
 			vec![]
 		};
+		if off_skeleton {
+			self.peers.remove(&who);
+			protocol.report_peer(who, Severity::Useless("Sent blocks of another fork than the skeleton"));
+			self.maintain_sync(protocol);
+			return None;
+		}
+		self.note_wasted_response(protocol, who, wasted);
 
 		let best_seen = self.best_seen_block();
 		let is_best = new_blocks.first().and_then(|b| b.block.header.as_ref()).map(|h| best_seen.as_ref().map_or(false, |n| h.number() >= n));
@@ -258,26 +432,137 @@ impl<B: BlockT> ChainSync<B> {
 			.and_then(|b| b.block.header.as_ref().map(|h|(b.block.hash.clone(), *h.number())))
 		{
 			if number > self.best_queued_number {
-				self.best_queued_number = number;
-				self.best_queued_hash = hash;
+				self.note_best_queued(hash, number);
 			}
 		}
 		self.maintain_sync(protocol);
 		Some((origin, new_blocks))
 	}
 
+	/// Whether the block is already imported or being imported.
+	fn is_known_block(&self, protocol: &Context<B>, hash: B::Hash) -> bool {
+		match block_status(&*protocol.client(), &*self.import_queue, hash) {
+			Ok(BlockStatus::InChain) | Ok(BlockStatus::Queued) => true,
+			_ => false,
+		}
+	}
+
+	/// Count the consecutive responses of a peer which only had blocks we already have, and
+	/// drop the peer once it wasted too many of our requests.
+	fn note_wasted_response(&mut self, protocol: &mut Context<B>, who: NodeIndex, wasted: bool) {
+		let wasted_responses = match self.peers.get_mut(&who) {
+			Some(ref mut peer) if wasted => {
+				peer.wasted_responses += 1;
+				peer.wasted_responses
+			},
+			Some(ref mut peer) => {
+				peer.wasted_responses = 0;
+				return;
+			},
+			None => return,
+		};
+		trace!(target: "sync", "Response of {} only had known blocks ({} in a row)", who, wasted_responses);
+		if wasted_responses >= MAX_WASTED_RESPONSES {
+			protocol.report_peer(who, Severity::Useless("Peer keeps answering with blocks we already have"));
+		}
+	}
+
+	/// Peer refused to serve our request because it is too busy. Let other peers download
+	/// the blocks instead.
+	fn on_busy_response(&mut self, protocol: &mut Context<B>, who: NodeIndex) {
+		trace!(target: "sync", "Peer {} is too busy to serve blocks", who);
+		match self.peers.get_mut(&who) {
+			Some(ref mut peer) => match peer.state {
+				PeerSyncState::DownloadingNew(_) | PeerSyncState::DownloadingStale(_) | PeerSyncState::DownloadingHeaders(_) =>
+					peer.state = PeerSyncState::Available,
+				_ => (),
+			},
+			None => return,
+		}
+		self.blocks.clear_peer_download(who);
+		self.headers.clear_peer_download(who);
+		let others: Vec<NodeIndex> = self.peers_by_priority().into_iter().filter(|p| *p != who).collect();
+		for peer in others {
+			self.download_new(protocol, peer);
+		}
+	}
+
+	/// Handle a digest of its recent blocks received from a peer.
+	pub(crate) fn on_block_digest(&mut self, who: NodeIndex, digest: message::BlockDigest) {
+		if let Some(ref mut peer) = self.peers.get_mut(&who) {
+			peer.known_blocks = Some(digest);
+		}
+	}
+
+	/// Next block to ask `peer` for when searching for a common ancestor below block `number`,
+	/// which is not 0. Our blocks which are missing from the digest of the peer are skipped.
+	fn next_ancestor_candidate(client: &::chain::Client<B>, peer: &PeerSync<B>, number: NumberFor<B>) -> NumberFor<B> {
+		let mut candidate = number.as_() - 1;
+		if let Some(ref digest) = peer.known_blocks {
+			let lowest_covered = peer.best_number.as_().saturating_sub(message::BLOCK_DIGEST_DEPTH - 1);
+			while candidate > 0 && candidate >= lowest_covered {
+				match client.block_hash(As::sa(candidate)) {
+					Ok(Some(ref hash)) if !digest.contains(hash.as_ref()) => candidate -= 1,
+					_ => break,
+				}
+			}
+		}
+		As::sa(candidate)
+	}
+
+	fn note_best_seen(&mut self) -> u64 {
+		self.best_blocks_seen += 1;
+		self.best_blocks_seen
+	}
+
+	/// Peers in the order in which they are asked for blocks. Peers with a higher best block
+	/// come first, ties are broken as configured.
+	fn peers_by_priority(&self) -> Vec<NodeIndex> {
+		let mut order: Vec<NodeIndex> = self.peers.keys().cloned().collect();
+		if self.tie_break == SyncTieBreak::Arbitrary {
+			return order;
+		}
+		order.sort_by_key(|who| {
+			let peer = &self.peers[who];
+			let tie = match self.tie_break {
+				SyncTieBreak::Arbitrary => 0,
+				SyncTieBreak::FirstSeen => peer.best_seen_at,
+				SyncTieBreak::Random(seed) => (*who as u64 ^ seed).wrapping_mul(0x9e37_79b9_7f4a_7c15),
+			};
+			(cmp::Reverse(peer.best_number), tie)
+		});
+		order
+	}
+
 	pub fn maintain_sync(&mut self, protocol: &mut Context<B>) {
-		let peers: Vec<NodeIndex> = self.peers.keys().map(|p| *p).collect();
+		let peers = self.peers_by_priority();
 		for peer in peers {
 			self.download_new(protocol, peer);
 		}
 	}
 
+	/// Note a new best queued block. The skeleton is dropped up to it, or entirely if the
+	/// block is on another fork, so that the skeleton is downloaded again from it.
+	fn note_best_queued(&mut self, hash: B::Hash, number: NumberFor<B>) {
+		self.best_queued_number = number;
+		self.best_queued_hash = hash;
+		let on_skeleton = self.skeleton.get(&number).cloned();
+		match on_skeleton {
+			Some(skeleton_hash) if skeleton_hash != hash => {
+				trace!(target: "sync", "Best block #{} ({}) is not on the skeleton, rebuilding it", number, hash);
+				self.skeleton.clear();
+				self.headers.clear();
+			},
+			_ => self.skeleton = self.skeleton.split_off(&(number + As::sa(1))),
+		}
+		self.note_progress(true);
+	}
+
 	pub fn block_imported(&mut self, hash: &B::Hash, number: NumberFor<B>) {
 		if number > self.best_queued_number {
-			self.best_queued_number = number;
-			self.best_queued_hash = *hash;
+			self.note_best_queued(*hash, number);
 		}
+		self.priority_blocks.retain(|&(h, _)| h != *hash);
 		// Update common blocks
 		for (_, peer) in self.peers.iter_mut() {
 			trace!(target: "sync", "Updating peer info ours={}, theirs={}", number, peer.best_number);
@@ -294,11 +579,18 @@ impl<B: BlockT> ChainSync<B> {
 	}
 
 	pub(crate) fn on_block_announce(&mut self, protocol: &mut Context<B>, who: NodeIndex, hash: B::Hash, header: &B::Header) {
+		self.note_progress(false);
 		let number = *header.number();
+		let best_seen_at = self.note_best_seen();
 		if let Some(ref mut peer) = self.peers.get_mut(&who) {
+			if number > peer.best_number && hash == peer.best_hash {
+				protocol.report_peer(who, Severity::Bad("Peer announced a higher best block with the same hash"));
+				return;
+			}
 			if number > peer.best_number {
 				peer.best_number = number;
 				peer.best_hash = hash;
+				peer.best_seen_at = best_seen_at;
 			}
 			if number <= self.best_queued_number && number > peer.common_number {
 				peer.common_number = number
@@ -325,6 +617,25 @@ impl<B: BlockT> ChainSync<B> {
 		}
 	}
 
+	/// Handle several block announcements, in order.
+	pub(crate) fn on_block_announces(&mut self, protocol: &mut Context<B>, announces: Vec<(NodeIndex, B::Hash, B::Header)>) {
+		for (who, hash, header) in announces {
+			self.on_block_announce(protocol, who, hash, &header);
+		}
+	}
+
+	/// Request a specific block ahead of bulk sync, e.g. a block under vote. The block is
+	/// requested from the first available peer with a best block at least as high.
+	pub(crate) fn request_priority_block(&mut self, protocol: &mut Context<B>, hash: B::Hash, number: NumberFor<B>) {
+		if self.is_known_or_already_downloading(protocol, &hash) || self.priority_blocks.iter().any(|&(h, _)| h == hash) {
+			trace!(target: "sync", "Ignoring priority request for known block {} ({})", hash, number);
+			return;
+		}
+		trace!(target: "sync", "Queueing priority request for block {} ({})", hash, number);
+		self.priority_blocks.push_back((hash, number));
+		self.maintain_sync(protocol);
+	}
+
 	fn is_known_or_already_downloading(&self, protocol: &mut Context<B>, hash: &B::Hash) -> bool {
 		self.peers.iter().any(|(_, p)| p.state == PeerSyncState::DownloadingStale(*hash))
 			|| block_status(&*protocol.client(), &*self.import_queue, *hash).ok().map_or(false, |s| s != BlockStatus::Unknown)
@@ -332,6 +643,7 @@ impl<B: BlockT> ChainSync<B> {
 
 	pub(crate) fn peer_disconnected(&mut self, protocol: &mut Context<B>, who: NodeIndex) {
 		self.blocks.clear_peer_download(who);
+		self.headers.clear_peer_download(who);
 		self.peers.remove(&who);
 		self.maintain_sync(protocol);
 	}
@@ -339,6 +651,8 @@ impl<B: BlockT> ChainSync<B> {
 	pub(crate) fn restart(&mut self, protocol: &mut Context<B>) {
 		self.import_queue.clear();
 		self.blocks.clear();
+		self.headers.clear();
+		self.skeleton.clear();
 		let ids: Vec<NodeIndex> = self.peers.keys().map(|p| *p).collect();
 		for id in ids {
 			self.new_peer(protocol, id);
@@ -354,15 +668,22 @@ impl<B: BlockT> ChainSync<B> {
 				self.best_queued_number = As::sa(0);
 			}
 		}
+		self.last_progress = Instant::now();
 	}
 
 	pub(crate) fn clear(&mut self) {
 		self.blocks.clear();
+		self.headers.clear();
+		self.skeleton.clear();
+		self.priority_blocks.clear();
 		self.peers.clear();
 	}
 
 	// Download old block.
 	fn download_stale(&mut self, protocol: &mut Context<B>, who: NodeIndex, hash: &B::Hash) {
+		if self.observer_mode {
+			return;
+		}
 		if let Some(ref mut peer) = self.peers.get_mut(&who) {
 			match peer.state {
 				PeerSyncState::Available => {
@@ -384,10 +705,19 @@ impl<B: BlockT> ChainSync<B> {
 
 	// Issue a request for a peer to download new blocks, if any are available
 	fn download_new(&mut self, protocol: &mut Context<B>, who: NodeIndex) {
+		if self.observer_mode {
+			return;
+		}
+		// don't pile requests on a stalled connection
+		if protocol.peer_info(who).map_or(false, |info| info.send_queue_len > MAX_PEER_SEND_QUEUE_LEN) {
+			trace!(target: "sync", "Send queue of {} is full, not requesting blocks.", who);
+			return;
+		}
+		let skeleton_best = self.skeleton_best();
 		if let Some(ref mut peer) = self.peers.get_mut(&who) {
 			let import_status = self.import_queue.status();
 			// when there are too many blocks in the queue => do not try to download new blocks
-			if import_status.importing_count > MAX_IMPORTING_BLOCKS {
+			if import_status.importing_count >= MAX_IMPORTING_BLOCKS {
 				trace!(target: "sync", "Too many blocks in the queue.");
 				return;
 			}
@@ -397,7 +727,53 @@ impl<B: BlockT> ChainSync<B> {
 			trace!(target: "sync", "Considering new block download from {}, common block is {}, best is {:?}", who, common_number, peer.best_number);
 			match peer.state {
 				PeerSyncState::Available => {
-					if let Some(range) = self.blocks.needed_blocks(who, MAX_BLOCKS_TO_REQUEST, peer.best_number, common_number) {
+					// consensus requests go first, unless they kept the peer from bulk sync for too long
+					let priority = if peer.priority_streak < MAX_PRIORITY_STREAK {
+						self.priority_blocks.iter().position(|&(_, n)| n <= peer.best_number)
+					} else {
+						None
+					};
+					if let Some(index) = priority {
+						let (hash, number) = self.priority_blocks.remove(index).expect("index returned by position; qed");
+						trace!(target: "sync", "Requesting priority block {} ({}) from {}", hash, number, who);
+						let request = message::generic::BlockRequest {
+							id: 0,
+							fields: self.required_block_attributes.clone(),
+							from: message::FromBlock::Hash(hash),
+							to: None,
+							direction: message::Direction::Ascending,
+							max: Some(1),
+						};
+						peer.priority_streak += 1;
+						peer.state = PeerSyncState::DownloadingStale(hash);
+						protocol.send_message(who, GenericMessage::BlockRequest(request));
+						return;
+					}
+					peer.priority_streak = 0;
+					if self.headers_first && skeleton_best < peer.best_number {
+						let common_header = cmp::max(common_number, skeleton_best);
+						if let Some(range) = self.headers.needed_blocks(who, MAX_HEADERS_TO_REQUEST, peer.best_number, common_header) {
+							trace!(target: "sync", "Requesting headers from {}, ({} to {})", who, range.start, range.end);
+							let request = message::generic::BlockRequest {
+								id: 0,
+								fields: message::BlockAttributes::HEADER,
+								from: message::FromBlock::Number(range.start),
+								to: None,
+								direction: message::Direction::Ascending,
+								max: Some((range.end - range.start).as_() as u32),
+							};
+							peer.state = PeerSyncState::DownloadingHeaders(range.start);
+							protocol.send_message(who, GenericMessage::BlockRequest(request));
+							return;
+						}
+					}
+					// in headers-first mode, bodies are only downloaded for the skeleton
+					let best_number = if self.headers_first {
+						cmp::min(peer.best_number, skeleton_best)
+					} else {
+						peer.best_number
+					};
+					if let Some(range) = self.blocks.needed_blocks(who, MAX_BLOCKS_TO_REQUEST, best_number, common_number) {
 						trace!(target: "sync", "Requesting blocks from {}, ({} to {})", who, range.start, range.end);
 						let request = message::generic::BlockRequest {
 							id: 0,
@@ -16,6 +16,8 @@
 
 #![allow(missing_docs)]
 
+#[cfg(test)]
+mod protocol;
 #[cfg(test)]
 mod sync;
 
@@ -33,7 +35,8 @@ use config::ProtocolConfig;
 use service::TransactionPool;
 use network_libp2p::{NodeIndex, PeerId, Severity};
 use keyring::Keyring;
-use codec::Encode;
+use codec::{Encode, Decode};
+use message::Message;
 use import_queue::{SyncImportQueue, PassThroughVerifier, Verifier};
 use consensus::BlockOrigin;
 use specialization::NetworkSpecialization;
@@ -81,6 +84,10 @@ impl NetworkSpecialization<Block> for DummySpecialization {
 pub struct TestIo<'p> {
 	queue: &'p RwLock<VecDeque<TestPacket>>,
 	pub to_disconnect: HashSet<NodeIndex>,
+	pub peer_ids: HashMap<NodeIndex, PeerId>,
+	pub send_queue_lens: HashMap<NodeIndex, usize>,
+	/// Peers to which sending packets fails.
+	pub failing_sends: HashSet<NodeIndex>,
 	packets: Vec<TestPacket>,
 	_sender: Option<NodeIndex>,
 }
@@ -91,6 +98,9 @@ impl<'p> TestIo<'p> where {
 			queue: queue,
 			_sender: sender,
 			to_disconnect: HashSet::new(),
+			peer_ids: HashMap::new(),
+			send_queue_lens: HashMap::new(),
+			failing_sends: HashSet::new(),
 			packets: Vec::new(),
 		}
 	}
@@ -107,26 +117,34 @@ impl<'p> SyncIo for TestIo<'p> {
 		self.to_disconnect.insert(who);
 	}
 
-	fn send(&mut self, who: NodeIndex, data: Vec<u8>) {
+	fn send(&mut self, who: NodeIndex, data: Vec<u8>) -> bool {
+		if self.failing_sends.contains(&who) {
+			return false;
+		}
 		self.packets.push(TestPacket {
 			data: data,
 			recipient: who,
 		});
+		true
 	}
 
 	fn peer_debug_info(&self, _who: NodeIndex) -> String {
 		"unknown".to_string()
 	}
 
-	fn peer_id(&self, _peer_id: NodeIndex) -> Option<PeerId> {
-		None
+	fn peer_id(&self, who: NodeIndex) -> Option<PeerId> {
+		self.peer_ids.get(&who).cloned()
+	}
+
+	fn send_queue_len(&self, who: NodeIndex) -> usize {
+		self.send_queue_lens.get(&who).cloned().unwrap_or(0)
 	}
 }
 
 /// Mocked subprotocol packet
 pub struct TestPacket {
-	data: Vec<u8>,
-	recipient: NodeIndex,
+	pub data: Vec<u8>,
+	pub recipient: NodeIndex,
 }
 
 pub type PeersClient = client::Client<test_client::Backend, test_client::Executor, Block, test_client::runtime::ClientWithApi>;
@@ -184,11 +202,23 @@ impl<V: 'static + Verifier<Block>, D> Peer<V, D> {
 	/// Receive a message from another peer. Return a set of peers to disconnect.
 	fn receive_message(&self, from: NodeIndex, msg: TestPacket) -> HashSet<NodeIndex> {
 		let mut io = TestIo::new(&self.queue, Some(from));
-		self.sync.handle_packet(&mut io, from, &msg.data);
+		self.sync.handle_packet(&mut io, from, &msg.data).ok();
 		self.flush();
 		io.to_disconnect.clone()
 	}
 
+	/// Receive a protocol message from another peer. Return a set of peers to disconnect.
+	fn receive(&self, from: NodeIndex, message: Message<Block>) -> HashSet<NodeIndex> {
+		self.receive_message(from, TestPacket { data: message.encode(), recipient: from })
+	}
+
+	/// Drain the messages this peer has queued for sending.
+	fn sent_messages(&self) -> Vec<(NodeIndex, Message<Block>)> {
+		self.queue.write().drain(..)
+			.map(|p| (p.recipient, Decode::decode(&mut &p.data[..]).expect("Test packets are always well-formed")))
+			.collect()
+	}
+
 	fn with_io<'a, F, U>(&'a self, f: F) -> U where F: FnOnce(&mut TestIo<'a>) -> U {
 		let mut io = TestIo::new(&self.queue, None);
 		f(&mut io)
@@ -256,6 +286,7 @@ impl<V: 'static + Verifier<Block>, D> Peer<V, D> {
 					receipt: None,
 					message_queue: None,
 					justification: Some(Vec::new()),
+					compact_body: None,
 				},
 			}]);
 		}
@@ -303,7 +334,7 @@ impl TransactionPool<Hash, Block> for EmptyTransactionPool {
 		Vec::new()
 	}
 
-	fn import(&self, _transaction: &Extrinsic) -> Option<Hash> {
+	fn import(&self, _reputation: i32, _transaction: &Extrinsic) -> Option<Hash> {
 		None
 	}
 
@@ -351,8 +382,12 @@ pub trait TestNetFactory: Sized {
 
 	/// Add a peer.
 	fn add_peer(&mut self, config: &ProtocolConfig) {
+		self.add_peer_with_pool(config, Arc::new(EmptyTransactionPool))
+	}
+
+	/// Add a peer with the given transaction pool.
+	fn add_peer_with_pool(&mut self, config: &ProtocolConfig, tx_pool: Arc<TransactionPool<Hash, Block>>) {
 		let client = Arc::new(test_client::new());
-		let tx_pool = Arc::new(EmptyTransactionPool);
 		let verifier = self.make_verifier(client.clone(), config);
 		let (block_import, data) = self.make_block_import(client.clone());
 
@@ -0,0 +1,3535 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chain::Client as ChainClient;
+use client::{BlockStatus, ClientInfo};
+use client::error::ErrorKind as ClientErrorKind;
+use client::light::fetcher::ChangesProof;
+use config::{GenesisMismatchAction, PeerFeatures, ReputationDecay, Roles, ServeDuringSync, SyncTieBreak, TunableProtocolConfig};
+use consensus::{ImportBlock, ImportResult};
+use primitives::AuthorityId;
+use runtime_primitives::Justification;
+use runtime_primitives::traits::{BlakeTwo256, Hash as HashT, Header as HeaderT};
+use test_client::TestClient;
+use test_client::runtime::Header;
+use message::{BlockAttributes, BlockChunkRequest, Direction, FromBlock, GoodbyeReason};
+use message::generic::{Message as GenericMessage, BlockAnnounce, BlockRequest, Status};
+use network_libp2p::parse_str_addr;
+use import_queue::ImportQueueStatus;
+use specialization::MultiplexedSpecialization;
+use sync::SyncState;
+use protocol::{CURRENT_VERSION, MIN_VERSION, BlockResponseSizes, Health, PacketError, PropagationSnapshot};
+use super::*;
+
+fn status_from(client: &PeersClient, roles: Roles, session_nonce: u64) -> Message<Block> {
+	let info = client.info().unwrap();
+	GenericMessage::Status(Status {
+		version: CURRENT_VERSION,
+		roles,
+		best_number: info.chain.best_number,
+		best_hash: info.chain.best_hash,
+		genesis_hash: info.chain.genesis_hash,
+		chain_status: vec![],
+		session_nonce,
+	})
+}
+
+#[test]
+fn status_counts_distinct_best_hashes() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(3);
+	// peers 1 and 2 are on two different forks of the same height
+	net.peer(1).push_blocks(1, false);
+	net.peer(2).push_blocks(1, true);
+	net.sync();
+	assert_eq!(net.peer(0).sync.status().distinct_best_hashes, 2);
+}
+
+#[test]
+fn counts_received_messages_per_type() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.sync();
+	let peer = net.peer(0);
+	peer.receive(1, GenericMessage::Transactions(vec![]));
+	peer.receive(1, GenericMessage::Transactions(vec![]));
+	peer.receive(1, GenericMessage::Consensus(Default::default(), vec![1]));
+
+	let metrics = peer.sync.metrics();
+	assert_eq!(metrics.get("messages.status"), Some(&1));
+	assert_eq!(metrics.get("messages.transactions"), Some(&2));
+	assert_eq!(metrics.get("messages.consensus"), Some(&1));
+	assert_eq!(metrics.get("messages.block_request"), None);
+}
+
+#[test]
+fn duplicate_status_keeps_peer_state() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.sync();
+	let peer = net.peer(0);
+	let status = status_from(&net.peer(1).client, Roles::AUTHORITY, 0);
+	assert!(peer.receive(1, status).is_empty());
+	assert_eq!(peer.sync.peer_info(&TestIo::new(&peer.queue, None), 1).unwrap().roles, Roles::FULL);
+}
+
+#[test]
+fn restarted_session_refreshes_peer_state() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.sync();
+	let peer = net.peer(0);
+	let status = status_from(&net.peer(1).client, Roles::AUTHORITY, u64::max_value());
+	assert!(peer.receive(1, status).is_empty());
+	assert_eq!(peer.sync.peer_info(&TestIo::new(&peer.queue, None), 1).unwrap().roles, Roles::AUTHORITY);
+	assert_eq!(peer.sync.status().num_peers, 1);
+}
+
+/// Status of a peer whose best block is `blocks` ahead of the genesis of `client`.
+fn status_ahead_of(client: &PeersClient, blocks: u64) -> Message<Block> {
+	let info = client.info().unwrap();
+	GenericMessage::Status(Status {
+		version: CURRENT_VERSION,
+		roles: Roles::FULL,
+		best_number: blocks,
+		best_hash: [1u8; 32].into(),
+		genesis_hash: info.chain.genesis_hash,
+		chain_status: vec![],
+		session_nonce: 0,
+	})
+}
+
+fn transfer(nonce: u64) -> Extrinsic {
+	let transfer = Transfer {
+		from: Keyring::Alice.to_raw_public().into(),
+		to: Keyring::Bob.to_raw_public().into(),
+		amount: 1,
+		nonce,
+	};
+	let signature = Keyring::Alice.sign(&transfer.encode()).into();
+	Extrinsic { transfer, signature }
+}
+
+/// Create a protocol on top of a fresh client, using the given transaction pool.
+fn protocol_with_pool(
+	config: ProtocolConfig,
+	transaction_pool: Arc<TransactionPool<Hash, Block>>,
+) -> (Arc<PeersClient>, Protocol<Block, DummySpecialization, Hash>) {
+	let client = Arc::new(test_client::new());
+	let import_queue = Arc::new(SyncImportQueue::new(Arc::new(PassThroughVerifier(false)), client.clone()));
+	let protocol = Protocol::new(
+		config,
+		client.clone(),
+		import_queue,
+		None,
+		transaction_pool,
+		DummySpecialization { },
+	).unwrap();
+	(client, protocol)
+}
+
+fn block_request(fields: BlockAttributes, from: u64, direction: Direction, max: Option<u32>) -> Message<Block> {
+	GenericMessage::BlockRequest(BlockRequest {
+		id: 0,
+		fields,
+		from: FromBlock::Number(from),
+		to: None,
+		direction,
+		max,
+	})
+}
+
+fn block_response(peer: &Peer<PassThroughVerifier, ()>) -> ::message::BlockResponse<Block> {
+	peer.sent_messages().into_iter()
+		.filter_map(|(_, m)| match m {
+			GenericMessage::BlockResponse(r) => Some(r),
+			_ => None,
+		})
+		.next()
+		.expect("A block response has been sent")
+}
+
+fn last_block_request(peer: &Peer<PassThroughVerifier, ()>) -> ::message::BlockRequest<Block> {
+	peer.sent_messages().into_iter()
+		.filter_map(|(_, m)| match m {
+			GenericMessage::BlockRequest(r) => Some(r),
+			_ => None,
+		})
+		.last()
+		.expect("A block request has been sent")
+}
+
+#[test]
+fn serves_compact_bodies() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	let peer = net.peer(0);
+	peer.push_blocks(1, true);
+	peer.receive(1, block_request(BlockAttributes::HEADER | BlockAttributes::COMPACT_BODY, 1, Direction::Ascending, Some(1)));
+
+	let response = block_response(peer);
+	let body = peer.client.body(&BlockId::Number(1)).unwrap().unwrap();
+	assert_eq!(response.blocks.len(), 1);
+	assert_eq!(response.blocks[0].body, None);
+	assert_eq!(response.blocks[0].compact_body, Some(body.iter().map(|e| BlakeTwo256::hash_of(e)).collect()));
+}
+
+#[test]
+fn omits_bodies_for_old_version_peers() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(0);
+	let mut config = ProtocolConfig::default();
+	config.min_version_for_block_bodies = CURRENT_VERSION + 1;
+	net.add_peer(&config);
+	net.add_peer(&ProtocolConfig::default());
+	let peer = net.peer(0);
+	peer.push_blocks(1, true);
+	peer.receive(1, status_from(&net.peer(1).client, Roles::FULL, 0));
+	peer.receive(1, block_request(BlockAttributes::HEADER | BlockAttributes::BODY, 1, Direction::Ascending, Some(1)));
+
+	let response = block_response(peer);
+	assert!(response.blocks[0].header.is_some());
+	assert_eq!(response.blocks[0].body, None);
+}
+
+#[test]
+fn health_reflects_sync_state_and_peers() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	assert_eq!(net.peer(0).sync.health(), Health { is_syncing: false, peers: 0, should_have_peers: true });
+
+	net.peer(1).push_blocks(500, false);
+	net.sync_steps(3);
+	assert_eq!(net.peer(0).sync.health(), Health { is_syncing: true, peers: 1, should_have_peers: true });
+
+	let mut config = ProtocolConfig::default();
+	config.isolated = true;
+	net.add_peer(&config);
+	assert!(!net.peer(2).sync.health().should_have_peers);
+}
+
+#[test]
+fn seeded_peer_order_is_reproducible() {
+	::env_logger::init().ok();
+	fn announcement_order(seed: u64) -> Vec<NodeIndex> {
+		let mut config = ProtocolConfig::default();
+		config.peer_order_seed = Some(seed);
+		let mut net = TestNet::new(0);
+		for _ in 0..5 {
+			net.add_peer(&config);
+		}
+		net.sync();
+
+		let peer = net.peer(0);
+		peer.generate_blocks(1, BlockOrigin::Own, |builder| builder.bake().unwrap());
+		let header = peer.client().header(&BlockId::Number(1)).unwrap().unwrap();
+		peer.with_io(|io| peer.sync.on_block_imported(io, header.hash(), &header));
+		peer.sent_messages().into_iter()
+			.filter_map(|(who, m)| match m {
+				GenericMessage::BlockAnnounce(_) => Some(who),
+				_ => None,
+			})
+			.collect()
+	}
+
+	let order = announcement_order(42);
+	assert_eq!(order.len(), 4);
+	assert_eq!(order, announcement_order(42));
+}
+
+#[test]
+fn defers_announcement_until_body_is_available() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(3);
+	net.sync();
+	net.peer(1).push_blocks(1, false);
+	let header = net.peer(1).client().header(&BlockId::Number(1)).unwrap().unwrap();
+	let announced = |peer: &Peer<PassThroughVerifier, ()>| peer.sent_messages().into_iter()
+		.any(|(_, m)| match m {
+			GenericMessage::BlockAnnounce(_) => true,
+			_ => false,
+		});
+
+	let peer = net.peer(0);
+	peer.with_io(|io| peer.sync.on_block_imported(io, header.hash(), &header));
+	assert!(!announced(peer));
+
+	peer.push_blocks(1, false);
+	assert_eq!(peer.client().info().unwrap().chain.best_hash, header.hash());
+	peer.sync_step();
+	assert!(announced(peer));
+}
+
+/// Transaction pool which takes a while to import every transaction.
+struct SlowTransactionPool {
+	delay: Duration,
+	imported: AtomicUsize,
+}
+
+impl TransactionPool<Hash, Block> for SlowTransactionPool {
+	fn transactions(&self) -> Vec<(Hash, Extrinsic)> {
+		Vec::new()
+	}
+
+	fn import(&self, _reputation: i32, transaction: &Extrinsic) -> Option<Hash> {
+		thread::sleep(self.delay);
+		self.imported.fetch_add(1, Ordering::SeqCst);
+		Some(BlakeTwo256::hash_of(transaction))
+	}
+
+	fn on_broadcasted(&self, _: HashMap<Hash, Vec<String>>) {}
+}
+
+#[test]
+fn imports_extrinsics_in_background() {
+	::env_logger::init().ok();
+	let pool = Arc::new(SlowTransactionPool { delay: Duration::from_millis(50), imported: AtomicUsize::new(0) });
+	let mut config = ProtocolConfig::default();
+	config.extrinsics_import_queue = Some(4);
+	let (client, protocol) = protocol_with_pool(config, pool.clone());
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+
+	let started = Instant::now();
+	let extrinsics = (0..20).map(transfer).collect();
+	protocol.handle_packet(&mut io, 1, &GenericMessage::Transactions(extrinsics).encode()).ok();
+	assert!(started.elapsed() < Duration::from_millis(500));
+
+	while pool.imported.load(Ordering::SeqCst) < 20 {
+		assert!(started.elapsed() < Duration::from_secs(10));
+		thread::sleep(Duration::from_millis(10));
+	}
+}
+
+/// Transaction pool holding a limited number of transactions. When full, a transaction from a
+/// peer with a better reputation replaces the one received from the least reputable peer.
+struct BoundedTransactionPool {
+	capacity: usize,
+	retained: RwLock<Vec<(i32, Hash)>>,
+}
+
+impl TransactionPool<Hash, Block> for BoundedTransactionPool {
+	fn transactions(&self) -> Vec<(Hash, Extrinsic)> {
+		Vec::new()
+	}
+
+	fn import(&self, reputation: i32, transaction: &Extrinsic) -> Option<Hash> {
+		let hash = BlakeTwo256::hash_of(transaction);
+		let mut retained = self.retained.write();
+		if retained.len() >= self.capacity {
+			let (worst, &(worst_reputation, _)) = retained.iter().enumerate().min_by_key(|&(_, r)| r.0)?;
+			if worst_reputation >= reputation {
+				return None;
+			}
+			retained.remove(worst);
+		}
+		retained.push((reputation, hash));
+		Some(hash)
+	}
+
+	fn on_broadcasted(&self, _: HashMap<Hash, Vec<String>>) {}
+}
+
+#[test]
+fn passes_sender_reputation_to_transaction_pool() {
+	::env_logger::init().ok();
+	let pool = Arc::new(BoundedTransactionPool { capacity: 2, retained: RwLock::new(Vec::new()) });
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), pool.clone());
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 1).encode()).ok();
+	let hash = |nonce| BlakeTwo256::hash_of(&transfer(nonce));
+	let retained = || pool.retained.read().iter().map(|&(_, h)| h).collect::<Vec<_>>();
+
+	// Peer 1 gains reputation by supplying transactions the pool accepts.
+	protocol.handle_packet(&mut io, 1, &GenericMessage::Transactions(vec![transfer(0)]).encode()).ok();
+	protocol.handle_packet(&mut io, 1, &GenericMessage::Transactions(vec![transfer(1)]).encode()).ok();
+	assert_eq!(retained(), vec![hash(0), hash(1)]);
+
+	// The pool is full and peer 2 is not trusted enough to replace anything.
+	protocol.handle_packet(&mut io, 2, &GenericMessage::Transactions(vec![transfer(2)]).encode()).ok();
+	assert_eq!(retained(), vec![hash(0), hash(1)]);
+
+	// Peer 1 is trusted enough to replace the transaction imported with the lowest reputation.
+	protocol.handle_packet(&mut io, 1, &GenericMessage::Transactions(vec![transfer(3)]).encode()).ok();
+	assert_eq!(pool.retained.read().clone(), vec![(1, hash(1)), (2, hash(3))]);
+}
+
+/// Transaction pool with a fixed set of ready transactions.
+struct ReadyTransactionPool(Vec<Extrinsic>);
+
+impl TransactionPool<Hash, Block> for ReadyTransactionPool {
+	fn transactions(&self) -> Vec<(Hash, Extrinsic)> {
+		self.0.iter().map(|t| (BlakeTwo256::hash_of(t), t.clone())).collect()
+	}
+
+	fn import(&self, _reputation: i32, _transaction: &Extrinsic) -> Option<Hash> {
+		None
+	}
+
+	fn on_broadcasted(&self, _: HashMap<Hash, Vec<String>>) {}
+}
+
+#[test]
+fn tracks_propagated_extrinsics() {
+	::env_logger::init().ok();
+	let pool = Arc::new(ReadyTransactionPool(vec![transfer(0)]));
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), pool);
+	let hash = BlakeTwo256::hash_of(&transfer(0));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+
+	protocol.propagate_extrinsics(&mut io);
+	assert!(!protocol.is_extrinsic_propagated(&hash));
+
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	protocol.propagate_extrinsics(&mut io);
+	assert!(protocol.is_extrinsic_propagated(&hash));
+}
+
+/// Transaction pool in which the last ready transaction is invalidated by a reorg right after
+/// the pool has been read.
+struct ReorgingTransactionPool {
+	ready: Vec<Extrinsic>,
+	invalid: RwLock<HashSet<Hash>>,
+}
+
+impl TransactionPool<Hash, Block> for ReorgingTransactionPool {
+	fn transactions(&self) -> Vec<(Hash, Extrinsic)> {
+		let transactions: Vec<_> = self.ready.iter().map(|t| (BlakeTwo256::hash_of(t), t.clone())).collect();
+		if let Some(&(hash, _)) = transactions.last() {
+			self.invalid.write().insert(hash);
+		}
+		transactions
+	}
+
+	fn import(&self, _reputation: i32, _transaction: &Extrinsic) -> Option<Hash> {
+		None
+	}
+
+	fn on_broadcasted(&self, _: HashMap<Hash, Vec<String>>) {}
+
+	fn is_ready(&self, hash: &Hash) -> bool {
+		!self.invalid.read().contains(hash)
+	}
+}
+
+#[test]
+fn does_not_propagate_extrinsics_invalidated_after_snapshot() {
+	::env_logger::init().ok();
+	let pool = Arc::new(ReorgingTransactionPool { ready: vec![transfer(0), transfer(1)], invalid: RwLock::new(HashSet::new()) });
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), pool);
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		protocol.propagate_extrinsics(&mut io);
+	}
+
+	let sent: Vec<_> = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::Transactions(transactions)) => Some(transactions),
+			_ => None,
+		})
+		.collect();
+	assert_eq!(sent, vec![vec![transfer(0)]]);
+	assert!(protocol.is_extrinsic_propagated(&BlakeTwo256::hash_of(&transfer(0))));
+	assert!(!protocol.is_extrinsic_propagated(&BlakeTwo256::hash_of(&transfer(1))));
+}
+
+/// Import queue recording the blocks it is asked to import.
+#[derive(Default)]
+struct RecordingImportQueue {
+	blocks: RwLock<Vec<::import_queue::BlockData<Block>>>,
+	importing_count: AtomicUsize,
+	/// Blocks reported as being imported.
+	importing: RwLock<HashSet<Hash>>,
+}
+
+impl ImportQueue<Block> for RecordingImportQueue {
+	fn clear(&self) {}
+
+	fn stop(&self) {}
+
+	fn status(&self) -> ImportQueueStatus<Block> {
+		ImportQueueStatus {
+			importing_count: self.importing_count.load(Ordering::SeqCst),
+			best_importing_number: 0,
+		}
+	}
+
+	fn is_importing(&self, hash: &Hash) -> bool {
+		self.importing.read().contains(hash)
+	}
+
+	fn import_blocks(&self, _origin: BlockOrigin, blocks: Vec<::import_queue::BlockData<Block>>) {
+		self.blocks.write().extend(blocks);
+	}
+}
+
+/// Connect two peers, in the given order, reporting different best blocks at the same height
+/// while the import queue is full. Returns the peer blocks are requested from once it drains.
+fn tied_peer_followed(tie_break: SyncTieBreak, connection_order: [NodeIndex; 2]) -> NodeIndex {
+	let client = Arc::new(test_client::new());
+	let import_queue = Arc::new(RecordingImportQueue::default());
+	import_queue.importing_count.store(2048, Ordering::SeqCst);
+	let protocol: Protocol<Block, DummySpecialization, Hash> = Protocol::new(
+		ProtocolConfig { sync_tie_break: tie_break, ..Default::default() },
+		client.clone(),
+		import_queue.clone(),
+		None,
+		Arc::new(EmptyTransactionPool),
+		DummySpecialization { },
+	).unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		for (i, who) in connection_order.iter().enumerate() {
+			let mut status = status_ahead_of(&client, 5);
+			if let GenericMessage::Status(ref mut status) = status {
+				status.best_hash = [i as u8 + 1; 32].into();
+			}
+			protocol.handle_packet(&mut io, *who, &status.encode()).ok();
+		}
+		protocol.handle_packet(&mut io, 3, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		import_queue.importing_count.store(0, Ordering::SeqCst);
+		protocol.on_peer_disconnected(&mut io, 3);
+	}
+
+	let requested: Vec<NodeIndex> = queue.write().drain(..)
+		.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(_)) => true,
+			_ => false,
+		})
+		.map(|p| p.recipient)
+		.collect();
+	assert_eq!(requested.len(), 1);
+	requested[0]
+}
+
+#[test]
+fn tie_break_selects_peer_to_follow() {
+	::env_logger::init().ok();
+	assert_eq!(tied_peer_followed(SyncTieBreak::FirstSeen, [1, 2]), 1);
+	assert_eq!(tied_peer_followed(SyncTieBreak::FirstSeen, [2, 1]), 2);
+
+	let seeded = tied_peer_followed(SyncTieBreak::Random(7), [1, 2]);
+	assert_eq!(tied_peer_followed(SyncTieBreak::Random(7), [2, 1]), seeded);
+}
+
+/// Download the single block of a fresh peer with compact bodies, with the given extrinsics in
+/// our pool, from a peer which may ignore the compact bodies requested. Returns the messages
+/// sent in answer to the response and the blocks queued for import.
+fn download_with_compact_bodies(ready: Vec<Extrinsic>, ignored: bool) -> (Vec<Message<Block>>, Vec<::import_queue::BlockData<Block>>) {
+	let net = TestNet::new(1);
+	let server = net.peer(0);
+	server.push_blocks(1, true);
+	let import_queue = Arc::new(RecordingImportQueue::default());
+	let protocol: Protocol<Block, DummySpecialization, Hash> = Protocol::new(
+		ProtocolConfig { compact_block_bodies: true, ..Default::default() },
+		Arc::new(test_client::new()),
+		import_queue.clone(),
+		None,
+		Arc::new(ReadyTransactionPool(ready)),
+		DummySpecialization { },
+	).unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let process = |message: Message<Block>| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			protocol.handle_packet(&mut io, 1, &message.encode()).ok();
+		}
+		queue.write().drain(..)
+			.filter_map(|p| <Message<Block> as Decode>::decode(&mut &p.data[..]))
+			.collect::<Vec<_>>()
+	};
+
+	let request = process(status_from(&server.client, Roles::FULL, 0)).into_iter()
+		.filter_map(|m| match m {
+			GenericMessage::BlockRequest(r) => Some(r),
+			_ => None,
+		})
+		.next()
+		.expect("Blocks are requested from the new peer");
+	assert!(request.fields.contains(BlockAttributes::COMPACT_BODY));
+	assert!(!request.fields.contains(BlockAttributes::BODY));
+	server.receive(0, GenericMessage::BlockRequest(request));
+	let mut response = block_response(server);
+	if ignored {
+		for block in &mut response.blocks {
+			block.compact_body = None;
+		}
+	}
+	let sent = process(GenericMessage::BlockResponse(response));
+	let blocks = import_queue.blocks.read().clone();
+	(sent, blocks)
+}
+
+#[test]
+fn rebuilds_compact_bodies_from_pool() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	net.peer(0).push_blocks(1, true);
+	let body = net.peer(0).client.body(&BlockId::Number(1)).unwrap().unwrap();
+
+	let (sent, blocks) = download_with_compact_bodies(body.clone(), false);
+	assert!(!sent.iter().any(|m| match *m { GenericMessage::BlockRequest(_) => true, _ => false }));
+	assert_eq!(blocks.len(), 1);
+	assert_eq!(blocks[0].block.body, Some(body));
+
+	let (sent, blocks) = download_with_compact_bodies(vec![], false);
+	assert!(sent.iter().any(|m| match *m {
+		GenericMessage::BlockRequest(ref r) => r.fields.contains(BlockAttributes::BODY),
+		_ => false,
+	}));
+	assert!(blocks.is_empty());
+}
+
+#[test]
+fn requests_full_bodies_from_peers_ignoring_compact_bodies() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	net.peer(0).push_blocks(1, true);
+	let body = net.peer(0).client.body(&BlockId::Number(1)).unwrap().unwrap();
+
+	let (sent, blocks) = download_with_compact_bodies(body, true);
+	assert!(sent.iter().any(|m| match *m {
+		GenericMessage::BlockRequest(ref r) => r.fields.contains(BlockAttributes::BODY),
+		_ => false,
+	}));
+	assert!(blocks.is_empty());
+}
+
+#[test]
+fn records_first_block_source() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(3);
+	net.sync();
+	net.peer(1).push_blocks(1, false);
+	let header = net.peer(1).client().header(&BlockId::Number(1)).unwrap().unwrap();
+
+	let peer = net.peer(0);
+	peer.receive(2, GenericMessage::BlockAnnounce(BlockAnnounce { header: header.clone() }));
+	peer.receive(1, GenericMessage::BlockAnnounce(BlockAnnounce { header: header.clone() }));
+	assert_eq!(peer.sync.block_source(&header.hash()), Some(2));
+	assert_eq!(peer.sync.block_source(&Default::default()), None);
+}
+
+#[test]
+fn defers_extrinsics_during_reorg() {
+	::env_logger::init().ok();
+	let pool = Arc::new(SlowTransactionPool { delay: Duration::from_millis(0), imported: AtomicUsize::new(0) });
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), pool.clone());
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+
+	let genesis = client.header(&BlockId::Number(0)).unwrap().unwrap();
+	protocol.on_block_imported(&mut io, genesis.hash(), &genesis);
+	let fork = Header::new(1, Default::default(), Default::default(), [1u8; 32].into(), Default::default());
+	protocol.on_block_imported(&mut io, fork.hash(), &fork);
+
+	protocol.handle_packet(&mut io, 1, &GenericMessage::Transactions(vec![transfer(0), transfer(1)]).encode()).ok();
+	assert_eq!(pool.imported.load(Ordering::SeqCst), 0);
+
+	protocol.tick(&mut io);
+	assert_eq!(pool.imported.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn applies_config_update_on_next_tick() {
+	::env_logger::init().ok();
+	let pool = Arc::new(SlowTransactionPool { delay: Duration::from_millis(0), imported: AtomicUsize::new(0) });
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), pool);
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	// A peer ahead of us is sent a block request.
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	protocol.tick(&mut io);
+	assert!(!io.to_disconnect.contains(&1));
+
+	let mut config = protocol.tunable_config();
+	config.min_block_request_timeout = Duration::from_millis(0);
+	config.max_block_request_timeout = Duration::from_millis(0);
+	protocol.update_config(config.clone());
+	assert!(protocol.tunable_config() != config);
+
+	thread::sleep(Duration::from_millis(10));
+	protocol.tick(&mut io);
+	assert_eq!(protocol.tunable_config(), config);
+	assert!(io.to_disconnect.contains(&1));
+}
+
+#[test]
+fn refuses_flapping_node() {
+	::env_logger::init().ok();
+	let pool = Arc::new(SlowTransactionPool { delay: Duration::from_millis(0), imported: AtomicUsize::new(0) });
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), pool);
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	let (peer_id, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+
+	// Every connection gets a new node index
+	let mut who = 0;
+	let mut connect = |io: &mut TestIo| {
+		who += 1;
+		io.peer_ids.insert(who, peer_id.clone());
+		protocol.on_peer_connected(io, who);
+		protocol.handle_packet(io, who, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		who
+	};
+	for _ in 0..5 {
+		let who = connect(&mut io);
+		assert!(protocol.peer_info(&TestIo::new(&queue, None), who).is_some());
+		protocol.on_peer_disconnected(&mut io, who);
+	}
+	assert!(io.to_disconnect.is_empty());
+
+	for _ in 0..2 {
+		let who = connect(&mut io);
+		assert!(io.to_disconnect.contains(&who));
+		assert!(protocol.peer_info(&TestIo::new(&queue, None), who).is_none());
+	}
+}
+
+#[test]
+fn requests_only_lost_chunks_again() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(1);
+	net.add_peer(&ProtocolConfig { chunked_block_responses: true, ..Default::default() });
+	net.peer(0).push_blocks(40, false);
+	let (server, client) = (net.peer(0), net.peer(1));
+	server.receive(1, status_from(&client.client, Roles::FULL, 0));
+	client.receive(0, status_from(&server.client, Roles::FULL, 0));
+
+	let request = client.sent_messages().into_iter()
+		.filter_map(|(_, m)| match m {
+			GenericMessage::BlockRequest(r) => Some(r),
+			_ => None,
+		})
+		.next()
+		.expect("The client requests the blocks it is missing");
+	assert!(request.fields.contains(BlockAttributes::CHUNKED));
+
+	let chunks = |peer: &Peer<PassThroughVerifier, ()>| -> Vec<_> {
+		peer.sent_messages().into_iter()
+			.filter_map(|(_, m)| match m {
+				GenericMessage::BlockResponseChunk(c) => Some(c),
+				_ => None,
+			})
+			.collect()
+	};
+	server.receive(1, GenericMessage::BlockRequest(request));
+	let sent = chunks(server);
+	assert_eq!(sent.len(), 3);
+
+	// The second chunk is lost
+	for chunk in sent.into_iter().filter(|c| c.index != 1) {
+		client.receive(0, GenericMessage::BlockResponseChunk(chunk));
+	}
+	let resend_requests: Vec<_> = client.sent_messages().into_iter()
+		.filter_map(|(_, m)| match m {
+			GenericMessage::BlockChunkRequest(r) => Some(r),
+			_ => None,
+		})
+		.collect();
+	assert_eq!(resend_requests.len(), 1);
+	assert_eq!(resend_requests[0].chunks, vec![1]);
+
+	server.receive(1, GenericMessage::BlockChunkRequest(resend_requests[0].clone()));
+	let resent = chunks(server);
+	assert_eq!(resent.len(), 1);
+	assert_eq!(resent[0].index, 1);
+	client.receive(0, GenericMessage::BlockResponseChunk(resent[0].clone()));
+	assert_eq!(client.client.info().unwrap().chain.best_number, 40);
+
+	// Repeated and unknown chunks are not sent
+	let id = resend_requests[0].id;
+	server.receive(1, GenericMessage::BlockChunkRequest(BlockChunkRequest { id, chunks: vec![2, 2, 2, 3, 1000] }));
+	let resent = chunks(server);
+	assert_eq!(resent.len(), 1);
+	assert_eq!(resent[0].index, 2);
+}
+
+#[test]
+fn refuses_requests_for_too_many_chunks() {
+	::env_logger::init().ok();
+	let (_, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	let request: Message<Block> = GenericMessage::BlockChunkRequest(BlockChunkRequest { id: 0, chunks: vec![0; 1000] });
+	protocol.handle_packet(&mut io, 1, &request.encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+}
+
+#[test]
+fn ignores_future_messages_unless_strict() {
+	::env_logger::init().ok();
+	let future_message = [::message::LAST_MESSAGE_INDEX + 1, 1, 2, 3];
+	let queue = RwLock::new(VecDeque::new());
+
+	let mut net = TestNet::new(2);
+	net.sync();
+	let mut io = TestIo::new(&queue, None);
+	net.peer(0).sync.handle_packet(&mut io, 1, &future_message).ok();
+	assert!(io.to_disconnect.is_empty());
+	assert_eq!(net.peer(0).sync.metrics().get("messages.unknown"), Some(&1));
+
+	net.add_peer(&ProtocolConfig { strict_messages: true, ..Default::default() });
+	let mut io = TestIo::new(&queue, None);
+	net.peer(2).sync.handle_packet(&mut io, 1, &future_message).ok();
+	assert!(io.to_disconnect.contains(&1));
+}
+
+#[test]
+fn does_not_request_blocks_from_backed_up_peer() {
+	::env_logger::init().ok();
+	let pool = Arc::new(SlowTransactionPool { delay: Duration::from_millis(0), imported: AtomicUsize::new(0) });
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), pool);
+	let queue = RwLock::new(VecDeque::new());
+	let sent_request = |queue: &RwLock<VecDeque<TestPacket>>| queue.write().drain(..)
+		.any(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(_)) => true,
+			_ => false,
+		});
+	{
+		let mut io = TestIo::new(&queue, None);
+		io.send_queue_lens.insert(1, 1000);
+		protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+		assert_eq!(protocol.peer_info(&io, 1).map(|info| info.send_queue_len), Some(1000));
+	}
+	assert!(!sent_request(&queue));
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 1).is_some());
+
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 2, &status_ahead_of(&client, 5).encode()).ok();
+	}
+	assert!(sent_request(&queue));
+}
+
+/// Import queue holding more blocks than sync is willing to queue.
+struct SaturatedImportQueue;
+
+impl ImportQueue<Block> for SaturatedImportQueue {
+	fn clear(&self) {}
+
+	fn stop(&self) {}
+
+	fn status(&self) -> ImportQueueStatus<Block> {
+		ImportQueueStatus {
+			importing_count: 10_000,
+			best_importing_number: 0,
+		}
+	}
+
+	fn is_importing(&self, _hash: &Hash) -> bool {
+		false
+	}
+
+	fn import_blocks(&self, _origin: BlockOrigin, _blocks: Vec<::import_queue::BlockData<Block>>) {}
+}
+
+#[test]
+fn pauses_block_requests_while_import_queue_is_saturated() {
+	::env_logger::init().ok();
+	let client = Arc::new(test_client::new());
+	let protocol: Protocol<Block, DummySpecialization, Hash> = Protocol::new(
+		ProtocolConfig::default(),
+		client.clone(),
+		Arc::new(SaturatedImportQueue),
+		None,
+		Arc::new(EmptyTransactionPool),
+		DummySpecialization { },
+	).unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	}
+	assert_eq!(protocol.status().importing_blocks, 10_000);
+	assert_eq!(protocol.import_queue_status().importing_count, 10_000);
+	assert!(!queue.write().drain(..).any(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+		Some(GenericMessage::BlockRequest(_)) => true,
+		_ => false,
+	}));
+}
+
+#[test]
+fn refuses_block_requests_while_busy() {
+	::env_logger::init().ok();
+	let client = Arc::new(test_client::new());
+	let protocol: Protocol<Block, DummySpecialization, Hash> = Protocol::new(
+		ProtocolConfig { busy_import_queue_len: Some(100), ..Default::default() },
+		client.clone(),
+		Arc::new(SaturatedImportQueue),
+		None,
+		Arc::new(EmptyTransactionPool),
+		DummySpecialization { },
+	).unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let respond = |request: Message<Block>| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			protocol.handle_packet(&mut io, 1, &request.encode()).ok();
+		}
+		queue.write().drain(..).filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockResponse(r)) => Some(r),
+			_ => None,
+		}).next()
+	};
+	respond(status_from(&client, Roles::FULL, 0));
+
+	let response = respond(block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(10)))
+		.expect("A block response has been sent");
+	assert!(response.busy);
+	assert!(response.blocks.is_empty());
+	assert_eq!(protocol.metrics().get("requests.refused_busy"), Some(&1));
+
+	let response = respond(block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(1)))
+		.expect("A block response has been sent");
+	assert!(!response.busy);
+	assert_eq!(response.blocks.len(), 1);
+}
+
+#[test]
+fn observer_never_requests_blocks() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { observer_mode: true, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	let header = Header::new(1, Default::default(), Default::default(), genesis, Default::default());
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	protocol.handle_packet(&mut io, 1, &GenericMessage::BlockAnnounce(BlockAnnounce { header }).encode()).ok();
+	protocol.tick(&mut io);
+	protocol.handle_packet(&mut io, 1, &block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(1)).encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	drop(io);
+
+	let sent: Vec<_> = queue.write().drain(..)
+		.filter_map(|p| <Message<Block> as Decode>::decode(&mut &p.data[..]))
+		.collect();
+	assert!(!sent.iter().any(|m| match *m { GenericMessage::BlockRequest(_) => true, _ => false }));
+	assert!(sent.iter().any(|m| match *m { GenericMessage::BlockResponse(_) => true, _ => false }));
+}
+
+#[test]
+fn penalizes_peer_sending_mismatched_responses() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	let mismatched: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id: 42,
+		blocks: vec![],
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	});
+
+	for sent in 1..9 {
+		protocol.handle_packet(&mut io, 1, &mismatched.encode()).ok();
+		assert_eq!(protocol.metrics().get("responses.mismatched_id"), Some(&sent));
+		assert!(io.to_disconnect.is_empty());
+	}
+	protocol.handle_packet(&mut io, 1, &mismatched.encode()).ok();
+	assert_eq!(protocol.metrics().get("responses.mismatched_id"), Some(&9));
+	assert!(io.to_disconnect.contains(&1));
+}
+
+#[test]
+fn block_digest_skips_blocks_unknown_to_peer() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(0);
+	let config = ProtocolConfig { block_digests: true, ..Default::default() };
+	net.add_peer(&config);
+	net.add_peer(&config);
+	net.peer(0).push_blocks(5, false);
+	net.peer(0).push_blocks(5, true);
+	net.peer(1).push_blocks(15, false);
+	let (us, them) = (net.peer(0), net.peer(1));
+
+	them.receive(0, status_from(&us.client, Roles::FULL, 0));
+	let digest = them.sent_messages().into_iter()
+		.map(|(_, m)| m)
+		.find(|m| match *m { GenericMessage::BlockDigest(_) => true, _ => false })
+		.expect("A digest is sent after the handshake");
+	us.receive(1, status_from(&them.client, Roles::FULL, 0));
+	us.receive(1, digest);
+
+	// Our best block is unknown to the peer, as are all our blocks down to the fork point.
+	let request = last_block_request(us);
+	assert_eq!(request.from, FromBlock::Number(10));
+	them.receive(0, GenericMessage::BlockRequest(request));
+	us.receive(1, GenericMessage::BlockResponse(block_response(them)));
+	assert_eq!(last_block_request(us).from, FromBlock::Number(5));
+}
+
+#[test]
+fn replays_announcements_received_before_status() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	let (peer_id, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	io.peer_ids.insert(1, peer_id);
+	let header = Header::new(1, Default::default(), Default::default(), genesis, Default::default());
+	let hash = header.hash();
+
+	protocol.on_peer_connected(&mut io, 1);
+	protocol.handle_packet(&mut io, 1, &GenericMessage::BlockAnnounce(BlockAnnounce { header }).encode()).ok();
+	assert_eq!(protocol.block_source(&hash), None);
+
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	assert_eq!(protocol.block_source(&hash), Some(1));
+	assert!(io.to_disconnect.is_empty());
+}
+
+#[test]
+fn maintain_peers_drops_timed_out_peers_once() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let start = Instant::now();
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+		protocol.on_peer_connected(&mut io, 2);
+		protocol.maintain_peers(&mut io);
+		assert!(io.to_disconnect.is_empty());
+	}
+
+	let later = start + ProtocolConfig::default().max_block_request_timeout + Duration::from_secs(1);
+	let mut io = TestIo::new(&queue, None);
+	protocol.maintain_peers_at(&mut io, later);
+	assert!(io.to_disconnect.contains(&1));
+	assert!(io.to_disconnect.contains(&2));
+
+	let mut io = TestIo::new(&queue, None);
+	protocol.maintain_peers_at(&mut io, later);
+	assert!(io.to_disconnect.is_empty());
+}
+
+#[test]
+fn penalizes_impossible_announcements() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.sync();
+	let peer = net.peer(0);
+	let genesis = peer.client.info().unwrap().chain.genesis_hash;
+
+	let announce = |number, parent_hash| {
+		let header = Header::new(number, Default::default(), Default::default(), parent_hash, Default::default());
+		let hash = header.hash();
+		let disconnected = peer.receive(1, GenericMessage::BlockAnnounce(BlockAnnounce { header }));
+		(hash, disconnected)
+	};
+
+	let (hash, disconnected) = announce(0, [1u8; 32].into());
+	assert!(disconnected.contains(&1));
+	assert_eq!(peer.sync.block_source(&hash), None);
+
+	let (hash, disconnected) = announce(5, genesis);
+	assert!(disconnected.contains(&1));
+	assert_eq!(peer.sync.block_source(&hash), None);
+
+	let (hash, disconnected) = announce(1, genesis);
+	assert!(disconnected.is_empty());
+	assert_eq!(peer.sync.block_source(&hash), Some(1));
+}
+
+#[test]
+fn penalizes_fake_genesis_announcements() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.sync();
+	let peer = net.peer(0);
+	let genesis = peer.client.info().unwrap().chain.genesis_hash;
+
+	let header = Header::new(0, Default::default(), [2u8; 32].into(), Default::default(), Default::default());
+	let hash = header.hash();
+	assert!(hash != genesis);
+	let disconnected = peer.receive(1, GenericMessage::BlockAnnounce(BlockAnnounce { header }));
+	assert!(disconnected.contains(&1));
+	assert_eq!(peer.sync.block_source(&hash), None);
+}
+
+#[test]
+fn best_effort_requests_skip_deep_blocks() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	let peer = net.peer(0);
+	peer.push_blocks(300, false);
+	let fields = BlockAttributes::HEADER | BlockAttributes::BODY;
+
+	peer.receive(1, block_request(fields | BlockAttributes::BEST_EFFORT, 10, Direction::Ascending, Some(10)));
+	assert!(block_response(peer).blocks.is_empty());
+
+	peer.receive(1, block_request(fields | BlockAttributes::BEST_EFFORT, 290, Direction::Ascending, Some(10)));
+	assert_eq!(block_response(peer).blocks.len(), 10);
+
+	peer.receive(1, block_request(fields, 10, Direction::Ascending, Some(10)));
+	assert_eq!(block_response(peer).blocks.len(), 10);
+}
+
+#[test]
+fn refuses_to_track_too_many_forks_from_a_peer() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.sync();
+	let peer = net.peer(0);
+	let genesis = peer.client.info().unwrap().chain.genesis_hash;
+
+	let mut announced = Vec::new();
+	for i in 0..20u8 {
+		let header = Header::new(1, Default::default(), [i + 1; 32].into(), genesis, Default::default());
+		let hash = header.hash();
+		let disconnected = peer.receive(1, GenericMessage::BlockAnnounce(BlockAnnounce { header }));
+		announced.push((hash, disconnected));
+	}
+
+	for &(ref hash, ref disconnected) in &announced[..16] {
+		assert!(disconnected.is_empty());
+		assert_eq!(peer.sync.block_source(hash), Some(1));
+	}
+	let &(ref hash, ref disconnected) = &announced[16];
+	assert!(disconnected.contains(&1));
+	assert_eq!(peer.sync.block_source(hash), None);
+}
+
+#[test]
+fn counts_actions_taken_through_context() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(3);
+	net.sync();
+	let peer = net.peer(0);
+	let count = |key: &str| peer.sync.metrics().get(key).cloned().unwrap_or(0);
+	let (sent, dropped, banned) = (count("context.messages_sent"), count("context.peers_dropped"), count("context.peers_banned"));
+
+	peer.with_spec(|_, context| {
+		context.send_message(1, GenericMessage::Transactions(vec![]));
+		context.send_message(2, GenericMessage::Transactions(vec![]));
+		context.report_peer(1, Severity::Useless("test"));
+		context.report_peer(2, Severity::Timeout);
+		context.report_peer(2, Severity::Bad("test"));
+	});
+
+	assert_eq!(count("context.messages_sent"), sent + 2);
+	assert_eq!(count("context.peers_dropped"), dropped + 2);
+	assert_eq!(count("context.peers_banned"), banned + 1);
+}
+
+#[test]
+fn proves_finality_of_served_blocks() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	let peer = net.peer(0);
+	peer.push_blocks(3, false);
+	peer.client.finalize_block(BlockId::Number(2), true).unwrap();
+	let fields = BlockAttributes::HEADER | BlockAttributes::JUSTIFICATION;
+
+	peer.receive(1, block_request(fields, 1, Direction::Ascending, Some(2)));
+	let proof = block_response(peer).finality_proof.expect("Block 2 is finalized");
+	assert_eq!(proof.header, peer.client.header(&BlockId::Number(2)).unwrap().unwrap());
+	assert_eq!(Some(proof.justification), peer.client.justification(&BlockId::Number(2)).unwrap());
+
+	peer.receive(1, block_request(fields, 1, Direction::Ascending, Some(3)));
+	assert_eq!(block_response(peer).finality_proof, None);
+
+	peer.receive(1, block_request(BlockAttributes::HEADER, 1, Direction::Ascending, Some(2)));
+	assert_eq!(block_response(peer).finality_proof, None);
+}
+
+/// Client failing to read the chain info a given number of times before recovering, and
+/// optionally returning a forged header in place of block #0.
+struct FlakyClient {
+	inner: Arc<PeersClient>,
+	failures: AtomicUsize,
+	forged_genesis: Option<Header>,
+}
+
+impl ChainClient<Block> for FlakyClient {
+	fn import(&self, block: ImportBlock<Block>, new_authorities: Option<Vec<AuthorityId>>)
+		-> Result<ImportResult, ClientError>
+	{
+		ChainClient::import(&*self.inner, block, new_authorities)
+	}
+
+	fn info(&self) -> Result<ClientInfo<Block>, ClientError> {
+		if self.failures.load(Ordering::SeqCst) > 0 {
+			self.failures.fetch_sub(1, Ordering::SeqCst);
+			return Err(ClientErrorKind::Backend("Database is not ready".into()).into());
+		}
+		ChainClient::info(&*self.inner)
+	}
+
+	fn block_status(&self, id: &BlockId<Block>) -> Result<BlockStatus, ClientError> {
+		ChainClient::block_status(&*self.inner, id)
+	}
+
+	fn block_hash(&self, block_number: u64) -> Result<Option<Hash>, ClientError> {
+		ChainClient::block_hash(&*self.inner, block_number)
+	}
+
+	fn header(&self, id: &BlockId<Block>) -> Result<Option<Header>, ClientError> {
+		match (id, &self.forged_genesis) {
+			(&BlockId::Number(0), &Some(ref header)) => Ok(Some(header.clone())),
+			_ => ChainClient::header(&*self.inner, id),
+		}
+	}
+
+	fn body(&self, id: &BlockId<Block>) -> Result<Option<Vec<Extrinsic>>, ClientError> {
+		ChainClient::body(&*self.inner, id)
+	}
+
+	fn justification(&self, id: &BlockId<Block>) -> Result<Option<Justification>, ClientError> {
+		ChainClient::justification(&*self.inner, id)
+	}
+
+	fn authorities(&self, id: &BlockId<Block>) -> Result<Vec<AuthorityId>, ClientError> {
+		ChainClient::authorities(&*self.inner, id)
+	}
+
+	fn header_proof(&self, block_number: u64) -> Result<(Header, Vec<Vec<u8>>), ClientError> {
+		ChainClient::header_proof(&*self.inner, block_number)
+	}
+
+	fn read_proof(&self, block: &Hash, key: &[u8]) -> Result<Vec<Vec<u8>>, ClientError> {
+		ChainClient::read_proof(&*self.inner, block, key)
+	}
+
+	fn execution_proof(&self, block: &Hash, method: &str, data: &[u8]) -> Result<(Vec<u8>, Vec<Vec<u8>>), ClientError> {
+		ChainClient::execution_proof(&*self.inner, block, method, data)
+	}
+
+	fn key_changes_proof(
+		&self,
+		first: Hash,
+		last: Hash,
+		min: Hash,
+		max: Hash,
+		key: &[u8]
+	) -> Result<ChangesProof<Header>, ClientError> {
+		ChainClient::key_changes_proof(&*self.inner, first, last, min, max, key)
+	}
+}
+
+#[test]
+fn retries_reading_chain_info_on_startup() {
+	::env_logger::init().ok();
+	let new_protocol = |failures| -> ::error::Result<Protocol<Block, DummySpecialization, Hash>> {
+		let inner = Arc::new(test_client::new());
+		let client = Arc::new(FlakyClient { inner: inner.clone(), failures: AtomicUsize::new(failures), forged_genesis: None });
+		let import_queue = Arc::new(SyncImportQueue::new(Arc::new(PassThroughVerifier(false)), inner));
+		Protocol::new(
+			ProtocolConfig::default(),
+			client,
+			import_queue,
+			None,
+			Arc::new(EmptyTransactionPool),
+			DummySpecialization { },
+		)
+	};
+
+	assert!(new_protocol(2).is_ok());
+	assert!(new_protocol(10).is_err());
+}
+
+#[test]
+fn batches_consecutive_messages_to_the_same_peer() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { batch_messages: true, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.with_spec(&mut io, |_, context| {
+			for i in 0..3 {
+				context.send_message(1, GenericMessage::ChainSpecific(vec![i]));
+			}
+			context.send_message(2, GenericMessage::ChainSpecific(vec![3]));
+		});
+	}
+
+	let sent: Vec<_> = queue.write().drain(..).collect();
+	assert_eq!(sent.len(), 2);
+	assert_eq!(sent[0].recipient, 1);
+	match <Message<Block> as Decode>::decode(&mut &sent[0].data[..]) {
+		Some(GenericMessage::Batch(messages)) => {
+			let expected: Vec<Message<Block>> = (0..3).map(|i| GenericMessage::ChainSpecific(vec![i])).collect();
+			assert_eq!(messages, expected.iter().map(Encode::encode).collect::<Vec<_>>());
+		},
+		other => panic!("Expected a batch, got {:?}", other),
+	}
+	assert_eq!(sent[1].recipient, 2);
+	assert_eq!(<Message<Block> as Decode>::decode(&mut &sent[1].data[..]), Some(GenericMessage::ChainSpecific(vec![3])));
+
+	// batched messages are handled one by one
+	let mut io = TestIo::new(&queue, None);
+	let batch: Message<Block> = GenericMessage::Batch(vec![
+		status_ahead_of(&client, 0).encode(),
+		block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(1)).encode(),
+	]);
+	protocol.handle_packet(&mut io, 1, &batch.encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	let nested: Message<Block> = GenericMessage::Batch(vec![batch.encode()]);
+	protocol.handle_packet(&mut io, 1, &nested.encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+}
+
+#[test]
+fn reports_local_node_info() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { roles: Roles::LIGHT, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let info = client.info().unwrap();
+	assert_eq!(protocol.genesis_hash(), info.chain.genesis_hash);
+
+	let local = protocol.local_node_info().unwrap();
+	assert_eq!(local.roles, Roles::LIGHT);
+	assert_eq!(local.protocol_version, CURRENT_VERSION);
+	assert_eq!(local.best_hash, info.chain.best_hash);
+	assert_eq!(local.best_number, info.chain.best_number);
+}
+
+#[test]
+fn tracks_average_block_response_sizes() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	let peer = net.peer(0);
+	peer.push_blocks(30, false);
+	assert_eq!(peer.sync.block_response_sizes(), BlockResponseSizes { served: None, received: None });
+
+	for &max in &[10, 20, 3] {
+		peer.receive(1, block_request(BlockAttributes::HEADER, 1, Direction::Ascending, Some(max)));
+	}
+	assert_eq!(peer.sync.block_response_sizes().served, Some(11.0));
+
+	let mut net = TestNet::new(2);
+	net.peer(0).push_blocks(5, false);
+	net.sync();
+	assert_eq!(net.peer(0).sync.block_response_sizes().served, Some(5.0));
+	assert_eq!(net.peer(1).sync.block_response_sizes().received, Some(5.0));
+}
+
+#[test]
+fn marks_fork_divergence_in_block_responses() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.peer(0).push_blocks(3, false);
+	net.sync();
+	net.peer(0).push_blocks(2, true);
+	net.peer(1).push_blocks(3, false);
+	let fork = net.peer(0).client.info().unwrap().chain.best_hash;
+	let fork_start = net.peer(0).client.header(&BlockId::Number(4)).unwrap().unwrap().hash();
+	net.sync();
+	let peer = net.peer(0);
+	assert!(peer.client.info().unwrap().chain.best_hash != fork);
+
+	peer.receive(1, GenericMessage::BlockRequest(BlockRequest {
+		id: 0,
+		fields: BlockAttributes::HEADER,
+		from: FromBlock::Hash(fork_start),
+		to: None,
+		direction: Direction::Ascending,
+		max: Some(10),
+	}));
+	let response = block_response(peer);
+	assert_eq!(response.blocks.iter().map(|b| b.hash).collect::<Vec<_>>(), vec![fork_start]);
+	assert_eq!(response.fork_divergence, Some(5));
+
+	// Canonical ranges are served in full.
+	peer.receive(1, block_request(BlockAttributes::HEADER, 1, Direction::Ascending, Some(6)));
+	let response = block_response(peer);
+	assert_eq!(response.blocks.len(), 6);
+	assert_eq!(response.fork_divergence, None);
+}
+
+#[test]
+fn serves_remote_calls_to_trusted_peers_only() {
+	::env_logger::init().ok();
+	let (trusted, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let config = ProtocolConfig {
+		trusted_peers: vec![trusted.clone()],
+		untrusted_peer_features: PeerFeatures::empty(),
+		..Default::default()
+	};
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		io.peer_ids.insert(2, trusted);
+		for who in 1..3 {
+			protocol.handle_packet(&mut io, who, &status_from(&client, Roles::FULL, 0).encode()).ok();
+			let request: Message<Block> = GenericMessage::RemoteCallRequest(::message::RemoteCallRequest {
+				id: 0,
+				block: genesis,
+				method: "authorities".into(),
+				data: vec![],
+			});
+			protocol.handle_packet(&mut io, who, &request.encode()).ok();
+		}
+		assert!(io.to_disconnect.is_empty());
+	}
+
+	let proofs: HashMap<NodeIndex, Vec<Vec<u8>>> = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::RemoteCallResponse(response)) => Some((p.recipient, response.proof)),
+			_ => None,
+		})
+		.collect();
+	assert!(proofs[&1].is_empty());
+	assert!(!proofs[&2].is_empty());
+}
+
+#[test]
+fn drops_responses_of_disconnected_peers() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 1).is_some());
+	protocol.on_peer_disconnected(&mut io, 1);
+
+	let response: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id: 0,
+		blocks: vec![],
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	});
+	protocol.handle_packet(&mut io, 1, &response.encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	assert_eq!(protocol.metrics().get("responses.disconnected_peer"), Some(&1));
+}
+
+#[test]
+fn evicts_worst_peer_above_soft_limit() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { soft_peer_limit: Some(2), ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	protocol.handle_packet(&mut io, 2, &status_ahead_of(&client, 5).encode()).ok();
+
+	// a peer no better than the existing ones is accepted without eviction
+	protocol.handle_packet(&mut io, 3, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 3).is_some());
+
+	// peer 2 answers with a response to an unknown request, lowering its score
+	let mismatched: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id: 42,
+		blocks: vec![],
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	});
+	protocol.handle_packet(&mut io, 2, &mismatched.encode()).ok();
+
+	protocol.handle_packet(&mut io, 4, &status_from(&client, Roles::AUTHORITY, 0).encode()).ok();
+	assert_eq!(io.to_disconnect.iter().cloned().collect::<Vec<_>>(), vec![2]);
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 2).is_none());
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 1).is_some());
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 4).is_some());
+}
+
+#[test]
+fn restored_propagation_state_suppresses_repropagation() {
+	::env_logger::init().ok();
+	let (peer_id, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	// Connect the peer to a fresh protocol, optionally restoring a snapshot first, and
+	// propagate the pool. Returns the number of transactions messages sent and the protocol.
+	let run = |snapshot: Option<PropagationSnapshot<Block, Hash>>, who: NodeIndex| {
+		let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(ReadyTransactionPool(vec![transfer(0)])));
+		if let Some(snapshot) = snapshot {
+			protocol.import_propagation_state(snapshot);
+		}
+		let queue = RwLock::new(VecDeque::new());
+		{
+			let mut io = TestIo::new(&queue, None);
+			io.peer_ids.insert(who, peer_id.clone());
+			protocol.handle_packet(&mut io, who, &status_from(&client, Roles::FULL, 0).encode()).ok();
+			protocol.propagate_extrinsics(&mut io);
+		}
+		let sent = queue.write().drain(..)
+			.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::Transactions(_)) => true,
+				_ => false,
+			})
+			.count();
+		(sent, protocol)
+	};
+
+	let (sent, before_restart) = run(None, 1);
+	assert_eq!(sent, 1);
+	let snapshot = before_restart.export_propagation_state();
+	assert!(snapshot.peers[&peer_id].extrinsics.contains(&BlakeTwo256::hash_of(&transfer(0))));
+
+	// The peer reconnects under a different index after the restart.
+	let (sent, _) = run(Some(snapshot), 5);
+	assert_eq!(sent, 0);
+	let (sent, _) = run(None, 5);
+	assert_eq!(sent, 1);
+}
+
+#[test]
+fn reports_unsolicited_responses_once() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	let unsolicited: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id: 0,
+		blocks: vec![],
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	});
+
+	protocol.handle_packet(&mut io, 1, &unsolicited.encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+	io.to_disconnect.clear();
+	for _ in 0..10 {
+		protocol.handle_packet(&mut io, 1, &unsolicited.encode()).ok();
+	}
+	assert!(io.to_disconnect.is_empty());
+	assert_eq!(protocol.metrics().get("responses.unsolicited_dropped"), Some(&10));
+}
+
+#[test]
+fn skips_blocks_queued_while_the_response_was_in_flight() {
+	::env_logger::init().ok();
+	let client = Arc::new(test_client::new());
+	let import_queue = Arc::new(RecordingImportQueue::default());
+	let protocol: Protocol<Block, DummySpecialization, Hash> = Protocol::new(
+		ProtocolConfig::default(),
+		client.clone(),
+		import_queue.clone(),
+		None,
+		Arc::new(EmptyTransactionPool),
+		DummySpecialization { },
+	).unwrap();
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 2).encode()).ok();
+
+	let first = Header::new(1, Default::default(), Default::default(), genesis, Default::default());
+	let second = Header::new(2, Default::default(), Default::default(), first.hash(), Default::default());
+	// the first block is queued from another source, e.g. an announcement
+	import_queue.importing.write().insert(first.hash());
+
+	let block_data = |header: Header| ::message::generic::BlockData {
+		hash: header.hash(),
+		header: Some(header),
+		body: None,
+		receipt: None,
+		message_queue: None,
+		justification: None,
+		compact_body: None,
+	};
+	let response: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id: 0,
+		blocks: vec![block_data(first), block_data(second.clone())],
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	});
+	protocol.handle_packet(&mut io, 1, &response.encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	let imported: Vec<Hash> = import_queue.blocks.read().iter().map(|b| b.block.hash).collect();
+	assert_eq!(imported, vec![second.hash()]);
+	assert_eq!(protocol.metrics().get("blocks.already_queued"), Some(&1));
+}
+
+#[test]
+fn rejects_incomplete_blocks_when_required() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { require_complete_blocks: true, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 1).encode()).ok();
+
+	let header = Header::new(1, Default::default(), Default::default(), genesis, Default::default());
+	let response: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id: 0,
+		blocks: vec![::message::generic::BlockData {
+			hash: header.hash(),
+			header: Some(header),
+			body: None,
+			receipt: None,
+			message_queue: None,
+			justification: None,
+			compact_body: None,
+		}],
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	});
+	protocol.handle_packet(&mut io, 1, &response.encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+	assert_eq!(protocol.metrics().get("responses.incomplete"), Some(&1));
+	drop(io);
+
+	let request = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(r)) => Some(r),
+			_ => None,
+		})
+		.next()
+		.expect("Blocks are requested from the new peer");
+	assert!(request.fields.contains(BlockAttributes::HEADER | BlockAttributes::BODY));
+}
+
+#[test]
+fn adapts_served_blocks_to_peer_speed() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { min_block_response_len: 16, max_block_response_len: 512, ..Default::default() };
+	let mut net = TestNet::from_config(&config);
+	net.add_peer(&config);
+	let peer = net.peer(0);
+	peer.push_blocks(600, false);
+	let queue = RwLock::new(VecDeque::new());
+	let served = |who: NodeIndex, send_queue_len: usize, requests: usize| {
+		let mut io = TestIo::new(&queue, None);
+		io.send_queue_lens.insert(who, send_queue_len);
+		peer.sync.handle_packet(&mut io, who, &status_from(&peer.client, Roles::FULL, 0).encode()).ok();
+		for _ in 0..requests {
+			peer.sync.handle_packet(&mut io, who, &block_request(BlockAttributes::HEADER, 1, Direction::Ascending, None).encode()).ok();
+		}
+		drop(io);
+		queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponse(r)) => Some(r.blocks.len()),
+				_ => None,
+			})
+			.collect::<Vec<_>>()
+	};
+
+	// Our responses to peer 1 are sent right away, those to peer 2 pile up.
+	assert_eq!(served(1, 0, 3), vec![256, 512, 512]);
+	assert_eq!(served(2, 10, 4), vec![64, 32, 16, 16]);
+}
+
+#[test]
+fn notifies_peers_of_pruning() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let notified = |f: &Fn(&mut TestIo)| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			f(&mut io);
+		}
+		queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::PruningNotification(n)) => Some((p.recipient, n.available_from)),
+				_ => None,
+			})
+			.collect::<Vec<_>>()
+	};
+
+	assert!(notified(&|io: &mut TestIo| { protocol.handle_packet(io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok(); }).is_empty());
+	assert_eq!(notified(&|io: &mut TestIo| protocol.on_blocks_pruned(io, 100)), vec![(1, 100)]);
+	// small advances are not worth a notification
+	assert!(notified(&|io: &mut TestIo| protocol.on_blocks_pruned(io, 150)).is_empty());
+	assert_eq!(notified(&|io: &mut TestIo| protocol.on_blocks_pruned(io, 400)), vec![(1, 400)]);
+	// peers connecting later learn the boundary after the handshake
+	assert_eq!(notified(&|io: &mut TestIo| { protocol.handle_packet(io, 2, &status_from(&client, Roles::FULL, 0).encode()).ok(); }), vec![(2, 400)]);
+
+	let notification: Message<Block> = GenericMessage::PruningNotification(::message::generic::PruningNotification { available_from: 50 });
+	assert_eq!(protocol.peer_available_from(1), Some(0));
+	notified(&|io: &mut TestIo| { protocol.handle_packet(io, 1, &notification.encode()).ok(); });
+	assert_eq!(protocol.peer_available_from(1), Some(50));
+}
+
+/// Specialization recording the peers on another chain routed to it.
+#[derive(Default)]
+struct BridgeSpecialization {
+	foreign_peers: Arc<RwLock<Vec<NodeIndex>>>,
+}
+
+impl NetworkSpecialization<Block> for BridgeSpecialization {
+	fn status(&self) -> Vec<u8> { vec![] }
+
+	fn on_connect(&mut self, _ctx: &mut Context<Block>, _who: NodeIndex, _status: ::message::Status<Block>) {
+	}
+
+	fn on_foreign_connect(&mut self, _ctx: &mut Context<Block>, who: NodeIndex, _status: ::message::Status<Block>) {
+		self.foreign_peers.write().push(who);
+	}
+
+	fn on_disconnect(&mut self, _ctx: &mut Context<Block>, _who: NodeIndex) {
+	}
+
+	fn on_message(&mut self, _ctx: &mut Context<Block>, _who: NodeIndex, _message: &mut Option<::message::Message<Block>>) {
+	}
+}
+
+#[test]
+fn routes_peers_on_other_chains_to_specialization() {
+	::env_logger::init().ok();
+	let client = Arc::new(test_client::new());
+	let specialization = BridgeSpecialization::default();
+	let foreign_peers = specialization.foreign_peers.clone();
+	let protocol: Protocol<Block, BridgeSpecialization, Hash> = Protocol::new(
+		ProtocolConfig {
+			genesis_mismatch_handler: Some(Arc::new(|_: &PeerId, _: &[u8]| GenesisMismatchAction::RouteToSpecialization)),
+			..Default::default()
+		},
+		client.clone(),
+		Arc::new(RecordingImportQueue::default()),
+		None,
+		Arc::new(EmptyTransactionPool),
+		specialization,
+	).unwrap();
+	let (peer_id, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let mut foreign_status = status_from(&client, Roles::FULL, 0);
+	if let GenericMessage::Status(ref mut status) = foreign_status {
+		status.genesis_hash = [7; 32].into();
+	}
+
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	io.peer_ids.insert(1, peer_id);
+	protocol.handle_packet(&mut io, 1, &foreign_status.encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	assert_eq!(*foreign_peers.read(), vec![1]);
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 1).is_none());
+
+	// without a known peer ID, the handler can't be consulted and the peer is disabled
+	protocol.handle_packet(&mut io, 2, &foreign_status.encode()).ok();
+	assert!(io.to_disconnect.contains(&2));
+	assert_eq!(*foreign_peers.read(), vec![1]);
+}
+
+
+/// Records the chain-specific messages it receives, and greets connecting peers with one.
+#[derive(Default)]
+struct RecordingSpecialization {
+	received: Arc<RwLock<Vec<Vec<u8>>>>,
+}
+
+impl NetworkSpecialization<Block> for RecordingSpecialization {
+	fn status(&self) -> Vec<u8> { vec![] }
+
+	fn on_connect(&mut self, ctx: &mut Context<Block>, who: NodeIndex, _status: ::message::Status<Block>) {
+		ctx.send_message(who, GenericMessage::ChainSpecific(vec![0xff]));
+	}
+
+	fn on_disconnect(&mut self, _ctx: &mut Context<Block>, _who: NodeIndex) {
+	}
+
+	fn on_message(&mut self, _ctx: &mut Context<Block>, _who: NodeIndex, message: &mut Option<::message::Message<Block>>) {
+		if let Some(GenericMessage::ChainSpecific(data)) = message.take() {
+			self.received.write().push(data);
+		}
+	}
+}
+
+#[test]
+fn multiplexes_chain_specific_messages_between_specializations() {
+	::env_logger::init().ok();
+	let client = Arc::new(test_client::new());
+	let (first, second) = (RecordingSpecialization::default(), RecordingSpecialization::default());
+	let (first_received, second_received) = (first.received.clone(), second.received.clone());
+	let protocol: Protocol<Block, MultiplexedSpecialization<Block>, Hash> = Protocol::new(
+		ProtocolConfig::default(),
+		client.clone(),
+		Arc::new(RecordingImportQueue::default()),
+		None,
+		Arc::new(EmptyTransactionPool),
+		MultiplexedSpecialization::new().with(1, first).with(2, second),
+	).unwrap();
+
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		for data in vec![vec![1, 10], vec![2, 20], vec![1, 11], vec![3, 30]] {
+			let message: Message<Block> = GenericMessage::ChainSpecific(data);
+			protocol.handle_packet(&mut io, 1, &message.encode()).ok();
+		}
+		assert!(io.to_disconnect.is_empty());
+	}
+	assert_eq!(*first_received.read(), vec![vec![10], vec![11]]);
+	assert_eq!(*second_received.read(), vec![vec![20]]);
+
+	// messages sent by the specializations are tagged with their sub-protocol
+	let greetings: Vec<Vec<u8>> = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::ChainSpecific(data)) => Some(data),
+			_ => None,
+		})
+		.collect();
+	assert_eq!(greetings, vec![vec![1, 0xff], vec![2, 0xff]]);
+}
+#[test]
+fn summarizes_peer_session_on_disconnect() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	let peer = net.peer(0);
+	peer.push_blocks(10, false);
+	let status = status_from(&peer.client, Roles::FULL, 0).encode();
+	let request = block_request(BlockAttributes::HEADER, 1, Direction::Ascending, Some(5)).encode();
+	let queue = RwLock::new(VecDeque::new());
+	let session = {
+		let mut io = TestIo::new(&queue, None);
+		peer.sync.handle_packet(&mut io, 1, &status).ok();
+		peer.sync.handle_packet(&mut io, 1, &request).ok();
+		peer.sync.on_peer_disconnected(&mut io, 1).expect("Peer 1 has completed the handshake")
+	};
+	let sent: usize = queue.write().drain(..).filter(|p| p.recipient == 1).map(|p| p.data.len()).sum();
+
+	assert_eq!(session.bytes_received, (status.len() + request.len()) as u64);
+	assert_eq!(session.bytes_sent, sent as u64);
+	assert_eq!(session.blocks_served, 5);
+	assert_eq!(session.blocks_received, 0);
+	let summary = session.to_string();
+	for field in &["duration=", "bytes_received=", "bytes_sent=", "blocks_served=5", "blocks_received=0"] {
+		assert!(summary.contains(field), "{} is missing from {}", field, summary);
+	}
+
+	// the session of the peer is over
+	let mut io = TestIo::new(&queue, None);
+	assert!(peer.sync.on_peer_disconnected(&mut io, 1).is_none());
+}
+
+#[test]
+fn requests_headers_first_when_configured() {
+	::env_logger::init().ok();
+	let requested_fields = |config: ProtocolConfig| {
+		let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+		let queue = RwLock::new(VecDeque::new());
+		{
+			let mut io = TestIo::new(&queue, None);
+			protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+		}
+		let fields: Vec<BlockAttributes> = queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockRequest(r)) => Some(r.fields),
+				_ => None,
+			})
+			.collect();
+		fields
+	};
+
+	let fields = requested_fields(ProtocolConfig { headers_first_sync: true, ..Default::default() });
+	assert_eq!(fields.len(), 1);
+	assert!(fields[0].contains(BlockAttributes::HEADER));
+	assert!(!fields[0].contains(BlockAttributes::BODY));
+
+	let fields = requested_fields(ProtocolConfig::default());
+	assert_eq!(fields.len(), 1);
+	assert!(fields[0].contains(BlockAttributes::BODY));
+}
+
+fn header_data(header: Header) -> ::message::BlockData<Block> {
+	::message::generic::BlockData {
+		hash: header.hash(),
+		header: Some(header),
+		body: Some(vec![]),
+		receipt: None,
+		message_queue: None,
+		justification: None,
+		compact_body: None,
+	}
+}
+
+fn headers_response(id: ::message::RequestId, headers: Vec<Header>) -> Message<Block> {
+	GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id,
+		blocks: headers.into_iter().map(header_data).collect(),
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	})
+}
+
+#[test]
+fn refuses_skeleton_not_linked_to_our_best_block() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { headers_first_sync: true, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 2).encode()).ok();
+
+	// the first header is linked to an unknown parent instead of our genesis
+	let first = Header::new(1, Default::default(), Default::default(), [7u8; 32].into(), Default::default());
+	let second = Header::new(2, Default::default(), Default::default(), first.hash(), Default::default());
+	protocol.handle_packet(&mut io, 1, &headers_response(0, vec![first, second]).encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+}
+
+/// Protocol syncing headers first from peer 1, which sent the skeleton of blocks #1 and #2.
+fn protocol_with_skeleton() -> (Arc<PeersClient>, Protocol<Block, DummySpecialization, Hash>, RwLock<VecDeque<TestPacket>>) {
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { headers_first_sync: true, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status_ahead_of(&client, 2).encode()).ok();
+	let request = last_request_in(&queue);
+	assert!(!request.fields.contains(BlockAttributes::BODY));
+
+	let first = Header::new(1, Default::default(), Default::default(), genesis, Default::default());
+	let second = Header::new(2, Default::default(), Default::default(), first.hash(), Default::default());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &headers_response(request.id, vec![first, second]).encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	drop(io);
+	(client, protocol, queue)
+}
+
+fn last_request_in(queue: &RwLock<VecDeque<TestPacket>>) -> ::message::BlockRequest<Block> {
+	queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(r)) => Some(r),
+			_ => None,
+		})
+		.last()
+		.expect("A block request has been sent")
+}
+
+#[test]
+fn requests_bodies_of_another_fork_than_the_skeleton_from_other_peers() {
+	::env_logger::init().ok();
+	let (client, protocol, queue) = protocol_with_skeleton();
+	let genesis = client.info().unwrap().chain.genesis_hash;
+
+	// bodies are now requested for the skeleton, but the peer sends the blocks of another fork
+	let request = last_request_in(&queue);
+	assert!(request.fields.contains(BlockAttributes::BODY));
+	let fork = Header::new(1, Default::default(), [2u8; 32].into(), genesis, Default::default());
+	let fork_child = Header::new(2, Default::default(), Default::default(), fork.hash(), Default::default());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &headers_response(request.id, vec![fork, fork_child]).encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+	drop(io);
+
+	// the bodies are requested from the next peer
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 2, &status_ahead_of(&client, 2).encode()).ok();
+	let requests: Vec<(NodeIndex, ::message::BlockRequest<Block>)> = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(r)) => Some((p.recipient, r)),
+			_ => None,
+		})
+		.collect();
+	assert_eq!(requests.len(), 1);
+	assert_eq!(requests[0].0, 2);
+	assert!(requests[0].1.fields.contains(BlockAttributes::BODY));
+	assert_eq!(requests[0].1.from, FromBlock::Number(1));
+}
+
+#[test]
+fn rebuilds_the_skeleton_after_importing_a_block_not_on_it() {
+	::env_logger::init().ok();
+	let (client, protocol, queue) = protocol_with_skeleton();
+	let request = last_request_in(&queue);
+	assert!(request.fields.contains(BlockAttributes::BODY));
+
+	// another block #1 than the one of the skeleton becomes our best block
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let header = client.header(&BlockId::Number(1)).unwrap().unwrap();
+	protocol.on_block_imported(&mut TestIo::new(&queue, None), header.hash(), &header);
+	queue.write().clear();
+
+	// once the peer answers, the missing headers are downloaded again on top of our best block
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &headers_response(request.id, vec![]).encode()).ok();
+	let request = last_request_in(&queue);
+	assert_eq!(request.fields, BlockAttributes::HEADER);
+	assert_eq!(request.from, FromBlock::Number(2));
+}
+
+#[test]
+fn requests_consensus_blocks_ahead_of_bulk_sync() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let last_request = || queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(r)) => Some(r),
+			_ => None,
+		})
+		.last()
+		.expect("A block request has been sent");
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 10).encode()).ok();
+	}
+	let mut request = last_request();
+	assert_eq!(request.from, FromBlock::Number(1));
+
+	// the peer is busy with a bulk request, so the priority requests are queued
+	let hashes: Vec<Hash> = (0..5u8).map(|i| [10 + i; 32].into()).collect();
+	{
+		let mut io = TestIo::new(&queue, None);
+		for hash in &hashes {
+			protocol.request_priority_block(&mut io, *hash, 3);
+		}
+	}
+	assert!(queue.read().is_empty());
+
+	let mut requested = Vec::new();
+	for _ in 0..6 {
+		{
+			let mut io = TestIo::new(&queue, None);
+			let response: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+				id: request.id,
+				blocks: vec![],
+				finality_proof: None,
+				busy: false,
+				fork_divergence: None,
+			});
+			protocol.handle_packet(&mut io, 1, &response.encode()).ok();
+		}
+		request = last_request();
+		requested.push(request.from.clone());
+	}
+
+	// priority requests go first, but bulk sync gets a turn after a streak of them
+	let mut expected: Vec<_> = hashes[..4].iter().map(|h| FromBlock::Hash(*h)).collect();
+	expected.push(FromBlock::Number(1));
+	expected.push(FromBlock::Hash(hashes[4]));
+	assert_eq!(requested, expected);
+}
+
+#[test]
+fn rejects_oversized_consensus_messages() {
+	use futures::{Future, Stream};
+
+	::env_logger::init().ok();
+	let config = ProtocolConfig { max_consensus_message_size: Some(16), ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::AUTHORITY, 0).encode()).ok();
+	protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::AUTHORITY, 0).encode()).ok();
+	let topic: Hash = [1u8; 32].into();
+	let messages = protocol.consensus_gossip().write().messages_for(topic);
+
+	let oversized: Message<Block> = GenericMessage::Consensus(topic, vec![1; 17]);
+	protocol.handle_packet(&mut io, 1, &oversized.encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+	assert_eq!(protocol.metrics().get("messages.oversized_consensus"), Some(&1));
+
+	let accepted: Message<Block> = GenericMessage::Consensus(topic, vec![2; 16]);
+	protocol.handle_packet(&mut io, 2, &accepted.encode()).ok();
+	assert!(!io.to_disconnect.contains(&2));
+	let (first, _) = messages.into_future().wait().ok().unwrap();
+	assert_eq!(first, Some(vec![2; 16]));
+}
+
+#[test]
+fn refuses_banned_nodes_until_the_ban_expires() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let (peer_id, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let connect = |who: NodeIndex| {
+		let mut io = TestIo::new(&queue, None);
+		io.peer_ids.insert(who, peer_id.clone());
+		protocol.on_peer_connected(&mut io, who);
+		protocol.handle_packet(&mut io, who, &status_from(&client, Roles::FULL, who as u64).encode()).ok();
+		io.to_disconnect.clone()
+	};
+	assert!(connect(1).is_empty());
+
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.ban_node(&mut io, peer_id.clone(), Duration::from_millis(200));
+		assert!(io.to_disconnect.contains(&1));
+		protocol.on_peer_disconnected(&mut io, 1);
+	}
+	assert_eq!(protocol.banned_nodes().into_iter().map(|(id, _)| id).collect::<Vec<_>>(), vec![peer_id.clone()]);
+	assert!(connect(2).contains(&2));
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 2).is_none());
+
+	thread::sleep(Duration::from_millis(250));
+	assert!(protocol.banned_nodes().is_empty());
+	assert!(connect(3).is_empty());
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 3).is_some());
+}
+
+#[test]
+fn serves_higher_scoring_peers_first_over_the_response_budget() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { block_response_budget: Some(1), ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let responded = |f: &Fn(&mut TestIo)| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			f(&mut io);
+		}
+		queue.write().drain(..)
+			.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponse(_)) => true,
+				_ => false,
+			})
+			.map(|p| p.recipient)
+			.collect::<Vec<_>>()
+	};
+	let request = block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(1));
+
+	responded(&|io| {
+		protocol.handle_packet(io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		protocol.handle_packet(io, 2, &status_from(&client, Roles::AUTHORITY, 0).encode()).ok();
+		protocol.handle_packet(io, 3, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	});
+	// the first response spends the budget, the next requests are deferred
+	assert_eq!(responded(&|io| { protocol.handle_packet(io, 3, &request.encode()).ok(); }), vec![3]);
+	assert_eq!(responded(&|io| {
+		protocol.handle_packet(io, 1, &request.encode()).ok();
+		protocol.handle_packet(io, 2, &request.encode()).ok();
+	}), Vec::<NodeIndex>::new());
+	assert_eq!(protocol.metrics().get("requests.deferred"), Some(&2));
+
+	// the authority is served first, the other peer on the following tick
+	assert_eq!(responded(&|io| { protocol.tick(io); }), vec![2]);
+	assert_eq!(responded(&|io| { protocol.tick(io); }), vec![1]);
+}
+
+#[test]
+fn says_goodbye_on_graceful_disconnect() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	}
+	queue.write().clear();
+
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.disconnect_peer(&mut io, 1, GoodbyeReason::Useless);
+		assert!(io.to_disconnect.contains(&1));
+	}
+	let sent: Vec<_> = queue.write().drain(..)
+		.map(|p| (p.recipient, <Message<Block> as Decode>::decode(&mut &p.data[..])))
+		.collect();
+	assert_eq!(sent, vec![(1, Some(GenericMessage::Goodbye(GoodbyeReason::Useless)))]);
+
+	// the reason given by a leaving peer is part of its session summary
+	let mut io = TestIo::new(&queue, None);
+	let goodbye: Message<Block> = GenericMessage::Goodbye(GoodbyeReason::Shutdown);
+	protocol.handle_packet(&mut io, 2, &goodbye.encode()).ok();
+	let session = protocol.on_peer_disconnected(&mut io, 2).expect("Peer completed the handshake");
+	assert_eq!(session.goodbye, Some(GoodbyeReason::Shutdown));
+	assert!(session.to_string().contains("goodbye=Shutdown"));
+	let session = protocol.on_peer_disconnected(&mut io, 1).expect("Peer completed the handshake");
+	assert_eq!(session.goodbye, None);
+	assert!(!io.to_disconnect.contains(&2));
+}
+
+#[test]
+fn counts_connected_and_active_peers() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	assert_eq!((protocol.peer_count(), protocol.active_peer_count()), (0, 0));
+
+	// we request blocks from the peer which is ahead of us only
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	assert_eq!((protocol.peer_count(), protocol.active_peer_count()), (2, 1));
+	let status = protocol.status();
+	assert_eq!((status.num_peers, status.num_active_peers), (2, 1));
+
+	protocol.on_peer_disconnected(&mut io, 1);
+	assert_eq!((protocol.peer_count(), protocol.active_peer_count()), (1, 0));
+	protocol.on_peer_disconnected(&mut io, 2);
+	assert_eq!((protocol.peer_count(), protocol.active_peer_count()), (0, 0));
+}
+
+#[test]
+fn snapshots_protocol_state_for_debugging() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 10).encode()).ok();
+		protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	}
+
+	let snapshot = protocol.debug_snapshot();
+	assert_eq!(snapshot.peers.iter().map(|p| p.index).collect::<Vec<_>>(), vec![1, 2]);
+	assert_eq!(snapshot.outstanding_requests, 1);
+	assert!(snapshot.peers[0].pending_request.is_some());
+	assert_eq!(snapshot.peers[0].best_number, 10);
+	assert!(snapshot.peers.iter().all(|p| p.bytes_received > 0));
+
+	let json = ::serde_json::to_value(&snapshot).unwrap();
+	for section in &["peers", "sync", "outstanding_requests", "propagated_extrinsics", "metrics"] {
+		assert!(json.get(section).is_some(), "missing section {}", section);
+	}
+	assert_eq!(json["sync"]["state"], "Downloading");
+	assert!(!json.to_string().contains("session_nonce"));
+}
+
+#[test]
+fn penalizes_higher_best_number_with_same_hash() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+
+	// a restarted peer reports a higher best block under its previous best hash
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	let mut status = status_ahead_of(&client, 6);
+	if let GenericMessage::Status(ref mut status) = status {
+		status.session_nonce = 1;
+	}
+	protocol.handle_packet(&mut io, 1, &status.encode()).ok();
+	assert!(io.to_disconnect.contains(&1));
+
+	// a peer announces a block above its best block, which has the same hash
+	let header = Header::new(2, Default::default(), Default::default(), [3u8; 32].into(), Default::default());
+	let mut status = status_ahead_of(&client, 1);
+	if let GenericMessage::Status(ref mut status) = status {
+		status.best_hash = header.hash();
+	}
+	protocol.handle_packet(&mut io, 2, &status.encode()).ok();
+	assert!(!io.to_disconnect.contains(&2));
+	let announce: Message<Block> = GenericMessage::BlockAnnounce(BlockAnnounce { header });
+	protocol.handle_packet(&mut io, 2, &announce.encode()).ok();
+	assert!(io.to_disconnect.contains(&2));
+}
+
+#[test]
+fn delivers_block_announcements_to_sync_in_batches() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { announce_batch_size: Some(4), ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	let block_requests = || queue.write().drain(..)
+		.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(_)) => true,
+			_ => false,
+		})
+		.count();
+	let announce = |io: &mut TestIo, who: NodeIndex, i: u8| {
+		let header = Header::new(1, Default::default(), [i; 32].into(), genesis, Default::default());
+		let announce: Message<Block> = GenericMessage::BlockAnnounce(BlockAnnounce { header });
+		protocol.handle_packet(io, who, &announce.encode()).ok();
+	};
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		announce(&mut io, 1, 1);
+		announce(&mut io, 2, 2);
+		announce(&mut io, 1, 3);
+	}
+	assert_eq!(block_requests(), 0);
+	assert_eq!(protocol.metrics().get("sync.announce_batches"), None);
+
+	// the buffered announcements are delivered on the next tick
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.tick(&mut io);
+	}
+	assert!(block_requests() > 0);
+	assert_eq!(protocol.metrics().get("sync.announce_batches"), Some(&1));
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.tick(&mut io);
+	}
+	assert_eq!(protocol.metrics().get("sync.announce_batches"), Some(&1));
+
+	// or as soon as the buffer is full
+	{
+		let mut io = TestIo::new(&queue, None);
+		for i in 4..8 {
+			announce(&mut io, 1, i);
+		}
+	}
+	assert_eq!(protocol.metrics().get("sync.announce_batches"), Some(&2));
+}
+
+#[test]
+fn serves_repeated_block_requests_from_cache() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	let peer = net.peer(0);
+	peer.push_blocks(3, true);
+	let queue = RwLock::new(VecDeque::new());
+	let served_bodies = |who: NodeIndex| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			peer.sync.handle_packet(&mut io, who, &status_from(&peer.client, Roles::FULL, 0).encode()).ok();
+			peer.sync.handle_packet(&mut io, who, &block_request(BlockAttributes::HEADER | BlockAttributes::BODY, 1, Direction::Ascending, Some(3)).encode()).ok();
+		}
+		queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponse(r)) => Some(r.blocks.into_iter().map(|b| b.body).collect::<Vec<_>>()),
+				_ => None,
+			})
+			.next()
+			.expect("Block request is answered")
+	};
+
+	let first = served_bodies(1);
+	assert_eq!(first.len(), 3);
+	assert!(first.iter().all(|body| body.is_some()));
+	assert_eq!(peer.sync.metrics().get("block_cache.hits"), None);
+	assert_eq!(served_bodies(2), first);
+	assert_eq!(peer.sync.metrics().get("block_cache.hits"), Some(&3));
+}
+
+#[test]
+fn omits_block_attributes_not_configured_to_be_served() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig {
+		servable_block_attributes: BlockAttributes::all() - BlockAttributes::JUSTIFICATION,
+		..Default::default()
+	};
+	let mut net = TestNet::from_config(&config);
+	net.add_peer(&config);
+	let peer = net.peer(0);
+	peer.push_blocks(3, false);
+	peer.client.finalize_block(BlockId::Number(2), true).unwrap();
+	assert!(peer.client.justification(&BlockId::Number(2)).unwrap().is_some());
+
+	peer.receive(1, block_request(BlockAttributes::HEADER | BlockAttributes::JUSTIFICATION, 1, Direction::Ascending, Some(2)));
+	let response = block_response(peer);
+	assert_eq!(response.blocks.len(), 2);
+	assert!(response.blocks.iter().all(|b| b.header.is_some() && b.justification.is_none()));
+	assert_eq!(response.finality_proof, None);
+	assert_eq!(peer.sync.metrics().get("requests.unservable_attributes"), Some(&1));
+}
+
+/// Pool returning the same hash for every transaction, keeping the last one imported.
+struct CollidingTransactionPool(RwLock<Option<Extrinsic>>);
+
+impl TransactionPool<Hash, Block> for CollidingTransactionPool {
+	fn transactions(&self) -> Vec<(Hash, Extrinsic)> {
+		self.0.read().iter().map(|t| (Default::default(), t.clone())).collect()
+	}
+
+	fn import(&self, _reputation: i32, transaction: &Extrinsic) -> Option<Hash> {
+		*self.0.write() = Some(transaction.clone());
+		Some(Default::default())
+	}
+
+	fn on_broadcasted(&self, _: HashMap<Hash, Vec<String>>) {}
+}
+
+#[test]
+fn detects_transaction_pool_hash_collisions() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(CollidingTransactionPool(RwLock::new(None))));
+	let queue = RwLock::new(VecDeque::new());
+	let propagated = || {
+		{
+			let mut io = TestIo::new(&queue, None);
+			protocol.propagate_extrinsics(&mut io);
+		}
+		queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::Transactions(t)) => Some((p.recipient, t)),
+				_ => None,
+			})
+			.collect::<Vec<_>>()
+	};
+	let receive = |who: NodeIndex, message: Message<Block>| {
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, who, &message.encode()).ok();
+	};
+	receive(1, status_from(&client, Roles::FULL, 0));
+	receive(2, status_from(&client, Roles::FULL, 0));
+	receive(1, GenericMessage::Transactions(vec![transfer(0)]));
+	receive(1, GenericMessage::Transactions(vec![transfer(0)]));
+	assert_eq!(propagated(), vec![(2, vec![transfer(0)])]);
+	assert_eq!(protocol.metrics().get("extrinsics.hash_collisions"), None);
+
+	// peer 1 only knows the first transfer under the hash the pool gives the second one
+	receive(2, GenericMessage::Transactions(vec![transfer(1)]));
+	assert_eq!(protocol.metrics().get("extrinsics.hash_collisions"), Some(&1));
+	assert_eq!(propagated(), vec![(1, vec![transfer(1)])]);
+}
+
+#[test]
+fn maintains_peers_incrementally_across_ticks() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { peers_maintained_per_tick: Some(4), ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let start = Instant::now();
+	{
+		// each peer is asked for another range of its blocks
+		let mut io = TestIo::new(&queue, None);
+		for who in 0..10 {
+			protocol.handle_packet(&mut io, who, &status_ahead_of(&client, 10_000).encode()).ok();
+		}
+	}
+	assert_eq!(protocol.active_peer_count(), 10);
+
+	let later = start + ProtocolConfig::default().max_block_request_timeout + Duration::from_secs(1);
+	let timed_out = || {
+		let mut io = TestIo::new(&queue, None);
+		protocol.maintain_peers_at(&mut io, later);
+		let mut reported: Vec<NodeIndex> = io.to_disconnect.iter().cloned().collect();
+		reported.sort();
+		reported
+	};
+	assert_eq!(timed_out(), vec![0, 1, 2, 3]);
+	assert_eq!(timed_out(), vec![4, 5, 6, 7]);
+	// peers 0 and 1 were already reported
+	assert_eq!(timed_out(), vec![8, 9]);
+}
+
+#[test]
+fn reports_stalled_sync() {
+	::env_logger::init().ok();
+	let sync_state = |config: ProtocolConfig, best_number: u64| {
+		let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+		let queue = RwLock::new(VecDeque::new());
+		let mut io = TestIo::new(&queue, None);
+		// the peer never answers our requests
+		protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, best_number).encode()).ok();
+		protocol.status().sync.state
+	};
+	let stalling = || ProtocolConfig { sync_stall_timeout: Some(Duration::from_secs(0)), ..Default::default() };
+
+	assert_eq!(sync_state(stalling(), 10_000), SyncState::Stalled);
+	assert_eq!(sync_state(ProtocolConfig::default(), 10_000), SyncState::Downloading);
+	assert_eq!(sync_state(ProtocolConfig { sync_stall_timeout: None, ..stalling() }, 10_000), SyncState::Downloading);
+	// not being behind is never a stall
+	assert_eq!(sync_state(stalling(), 1), SyncState::Idle);
+}
+
+#[test]
+fn injected_block_announce_is_followed_by_request() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let (peer_id, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let header = Header::new(1, Default::default(), Default::default(), genesis, Default::default());
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		assert!(!protocol.inject_block_announce(&mut io, &peer_id, BlockAnnounce { header: header.clone() }));
+		io.peer_ids.insert(1, peer_id.clone());
+		protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		assert!(protocol.inject_block_announce(&mut io, &peer_id, BlockAnnounce { header: header.clone() }));
+	}
+	let requests: Vec<_> = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(r)) => Some((p.recipient, r.from)),
+			_ => None,
+		})
+		.collect();
+	assert_eq!(requests, vec![(1, FromBlock::Number(1))]);
+	assert_eq!(protocol.block_source(&header.hash()), Some(1));
+}
+
+#[test]
+fn drops_duplicate_messages_sent_through_a_context() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { dedup_context_messages: true, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let announce = |i: u8| -> Message<Block> {
+		let header = Header::new(1, Default::default(), [i; 32].into(), genesis, Default::default());
+		GenericMessage::BlockAnnounce(BlockAnnounce { header })
+	};
+	let queue = RwLock::new(VecDeque::new());
+	let send_all = || {
+		let mut io = TestIo::new(&queue, None);
+		protocol.with_spec(&mut io, |_, context| {
+			context.send_message(1, announce(1));
+			context.send_message(1, announce(1));
+			context.send_message(1, announce(2));
+			context.send_message(2, announce(1));
+		});
+	};
+
+	send_all();
+	let sent: Vec<_> = queue.write().drain(..)
+		.map(|p| (p.recipient, <Message<Block> as Decode>::decode(&mut &p.data[..]).unwrap()))
+		.collect();
+	assert_eq!(sent, vec![(1, announce(1)), (1, announce(2)), (2, announce(1))]);
+	assert_eq!(protocol.metrics().get("context.duplicates_dropped"), Some(&1));
+
+	// messages are only compared within a context
+	send_all();
+	assert_eq!(queue.write().drain(..).count(), 3);
+}
+
+#[test]
+fn does_not_serve_a_block_zero_that_is_not_our_genesis() {
+	::env_logger::init().ok();
+	let served_blocks = |forged_genesis| {
+		let inner = Arc::new(test_client::new());
+		let client = Arc::new(FlakyClient { inner: inner.clone(), failures: AtomicUsize::new(0), forged_genesis });
+		let import_queue = Arc::new(SyncImportQueue::new(Arc::new(PassThroughVerifier(false)), inner.clone()));
+		let protocol = Protocol::new(
+			ProtocolConfig::default(),
+			client,
+			import_queue,
+			None,
+			Arc::new(EmptyTransactionPool),
+			DummySpecialization { },
+		).unwrap();
+		let queue = RwLock::new(VecDeque::new());
+		{
+			let mut io = TestIo::new(&queue, None);
+			protocol.handle_packet(&mut io, 1, &status_from(&inner, Roles::FULL, 0).encode()).ok();
+			protocol.handle_packet(&mut io, 1, &block_request(BlockAttributes::HEADER, 0, Direction::Descending, Some(2)).encode()).ok();
+		}
+		let blocks = queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponse(r)) => Some(r.blocks.len()),
+				_ => None,
+			})
+			.next()
+			.expect("A block response has been sent");
+		(blocks, protocol.metrics().get("requests.wrong_genesis").cloned())
+	};
+
+	assert_eq!(served_blocks(None), (1, None));
+	let forged = Header::new(0, Default::default(), Default::default(), Default::default(), Default::default());
+	assert_eq!(served_blocks(Some(forged)), (0, Some(1)));
+}
+
+#[test]
+fn reports_transactions_imported_from_peers_to_the_hook() {
+	::env_logger::init().ok();
+	let imported = Arc::new(RwLock::new(Vec::new()));
+	let hook_imported = imported.clone();
+	let config = ProtocolConfig {
+		on_transaction_imported_hook: Some(Arc::new(move |hash: &[u8], source: PeerId| hook_imported.write().push((hash.to_vec(), source)))),
+		..Default::default()
+	};
+	let pool = Arc::new(BoundedTransactionPool { capacity: 2, retained: RwLock::new(Vec::new()) });
+	let (client, protocol) = protocol_with_pool(config, pool);
+	let (first, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let (second, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmNWVCgizRFj96AkPv2Zi2MHUnSDBTF3y9eMsa3cJgAJV7").unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	io.peer_ids.insert(1, first.clone());
+	io.peer_ids.insert(2, second.clone());
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 1).encode()).ok();
+	let hash = |nonce| BlakeTwo256::hash_of(&transfer(nonce)).as_ref().to_vec();
+
+	protocol.handle_packet(&mut io, 2, &GenericMessage::Transactions(vec![transfer(0)]).encode()).ok();
+	// the pool is full once the first of these is imported and rejects the other one
+	protocol.handle_packet(&mut io, 1, &GenericMessage::Transactions(vec![transfer(1), transfer(2)]).encode()).ok();
+	assert_eq!(*imported.read(), vec![(hash(0), second), (hash(1), first)]);
+}
+
+#[test]
+fn encodes_messages_for_the_protocol_version_of_each_peer() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { batch_messages: true, ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let sent = |f: &Fn(&mut TestIo)| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			f(&mut io);
+		}
+		queue.write().drain(..)
+			.map(|p| (p.recipient, <Message<Block> as Decode>::decode(&mut &p.data[..]).unwrap()))
+			.collect::<Vec<_>>()
+	};
+	sent(&|io| {
+		let old_status = match status_from(&client, Roles::FULL, 0) {
+			GenericMessage::Status(status) => GenericMessage::Status(Status { version: MIN_VERSION, ..status }),
+			_ => unreachable!(),
+		};
+		protocol.handle_packet(io, 1, &old_status.encode()).ok();
+		protocol.handle_packet(io, 2, &status_from(&client, Roles::FULL, 1).encode()).ok();
+	});
+	assert_eq!(protocol.peer_info(&TestIo::new(&queue, None), 1).unwrap().protocol_version, MIN_VERSION);
+	assert_eq!(protocol.peer_info(&TestIo::new(&queue, None), 2).unwrap().protocol_version, CURRENT_VERSION);
+
+	// batches are only understood by the peer using the current version
+	let messages = sent(&|io| protocol.with_spec(io, |_, context| {
+		for who in 1..3 {
+			context.send_message(who, GenericMessage::ChainSpecific(vec![1]));
+			context.send_message(who, GenericMessage::ChainSpecific(vec![2]));
+		}
+	}));
+	let chain_specific = |i| GenericMessage::ChainSpecific(vec![i]);
+	assert_eq!(messages, vec![
+		(1, chain_specific(1)),
+		(1, chain_specific(2)),
+		(2, GenericMessage::Batch(vec![chain_specific(1).encode(), chain_specific(2).encode()])),
+	]);
+
+	// and so are goodbyes
+	let messages = sent(&|io| {
+		protocol.disconnect_peer(io, 1, GoodbyeReason::Shutdown);
+		protocol.disconnect_peer(io, 2, GoodbyeReason::Shutdown);
+	});
+	assert_eq!(messages, vec![(2, GenericMessage::Goodbye(GoodbyeReason::Shutdown))]);
+}
+
+#[test]
+fn tick_skips_consensus_garbage_collection_while_gossip_is_locked() {
+	::env_logger::init().ok();
+	let (_, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let protocol = Arc::new(protocol);
+	let (locked_tx, locked_rx) = ::std::sync::mpsc::channel();
+	let (release_tx, release_rx) = ::std::sync::mpsc::channel::<()>();
+	let holder = {
+		let protocol = protocol.clone();
+		thread::spawn(move || {
+			let _gossip = protocol.consensus_gossip().write();
+			locked_tx.send(()).unwrap();
+			release_rx.recv().ok();
+		})
+	};
+	locked_rx.recv().unwrap();
+
+	let queue = RwLock::new(VecDeque::new());
+	let started = Instant::now();
+	protocol.tick(&mut TestIo::new(&queue, None));
+	assert!(started.elapsed() < Duration::from_secs(5));
+	assert_eq!(protocol.metrics().get("gossip.garbage_collection_skipped"), Some(&1));
+
+	release_tx.send(()).unwrap();
+	holder.join().unwrap();
+	protocol.tick(&mut TestIo::new(&queue, None));
+	assert_eq!(protocol.metrics().get("gossip.garbage_collection_skipped"), Some(&1));
+}
+
+#[test]
+fn serves_blocks_from_the_serving_chain_when_configured() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	net.peer(0).push_blocks(1, false);
+	let replica: Arc<ChainClient<Block>> = net.peer(0).client().clone();
+	let served_blocks = |protocol: Protocol<Block, DummySpecialization, Hash>, client: &PeersClient| {
+		let queue = RwLock::new(VecDeque::new());
+		{
+			let mut io = TestIo::new(&queue, None);
+			protocol.handle_packet(&mut io, 1, &status_from(client, Roles::FULL, 0).encode()).ok();
+			protocol.handle_packet(&mut io, 1, &block_request(BlockAttributes::HEADER, 1, Direction::Ascending, Some(1)).encode()).ok();
+		}
+		let blocks = queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponse(r)) => Some(r.blocks.len()),
+				_ => None,
+			})
+			.next();
+		blocks.expect("A block response has been sent")
+	};
+
+	// block #1 is only known to the replica
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	assert_eq!(served_blocks(protocol, &client), 0);
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	assert_eq!(served_blocks(protocol.with_serving_chain(replica), &client), 1);
+}
+
+#[test]
+fn defers_block_requests_over_the_in_flight_response_limit() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { max_in_flight_response_bytes: Some(1), ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let responded = |queued: usize, f: &Fn(&mut TestIo)| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			io.send_queue_lens.insert(1, queued);
+			f(&mut io);
+		}
+		queue.write().drain(..)
+			.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponse(_)) => true,
+				_ => false,
+			})
+			.count()
+	};
+	let request = block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(1));
+
+	responded(0, &|io| { protocol.handle_packet(io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok(); });
+	assert_eq!(responded(0, &|io| { protocol.handle_packet(io, 1, &request.encode()).ok(); }), 1);
+	// the response is still queued, further requests wait for it to be sent
+	assert_eq!(responded(4, &|io| {
+		protocol.handle_packet(io, 1, &request.encode()).ok();
+		protocol.handle_packet(io, 1, &request.encode()).ok();
+	}), 0);
+	assert_eq!(protocol.metrics().get("requests.deferred"), Some(&2));
+	assert_eq!(responded(4, &|io| { protocol.tick(io); }), 0);
+
+	// the deferred requests are served once it is sent
+	assert_eq!(responded(0, &|io| { protocol.tick(io); }), 2);
+}
+
+#[test]
+fn decays_peer_reputation_toward_neutral() {
+	::env_logger::init().ok();
+	let decay = ReputationDecay { interval: Duration::from_secs(10), fraction: 0.5 };
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { reputation_decay: Some(decay), ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	let start = Instant::now();
+	// the pool rejects all of these
+	protocol.handle_packet(&mut io, 1, &GenericMessage::Transactions((0..8).map(transfer).collect()).encode()).ok();
+	let reputation = || protocol.debug_snapshot().peers[0].reputation;
+	assert_eq!(reputation(), -8);
+
+	protocol.maintain_peers_at(&mut io, start + Duration::from_secs(5));
+	assert_eq!(reputation(), -8);
+	protocol.maintain_peers_at(&mut io, start + Duration::from_secs(11));
+	assert_eq!(reputation(), -4);
+	protocol.maintain_peers_at(&mut io, start + Duration::from_secs(31));
+	assert_eq!(reputation(), -1);
+	protocol.maintain_peers_at(&mut io, start + Duration::from_secs(41));
+	assert_eq!(reputation(), 0);
+}
+
+#[test]
+fn ignores_announcements_of_blocks_in_our_chain() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(2);
+	net.sync();
+	let peer = net.peer(0);
+	peer.push_blocks(1, false);
+	let header = peer.client().header(&BlockId::Number(1)).unwrap().unwrap();
+	let best_seen_block = || peer.sync.status().sync.best_seen_block;
+	assert_eq!(best_seen_block(), Some(0));
+	peer.sent_messages();
+
+	// the best block of the peer is updated, nothing is downloaded
+	peer.receive(1, GenericMessage::BlockAnnounce(BlockAnnounce { header: header.clone() }));
+	assert_eq!(best_seen_block(), Some(1));
+	assert!(!peer.sent_messages().into_iter().any(|(_, m)| match m {
+		GenericMessage::BlockRequest(_) => true,
+		_ => false,
+	}));
+	assert_eq!(peer.sync.block_source(&header.hash()), None);
+	assert_eq!(peer.sync.metrics().get("announces.already_in_chain"), Some(&1));
+}
+
+#[test]
+fn disconnects_all_peers_keeping_sync_state() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let block_requests = || queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockRequest(r)) => Some((p.recipient, r.from)),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+	let genesis = client.header(&BlockId::Number(0)).unwrap().unwrap();
+	let header = Header::new(1, Default::default(), Default::default(), genesis.hash(), Default::default());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 10).encode()).ok();
+		protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		protocol.on_peer_connected(&mut io, 3);
+		protocol.request_priority_block(&mut io, header.hash(), 1);
+	}
+	block_requests();
+
+	let disconnected = {
+		let mut io = TestIo::new(&queue, None);
+		protocol.disconnect_all(&mut io);
+		io.to_disconnect.clone()
+	};
+	assert_eq!(disconnected, vec![1, 2, 3].into_iter().collect());
+	assert_eq!(protocol.status().num_peers, 0);
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 1).is_none());
+
+	// the priority block is still wanted, and requested from the next peer
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 4, &status_ahead_of(&client, 10).encode()).ok();
+	}
+	assert_eq!(block_requests(), vec![(4, FromBlock::Hash(header.hash()))]);
+}
+
+#[test]
+fn interleaves_chunks_of_concurrent_block_responses() {
+	::env_logger::init().ok();
+	let mut net = TestNet::new(0);
+	net.add_peer(&ProtocolConfig { interleave_chunked_responses: true, ..Default::default() });
+	let peer = net.peer(0);
+	peer.push_blocks(48, false);
+	let queue = RwLock::new(VecDeque::new());
+	let chunks_sent = |queued: usize, f: &Fn(&mut TestIo)| {
+		{
+			let mut io = TestIo::new(&queue, None);
+			io.send_queue_lens.insert(1, queued);
+			io.send_queue_lens.insert(2, queued);
+			f(&mut io);
+		}
+		queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponseChunk(chunk)) => Some((p.recipient, chunk.index)),
+				_ => None,
+			})
+			.collect::<Vec<_>>()
+	};
+	let request = block_request(BlockAttributes::HEADER | BlockAttributes::CHUNKED, 1, Direction::Ascending, Some(48));
+
+	chunks_sent(0, &|io| {
+		peer.sync.handle_packet(io, 1, &status_from(peer.client(), Roles::FULL, 0).encode()).ok();
+		peer.sync.handle_packet(io, 2, &status_from(peer.client(), Roles::FULL, 1).encode()).ok();
+	});
+	// both responses wait for the send queues to drain
+	assert_eq!(chunks_sent(2, &|io| {
+		peer.sync.handle_packet(io, 1, &request.encode()).ok();
+		peer.sync.handle_packet(io, 2, &request.encode()).ok();
+	}), vec![]);
+	assert_eq!(chunks_sent(0, &|io| { peer.sync.tick(io); }), vec![(1, 0), (2, 0), (1, 1), (2, 1), (1, 2), (2, 2)]);
+}
+
+#[test]
+fn serves_the_genesis_block_from_a_genesis_only_chain() {
+	::env_logger::init().ok();
+	let net = TestNet::new(1);
+	let peer = net.peer(0);
+	let genesis = peer.client().info().unwrap().chain.genesis_hash;
+	let served = |from, direction| {
+		peer.receive(1, block_request(BlockAttributes::HEADER, from, direction, Some(10)));
+		block_response(peer).blocks.into_iter().map(|b| b.hash).collect::<Vec<_>>()
+	};
+
+	assert_eq!(served(0, Direction::Ascending), vec![genesis]);
+	assert_eq!(served(1, Direction::Ascending), vec![]);
+	assert_eq!(served(0, Direction::Descending), vec![genesis]);
+	assert_eq!(served(100, Direction::Descending), vec![genesis]);
+}
+
+#[test]
+fn bounds_forks_announced_by_all_peers() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { max_announced_forks: Some(8), ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	let announce = |io: &mut TestIo, who: NodeIndex, i: u8| {
+		let header = Header::new(1, Default::default(), [i; 32].into(), genesis, Default::default());
+		let announce: Message<Block> = GenericMessage::BlockAnnounce(BlockAnnounce { header });
+		protocol.handle_packet(io, who, &announce.encode()).ok();
+	};
+	for who in 1..5 {
+		protocol.handle_packet(&mut io, who, &status_from(&client, Roles::FULL, who as u64).encode()).ok();
+	}
+
+	// all peers announce a common fork, then many of their own
+	for who in 1..5 {
+		announce(&mut io, who, 1);
+	}
+	for who in 1..5 {
+		for i in 0..10 {
+			announce(&mut io, who, 10 * who as u8 + i);
+			assert!(protocol.announced_forks() <= 8);
+		}
+	}
+	assert_eq!(protocol.announced_forks(), 8);
+	assert!(io.to_disconnect.is_empty());
+
+	// the common fork is still tracked as it has the most support: announcing it again evicts nothing
+	let evicted = protocol.metrics().get("announces.evicted_forks").cloned();
+	assert!(evicted.is_some());
+	announce(&mut io, 1, 1);
+	assert_eq!(protocol.metrics().get("announces.evicted_forks").cloned(), evicted);
+}
+
+#[test]
+fn serves_the_authority_set_to_trusted_peers_only() {
+	::env_logger::init().ok();
+	let (trusted, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let config = ProtocolConfig {
+		trusted_peers: vec![trusted.clone()],
+		untrusted_peer_features: PeerFeatures::all() - PeerFeatures::AUTHORITY_SET,
+		..Default::default()
+	};
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let best = client.info().unwrap().chain;
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		io.peer_ids.insert(2, trusted);
+		for who in 1..3 {
+			protocol.handle_packet(&mut io, who, &status_from(&client, Roles::FULL, 0).encode()).ok();
+			let request: Message<Block> = GenericMessage::AuthoritySetRequest(::message::AuthoritySetRequest { id: who as u64 });
+			protocol.handle_packet(&mut io, who, &request.encode()).ok();
+		}
+		assert!(io.to_disconnect.is_empty());
+	}
+
+	let responses: HashMap<NodeIndex, ::message::AuthoritySetResponse<Hash, u64>> = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::AuthoritySetResponse(response)) => Some((p.recipient, response)),
+			_ => None,
+		})
+		.collect();
+	assert!(responses[&1].authorities.is_empty());
+	let served = &responses[&2];
+	assert_eq!(served.id, 2);
+	assert_eq!((served.number, served.hash), (best.best_number, best.best_hash));
+	assert!(!served.authorities.is_empty());
+	assert_eq!(served.authorities, client.authorities_at(&BlockId::Hash(best.best_hash)).unwrap());
+}
+
+#[test]
+fn returns_the_error_of_packets_not_handled() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	assert_eq!(protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()), Ok(()));
+	assert_eq!(protocol.handle_packet(&mut io, 1, &[::message::LAST_MESSAGE_INDEX + 1, 1, 2, 3]),
+		Err(PacketError::Unknown(::message::LAST_MESSAGE_INDEX + 1)));
+	assert!(io.to_disconnect.is_empty());
+
+	assert_eq!(protocol.handle_packet(&mut io, 1, &[0, 1]), Err(PacketError::Malformed));
+	assert!(io.to_disconnect.contains(&1));
+}
+
+#[test]
+fn returns_the_error_of_invalid_messages() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	let handle = |io: &mut TestIo, who: NodeIndex, message: Message<Block>| {
+		let result = protocol.handle_packet(io, who, &message.encode());
+		assert!(io.to_disconnect.remove(&who));
+		result
+	};
+	let mut foreign = status_from(&client, Roles::FULL, 0);
+	if let GenericMessage::Status(ref mut status) = foreign {
+		status.genesis_hash = [9u8; 32].into();
+	}
+	assert_eq!(handle(&mut io, 1, foreign), Err(PacketError::GenesisMismatch));
+
+	let genesis_hash = client.info().unwrap().chain.genesis_hash;
+	let compact = GenericMessage::CompactStatus(::message::generic::CompactStatus { version: 1, genesis_hash, best_number: 0 });
+	assert_eq!(handle(&mut io, 2, compact), Err(PacketError::UnsupportedVersion(1)));
+
+	protocol.handle_packet(&mut io, 3, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	let digest = GenericMessage::BlockDigest(::message::BlockDigest { bits: Vec::new() });
+	assert_eq!(handle(&mut io, 3, digest), Err(PacketError::InvalidDigest));
+
+	let fake_genesis = Header::new(0, Default::default(), Default::default(), [2u8; 32].into(), Default::default());
+	let announce = GenericMessage::BlockAnnounce(BlockAnnounce { header: fake_genesis });
+	assert_eq!(handle(&mut io, 3, announce), Err(PacketError::InvalidAnnouncement));
+
+	let chunk = GenericMessage::BlockResponseChunk(::message::generic::BlockResponseChunk {
+		id: 0,
+		index: 2,
+		count: 2,
+		blocks: Vec::new(),
+		finality_proof: None,
+		fork_divergence: None,
+	});
+	assert_eq!(handle(&mut io, 3, chunk), Err(PacketError::InvalidChunk));
+}
+
+#[test]
+fn evicts_the_idle_peer_of_equally_scored_ones() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig { soft_peer_limit: Some(2), ..Default::default() }, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	protocol.handle_packet(&mut io, 2, &status_from(&client, Roles::FULL, 0).encode()).ok();
+
+	// peer 1 connected first but keeps requesting blocks, peer 2 stays idle
+	thread::sleep(Duration::from_millis(10));
+	protocol.handle_packet(&mut io, 1, &block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(1)).encode()).ok();
+
+	protocol.handle_packet(&mut io, 3, &status_from(&client, Roles::AUTHORITY, 0).encode()).ok();
+	assert_eq!(io.to_disconnect.iter().cloned().collect::<Vec<_>>(), vec![2]);
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 1).is_some());
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 2).is_none());
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 3).is_some());
+}
+
+#[test]
+fn defers_block_requests_over_the_global_in_flight_limit() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { global_max_inflight_requests: Some(1), ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let requested = || -> Vec<NodeIndex> {
+		let requested = queue.write().drain(..)
+			.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockRequest(_)) => true,
+				_ => false,
+			})
+			.map(|p| p.recipient)
+			.collect();
+		requested
+	};
+
+	// each peer is asked for a different range, but only one request is sent
+	{
+		let mut io = TestIo::new(&queue, None);
+		for who in 1..4 {
+			protocol.handle_packet(&mut io, who, &status_ahead_of(&client, 1000).encode()).ok();
+		}
+	}
+	let first = requested();
+	assert_eq!(first.len(), 1);
+	assert_eq!(protocol.metrics().get("requests.in_flight"), Some(&1));
+	assert_eq!(protocol.metrics().get("requests.globally_deferred"), Some(&2));
+
+	// the request deferred first is sent once the slot is freed
+	protocol.on_peer_disconnected(&mut TestIo::new(&queue, None), first[0]);
+	let second = requested();
+	assert_eq!(second.len(), 1);
+	assert!(second[0] != first[0]);
+	assert_eq!(protocol.metrics().get("requests.in_flight"), Some(&1));
+}
+
+/// Verifier accepting the blocks justified with `b"valid"` only.
+struct JustificationVerifier;
+
+impl Verifier<Block> for JustificationVerifier {
+	fn verify(
+		&self,
+		origin: BlockOrigin,
+		header: Header,
+		justification: Vec<u8>,
+		body: Option<Vec<Extrinsic>>
+	) -> Result<(ImportBlock<Block>, Option<Vec<AuthorityId>>), String> {
+		if justification != b"valid".to_vec() {
+			return Err("Invalid justification".into());
+		}
+		PassThroughVerifier(true).verify(origin, header, justification, body)
+	}
+}
+
+#[test]
+fn imports_justifications_of_known_blocks() {
+	::env_logger::init().ok();
+	let client = Arc::new(test_client::new());
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let hash = client.block_hash(1).unwrap().unwrap();
+	let import_queue = Arc::new(SyncImportQueue::new(Arc::new(JustificationVerifier), client.clone()));
+	let protocol: Protocol<Block, DummySpecialization, Hash> = Protocol::new(
+		ProtocolConfig::default(),
+		client.clone(),
+		import_queue,
+		None,
+		Arc::new(EmptyTransactionPool),
+		DummySpecialization { },
+	).unwrap();
+
+	let (sender, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	io.peer_ids.insert(1, sender.clone());
+	protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+
+	assert!(protocol.import_justification(&mut io, Some(sender.clone()), hash, b"valid".to_vec()));
+	assert_eq!(client.justification(&BlockId::Hash(hash)).unwrap(), Some(b"valid".to_vec()));
+	assert_eq!(client.info().unwrap().chain.finalized_hash, hash);
+	assert!(io.to_disconnect.is_empty());
+
+	assert!(!protocol.import_justification(&mut io, Some(sender.clone()), hash, b"forged".to_vec()));
+	assert_eq!(client.justification(&BlockId::Hash(hash)).unwrap(), Some(b"valid".to_vec()));
+	assert!(io.to_disconnect.contains(&1));
+
+	// justifications of unknown blocks might just be early
+	io.to_disconnect.clear();
+	assert!(!protocol.import_justification(&mut io, Some(sender), [7u8; 32].into(), b"valid".to_vec()));
+	assert!(io.to_disconnect.is_empty());
+	assert_eq!(protocol.metrics().get("justifications.imported"), Some(&1));
+	assert_eq!(protocol.metrics().get("justifications.rejected"), Some(&2));
+}
+
+#[test]
+fn propagates_stale_extrinsics_again() {
+	::env_logger::init().ok();
+	let age = Duration::from_secs(60);
+	let config = ProtocolConfig { extrinsics_repropagation_age: Some(age), ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(ReadyTransactionPool(vec![transfer(0)])));
+	let queue = RwLock::new(VecDeque::new());
+	let sent = || -> Vec<(NodeIndex, Vec<Extrinsic>)> {
+		let sent = queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::Transactions(transactions)) => Some((p.recipient, transactions)),
+				_ => None,
+			})
+			.collect();
+		sent
+	};
+
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	protocol.propagate_extrinsics(&mut TestIo::new(&queue, None));
+	assert_eq!(sent(), vec![(1, vec![transfer(0)])]);
+
+	// known to the peer, it is not sent again until it gets stale
+	let now = Instant::now();
+	protocol.repropagate_extrinsics_at(&mut TestIo::new(&queue, None), now);
+	assert!(sent().is_empty());
+	protocol.repropagate_extrinsics_at(&mut TestIo::new(&queue, None), now + age);
+	assert_eq!(sent(), vec![(1, vec![transfer(0)])]);
+	assert_eq!(protocol.metrics().get("extrinsics.repropagated"), Some(&1));
+}
+
+#[test]
+fn drops_peers_answering_with_known_blocks_only() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let last_request_id = || -> ::message::RequestId {
+		let id = queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockRequest(r)) => Some(r.id),
+				_ => None,
+			})
+			.last();
+		id.expect("A block request has been sent")
+	};
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status_ahead_of(&client, 5).encode()).ok();
+
+	// block #1 gets imported from elsewhere while the peer keeps sending it
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let header = client.header(&BlockId::Number(1)).unwrap().unwrap();
+	for attempt in 0..3 {
+		let response: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+			id: last_request_id(),
+			blocks: vec![::message::generic::BlockData {
+				hash: header.hash(),
+				header: Some(header.clone()),
+				body: Some(vec![]),
+				receipt: None,
+				message_queue: None,
+				justification: Some(vec![]),
+				compact_body: None,
+			}],
+			finality_proof: None,
+			busy: false,
+			fork_divergence: None,
+		});
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &response.encode()).ok();
+		assert_eq!(io.to_disconnect.contains(&1), attempt == 2);
+	}
+}
+
+#[test]
+fn accepts_compact_statuses_of_light_clients() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let genesis_hash = client.info().unwrap().chain.genesis_hash;
+	let compact_status = |version| -> Message<Block> {
+		GenericMessage::CompactStatus(::message::generic::CompactStatus { version, genesis_hash, best_number: 0 })
+	};
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	protocol.handle_packet(&mut io, 1, &compact_status(CURRENT_VERSION).encode()).ok();
+	assert!(io.to_disconnect.is_empty());
+	let info = protocol.peer_info(&TestIo::new(&queue, None), 1).expect("The light client is connected");
+	assert_eq!(info.roles, Roles::LIGHT);
+	assert_eq!(info.protocol_version, CURRENT_VERSION);
+	assert_eq!((info.best_hash, info.best_number), (genesis_hash, 0));
+
+	// peers using an older version don't know compact statuses
+	protocol.handle_packet(&mut io, 2, &compact_status(MIN_VERSION).encode()).ok();
+	assert!(io.to_disconnect.contains(&2));
+	assert!(protocol.peer_info(&TestIo::new(&queue, None), 2).is_none());
+}
+
+#[test]
+fn ignores_unknown_best_hashes_of_compact_statuses() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let compact_status: Message<Block> = GenericMessage::CompactStatus(::message::generic::CompactStatus {
+		version: CURRENT_VERSION,
+		genesis_hash: client.info().unwrap().chain.genesis_hash,
+		best_number: 5,
+	});
+	let queue = RwLock::new(VecDeque::new());
+	let requested_from = || -> Vec<NodeIndex> {
+		let requested_from = queue.write().drain(..)
+			.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockRequest(_)) => true,
+				_ => false,
+			})
+			.map(|p| p.recipient)
+			.collect();
+		requested_from
+	};
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &compact_status.encode()).ok();
+	assert!(requested_from().is_empty());
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 2, &status_ahead_of(&client, 5).encode()).ok();
+	assert_eq!(requested_from(), vec![2]);
+
+	// the best block of the light client is not known, so it doesn't count as another fork
+	assert_eq!(protocol.status().distinct_best_hashes, 1);
+
+	// until it is announced
+	let header = Header::new(5, Default::default(), Default::default(), [3u8; 32].into(), Default::default());
+	let announce: Message<Block> = GenericMessage::BlockAnnounce(BlockAnnounce { header: header.clone() });
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &announce.encode()).ok();
+	assert_eq!(protocol.peer_info(&TestIo::new(&queue, None), 1).unwrap().best_hash, header.hash());
+	assert_eq!(protocol.status().distinct_best_hashes, 2);
+}
+
+#[test]
+fn does_not_mark_peers_as_knowing_what_failed_to_be_sent() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(ReadyTransactionPool(vec![transfer(0)])));
+	let queue = RwLock::new(VecDeque::new());
+	let sent = || -> Vec<Message<Block>> {
+		let sent = queue.write().drain(..)
+			.filter_map(|p| <Message<Block> as Decode>::decode(&mut &p.data[..]))
+			.filter(|m| match *m {
+				GenericMessage::Transactions(_) | GenericMessage::BlockAnnounce(_) => true,
+				_ => false,
+			})
+			.collect();
+		sent
+	};
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	sent();
+
+	// the extrinsic is sent again once sending works
+	{
+		let mut io = TestIo::new(&queue, None);
+		io.failing_sends.insert(1);
+		protocol.propagate_extrinsics(&mut io);
+	}
+	assert!(sent().is_empty());
+	protocol.propagate_extrinsics(&mut TestIo::new(&queue, None));
+	assert_eq!(sent(), vec![GenericMessage::Transactions(vec![transfer(0)])]);
+
+	// and so is the announcement of a block
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let header = client.header(&BlockId::Number(1)).unwrap().unwrap();
+	{
+		let mut io = TestIo::new(&queue, None);
+		io.failing_sends.insert(1);
+		protocol.on_block_imported(&mut io, header.hash(), &header);
+	}
+	assert!(sent().is_empty());
+	protocol.on_block_imported(&mut TestIo::new(&queue, None), header.hash(), &header);
+	assert_eq!(sent(), vec![GenericMessage::BlockAnnounce(BlockAnnounce { header })]);
+}
+
+#[test]
+fn lists_the_peers_informed_of_a_block() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let (informed, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30333/p2p/QmV6ttrdRBjuY6EV4Zh5saz8MaqY6anjXo4SrCWt5caANX").unwrap();
+	let (uninformed, _) = parse_str_addr("/ip4/127.0.0.1/tcp/30334/p2p/QmYtqxbFUXmG6WCwhHLqpi3Vzd15Wtmukm8PbiGTPEcCH3").unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	io.peer_ids.insert(1, informed.clone());
+	io.peer_ids.insert(2, uninformed);
+	for who in 1..3 {
+		protocol.handle_packet(&mut io, who, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	}
+
+	// the announcement only reaches the first peer
+	io.failing_sends.insert(2);
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let header = client.header(&BlockId::Number(1)).unwrap().unwrap();
+	assert!(protocol.peers_informed_of_block(&header.hash()).is_empty());
+	protocol.on_block_imported(&mut io, header.hash(), &header);
+	assert_eq!(protocol.peers_informed_of_block(&header.hash()), vec![informed]);
+}
+
+#[test]
+fn bans_peers_whose_best_block_regresses_too_deep() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	let announce = |number| GenericMessage::BlockAnnounce(BlockAnnounce {
+		header: Header::new(number, Default::default(), Default::default(), [2u8; 32].into(), Default::default()),
+	}).encode();
+	let restarted = |number, session_nonce| {
+		let mut status = status_ahead_of(&client, number);
+		if let GenericMessage::Status(ref mut status) = status {
+			status.best_hash = [number as u8; 32].into();
+			status.session_nonce = session_nonce;
+		}
+		status.encode()
+	};
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5000).encode()).ok();
+
+	// announcing an old fork is fine, announcing a new head raises the best block
+	protocol.handle_packet(&mut io, 1, &announce(10)).ok();
+	protocol.handle_packet(&mut io, 1, &announce(5002)).ok();
+	assert!(!io.to_disconnect.contains(&1));
+	assert_eq!(protocol.peer_info(&TestIo::new(&queue, None), 1).unwrap().best_number, 5002);
+	assert_eq!(protocol.metrics().get("peers.best_regressions"), None);
+
+	// a reorg a few blocks deep is fine
+	protocol.handle_packet(&mut io, 1, &restarted(4997, 1)).ok();
+	assert!(!io.to_disconnect.contains(&1));
+	assert_eq!(protocol.metrics().get("peers.best_regressions"), Some(&1));
+
+	// thousands of blocks are not
+	protocol.handle_packet(&mut io, 1, &restarted(10, 2)).ok();
+	assert!(io.to_disconnect.contains(&1));
+}
+
+#[test]
+fn tick_reports_the_work_done() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig {
+		min_block_request_timeout: Duration::from_millis(0),
+		max_block_request_timeout: Duration::from_millis(0),
+		..Default::default()
+	};
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	assert!(protocol.tick(&mut io).is_idle());
+
+	// the block request sent to the peer times out
+	protocol.handle_packet(&mut io, 1, &status_ahead_of(&client, 5).encode()).ok();
+	thread::sleep(Duration::from_millis(10));
+	let outcome = protocol.tick(&mut io);
+	assert_eq!(outcome.peers_dropped, 1);
+	assert_eq!(outcome.requests_timed_out, 1);
+	assert!(!outcome.is_idle());
+	assert!(io.to_disconnect.contains(&1));
+
+	// the peer is only reported once
+	assert!(protocol.tick(&mut io).is_idle());
+}
+
+#[test]
+fn caps_and_notes_requests_for_absurdly_many_blocks() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { min_block_response_len: 1, max_block_response_len: 2, ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	for _ in 0..3 {
+		let block = client.new_block().unwrap().bake().unwrap();
+		client.justify_and_import(BlockOrigin::File, block).unwrap();
+	}
+	let queue = RwLock::new(VecDeque::new());
+	{
+		let mut io = TestIo::new(&queue, None);
+		protocol.handle_packet(&mut io, 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+		protocol.handle_packet(&mut io, 1, &block_request(BlockAttributes::HEADER, 1, Direction::Ascending, Some(u32::max_value())).encode()).ok();
+		assert!(!io.to_disconnect.contains(&1));
+	}
+	let served: Vec<_> = queue.write().drain(..)
+		.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+			Some(GenericMessage::BlockResponse(r)) => Some(r.blocks.len()),
+			_ => None,
+		})
+		.collect();
+	assert_eq!(served, vec![2]);
+	assert_eq!(protocol.metrics().get("requests.suspicious_max"), Some(&1));
+}
+
+#[test]
+fn serves_only_headers_while_syncing_if_configured() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { serve_during_sync: ServeDuringSync::HeadersOnly, ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let served = || {
+		{
+			let mut io = TestIo::new(&queue, None);
+			let request = block_request(BlockAttributes::HEADER | BlockAttributes::BODY, 1, Direction::Ascending, Some(1));
+			protocol.handle_packet(&mut io, 1, &request.encode()).ok();
+		}
+		let served = queue.write().drain(..)
+			.filter_map(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockResponse(r)) => r.blocks.into_iter().next(),
+				_ => None,
+			})
+			.next()
+			.expect("A block is served");
+		served
+	};
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	let block = served();
+	assert!(block.header.is_some() && block.body.is_some());
+
+	// a peer far ahead of us gets us syncing
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 2, &status_ahead_of(&client, 10_000).encode()).ok();
+	assert_eq!(protocol.status().sync.state, SyncState::Downloading);
+	let block = served();
+	assert!(block.header.is_some() && block.body.is_none());
+}
+
+#[test]
+fn detects_being_on_a_minority_fork() {
+	::env_logger::init().ok();
+	let notified = Arc::new(AtomicUsize::new(0));
+	let hook_notified = notified.clone();
+	let config = ProtocolConfig {
+		on_minority_fork_hook: Some(Arc::new(move |_: &[u8]| { hook_notified.fetch_add(1, Ordering::SeqCst); })),
+		..Default::default()
+	};
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let queue = RwLock::new(VecDeque::new());
+	let mut io = TestIo::new(&queue, None);
+	assert!(!protocol.status().on_minority_fork);
+
+	// both peers at our height report another best block
+	for who in 1..3 {
+		protocol.handle_packet(&mut io, who, &status_ahead_of(&client, 1).encode()).ok();
+	}
+	assert!(protocol.status().on_minority_fork);
+	protocol.tick(&mut io);
+	protocol.tick(&mut io);
+	assert_eq!(notified.load(Ordering::SeqCst), 1);
+
+	// until one agrees with us
+	protocol.handle_packet(&mut io, 3, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	assert!(!protocol.status().on_minority_fork);
+}
+
+#[test]
+fn talks_to_peers_using_the_baseline_formats() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { chunked_block_responses: true, ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let genesis = client.info().unwrap().chain.genesis_hash;
+	let queue = RwLock::new(VecDeque::new());
+	let sent = || -> Vec<Vec<u8>> {
+		let sent = queue.write().drain(..).map(|p| p.data).collect();
+		sent
+	};
+
+	// a baseline status: no session nonce, a peer 5 blocks ahead of us
+	let mut status = vec![0u8];
+	MIN_VERSION.encode_to(&mut status);
+	Roles::FULL.encode_to(&mut status);
+	5u64.encode_to(&mut status);
+	status.extend_from_slice(&[1u8; 32]);
+	genesis.encode_to(&mut status);
+	Vec::<u8>::new().encode_to(&mut status);
+	assert_eq!(protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status), Ok(()));
+	assert_eq!(protocol.peer_info(&TestIo::new(&queue, None), 1).map(|p| p.protocol_version), Some(MIN_VERSION));
+
+	// our request only has attributes the peer knows
+	let request = sent().into_iter()
+		.filter_map(|data| match <Message<Block> as Decode>::decode(&mut &data[..]) {
+			Some(GenericMessage::BlockRequest(r)) => Some(r),
+			_ => None,
+		})
+		.next()
+		.expect("A block request has been sent");
+	assert!(!request.fields.contains(BlockAttributes::CHUNKED));
+
+	// a baseline response: block data only
+	let mut response = vec![2u8];
+	request.id.encode_to(&mut response);
+	Vec::<::message::v1::BlockData<Header, Hash, Extrinsic>>::new().encode_to(&mut response);
+	assert_eq!(protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &response), Ok(()));
+	assert_eq!(protocol.metrics().get("messages.block_response"), Some(&1));
+	sent();
+
+	// and our responses are in the baseline format
+	let request = block_request(BlockAttributes::HEADER, 0, Direction::Ascending, Some(1));
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &request.encode()).ok();
+	let response = sent().pop().expect("A block response has been sent");
+	let decoded: ::message::v1::BlockResponse<Header, Hash, Extrinsic> = Decode::decode(&mut &response[1..]).unwrap();
+	assert_eq!(response[0], 2);
+	assert_eq!(response.len(), 1 + decoded.encode().len());
+	assert_eq!(decoded.blocks.len(), 1);
+}
+
+#[test]
+fn drops_deferred_block_requests_superseded_by_newer_ones() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { global_max_inflight_requests: Some(1), ..Default::default() };
+	let (client, protocol) = protocol_with_pool(config, Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let requested = || -> Vec<NodeIndex> {
+		let requested = queue.write().drain(..)
+			.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockRequest(_)) => true,
+				_ => false,
+			})
+			.map(|p| p.recipient)
+			.collect();
+		requested
+	};
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status_ahead_of(&client, 1000).encode()).ok();
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 2, &status_ahead_of(&client, 1000).encode()).ok();
+	assert_eq!(requested(), vec![1]);
+
+	// the request deferred for peer 2 is superseded by the one sent after reconnecting
+	protocol.abort();
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 2, &status_ahead_of(&client, 1000).encode()).ok();
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 3, &status_ahead_of(&client, 1000).encode()).ok();
+	assert_eq!(requested(), vec![2]);
+
+	let response: Message<Block> = GenericMessage::BlockResponse(::message::generic::BlockResponse {
+		id: 0,
+		blocks: vec![],
+		finality_proof: None,
+		busy: false,
+		fork_divergence: None,
+	});
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 2, &response.encode()).ok();
+	assert_eq!(requested(), vec![3]);
+	assert_eq!(protocol.metrics().get("requests.in_flight"), Some(&1));
+}
+
+#[test]
+fn announces_imported_blocks_to_connected_peers_once() {
+	::env_logger::init().ok();
+	let (client, protocol) = protocol_with_pool(ProtocolConfig::default(), Arc::new(EmptyTransactionPool));
+	let queue = RwLock::new(VecDeque::new());
+	let announced_to = || -> Vec<NodeIndex> {
+		let announced_to = queue.write().drain(..)
+			.filter(|p| match <Message<Block> as Decode>::decode(&mut &p.data[..]) {
+				Some(GenericMessage::BlockAnnounce(_)) => true,
+				_ => false,
+			})
+			.map(|p| p.recipient)
+			.collect();
+		announced_to
+	};
+	protocol.handle_packet(&mut TestIo::new(&queue, None), 1, &status_from(&client, Roles::FULL, 0).encode()).ok();
+	assert!(announced_to().is_empty());
+
+	let block = client.new_block().unwrap().bake().unwrap();
+	client.justify_and_import(BlockOrigin::File, block).unwrap();
+	let header = client.header(&BlockId::Number(1)).unwrap().unwrap();
+	protocol.on_block_imported(&mut TestIo::new(&queue, None), header.hash(), &header);
+	assert_eq!(announced_to(), vec![1]);
+
+	// the peer is now known to have the block
+	protocol.on_block_imported(&mut TestIo::new(&queue, None), header.hash(), &header);
+	assert!(announced_to().is_empty());
+}
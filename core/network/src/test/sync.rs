@@ -138,3 +138,15 @@ fn blocks_are_not_announced_by_light_nodes() {
 	assert_eq!(net.peer(1).client.backend().blockchain().info().unwrap().best_number, 1);
 	assert_eq!(net.peer(2).client.backend().blockchain().info().unwrap().best_number, 0);
 }
+
+#[test]
+fn headers_first_sync_works() {
+	::env_logger::init().ok();
+	let config = ProtocolConfig { headers_first_sync: true, ..Default::default() };
+	let mut net = TestNet::from_config(&config);
+	net.add_peer(&config);
+	net.add_peer(&config);
+	net.peer(1).push_blocks(700, false);
+	net.sync();
+	assert!(net.peer(0).client.backend().blockchain().equals_to(net.peer(1).client.backend().blockchain()));
+}
@@ -120,7 +120,8 @@ pub fn import_blocks<F, E, R>(mut config: FactoryFullConfiguration<F>, exit: E,
 				header: Some(header),
 				body: Some(extrinsics),
 				receipt: None,
-				message_queue: None
+				message_queue: None,
+				compact_body: None,
 			};
 			// import queue handles verification and importing it into the client
 			queue.import_blocks(BlockOrigin::File, vec![BlockData::<F::Block> { block, origin: None }]);
@@ -195,9 +195,10 @@ impl<T: Components> CreateNetworkParams<Self> for T where
 		specialization: S,
 	) -> network::config::Params<ComponentBlock<T>, S, ComponentExHash<T>> {
 		network::config::Params {
-			config: network::config::ProtocolConfig { roles },
+			config: network::config::ProtocolConfig { roles, ..Default::default() },
 			network_config,
 			chain: client,
+			serving_chain: None,
 			on_demand: on_demand.map(|d| d as Arc<network::OnDemandService<ComponentBlock<T>>>),
 			transaction_pool: Arc::new(transaction_pool_adapter),
 			specialization,
@@ -93,6 +93,9 @@ use components::{StartRPC, CreateNetworkParams};
 pub use network::OnDemand;
 
 const DEFAULT_PROTOCOL_ID: &'static str = "sup";
+/// Number of transactions in the pool from which the transactions of peers with a negative
+/// reputation are rejected, leaving the room to transactions of better peers.
+const MIN_REPUTATION_POOL_SIZE: usize = 4096;
 
 /// Substrate service.
 pub struct Service<Components: components::Components> {
@@ -415,12 +418,20 @@ impl<C: Components> network::TransactionPool<ComponentExHash<C>, ComponentBlock<
 			.collect()
 	}
 
-	fn import(&self, transaction: &ComponentExtrinsic<C>) -> Option<ComponentExHash<C>> {
+	fn import(&self, reputation: i32, transaction: &ComponentExtrinsic<C>) -> Option<ComponentExHash<C>> {
 		if !self.imports_external_transactions {
 			debug!("Transaction rejected");
 			return None;
 		}
 
+		if reputation < 0 {
+			let status = self.pool.status();
+			if status.ready + status.future >= MIN_REPUTATION_POOL_SIZE {
+				debug!("Transaction from a peer with reputation {} rejected, the pool is full", reputation);
+				return None;
+			}
+		}
+
 		let encoded = transaction.encode();
 		if let Some(uxt) = Decode::decode(&mut &encoded[..]) {
 			let best_block_id = self.best_block_id()?;
@@ -197,6 +197,9 @@ pub fn connectivity<F: ServiceFactory>(spec: FactoryChainSpec<F>) where
 			network.run_until_all_full(|_index, service|
 				service.network().status().num_peers == NUM_NODES as usize - 1
 			);
+			for (_, service) in network.full_nodes.iter() {
+				assert_eq!(service.network().peer_count(), service.network().status().num_peers);
+			}
 		}
 		temp.close().expect("Error removing temp dir");
 	}
@@ -213,6 +216,9 @@ pub fn connectivity<F: ServiceFactory>(spec: FactoryChainSpec<F>) where
 			network.run_until_all_full(|_index, service| {
 				service.network().status().num_peers == NUM_NODES as usize - 1
 			});
+			for (_, service) in network.full_nodes.iter() {
+				assert_eq!(service.network().peer_count(), service.network().status().num_peers);
+			}
 		}
 		temp.close().expect("Error removing temp dir");
 	}